@@ -1,13 +1,127 @@
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
 use std::time::{SystemTime, UNIX_EPOCH};
+use afterburner_common::bgwrite::BackgroundWriter;
+use log::{error, info, warn};
+use afterburner_common::codec::{
+    self, Frame, FrameReader, Probe, CONTROL_STREAM, ECHO_STREAM, ERR_FRAMING, ERR_NO_COMMON_VERSION,
+    MAX_FRAME_LEN, TIMESTAMP_STREAM, TX_LEN,
+};
+
+use afterburner::echo::monotonic_raw_ns;
+use afterburner::histogram::Histogram;
+use afterburner::Error;
+
+/// Transactions received on one path (streams or datagrams), from the client's stamps.
+struct TxStats {
+    received: u64,
+    first_seq: Option<u64>,
+    max_seq: u64,
+    latency_ns: Histogram,
+}
+
+impl TxStats {
+    fn new() -> Self {
+        TxStats { received: 0, first_seq: None, max_seq: 0, latency_ns: Histogram::new() }
+    }
+
+    fn record(&mut self, tx: &[u8]) {
+        let Some((seq, sent_ns)) = codec::read_tx_stamp(tx) else { return };
+        let now_ns = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        self.received += 1;
+        self.first_seq = Some(self.first_seq.map_or(seq, |f| f.min(seq)));
+        self.max_seq = self.max_seq.max(seq);
+        self.latency_ns.record(now_ns.saturating_sub(sent_ns));
+    }
+
+    /// Sequence numbers below the highest seen that never arrived (includes ones still in
+    /// flight or reordered).
+    fn lost(&self) -> u64 {
+        self.first_seq.map_or(0, |first| (self.max_seq - first + 1).saturating_sub(self.received))
+    }
+
+    fn print(&self, path: &str) {
+        if self.received == 0 {
+            return;
+        }
+        let us = |ns: u64| ns as f64 / 1000.0;
+        info!(target: "server", "{} TX: RX {} | Lost ~{} | Lat(us) p50={:.1} p90={:.1} p99={:.1} p99.9={:.1} Max={:.1}",
+            path, self.received, self.lost(),
+            us(self.latency_ns.percentile(50.0)), us(self.latency_ns.percentile(90.0)),
+            us(self.latency_ns.percentile(99.0)), us(self.latency_ns.percentile(99.9)), us(self.latency_ns.max()));
+    }
+}
+
+/// Attach keylog (`SSLKEYLOGFILE`) and qlog (`QLOGDIR`, needs the `qlog` feature) writers
+/// to a new connection, returning their writer threads.
+fn attach_debug_logs(c: &mut quiche::Connection, scid: &[u8]) -> Vec<JoinHandle<()>> {
+    let mut threads = Vec::new();
+    if let Some(path) = std::env::var_os("SSLKEYLOGFILE") {
+        match BackgroundWriter::create(path.as_ref()) {
+            Ok((writer, handle)) => {
+                c.set_keylog(Box::new(writer));
+                threads.push(handle);
+            }
+            Err(e) => error!(target: "server", "Failed to create keylog: {}", e),
+        }
+    }
+    if let Some(dir) = std::env::var_os("QLOGDIR") {
+        #[cfg(feature = "qlog")]
+        {
+            let id: String = scid.iter().map(|b| format!("{:02x}", b)).collect();
+            let path = std::path::Path::new(&dir).join(format!("server-{}.sqlog", id));
+            match BackgroundWriter::create(&path) {
+                Ok((writer, handle)) => {
+                    c.set_qlog(Box::new(writer), "afterburner".to_string(), "stream_server".to_string());
+                    threads.push(handle);
+                }
+                Err(e) => error!(target: "server", "Failed to create qlog {}: {}", path.display(), e),
+            }
+        }
+        #[cfg(not(feature = "qlog"))]
+        {
+            let _ = scid;
+            warn!(target: "server", "Ignoring QLOGDIR={:?}: built without the `qlog` feature", dir);
+        }
+    }
+    threads
+}
+
+/// Encode and send a whole frame; never a partial one, which would break the stream's framing.
+fn send_frame(c: &mut quiche::Connection, stream_id: u64, frame: Frame) -> bool {
+    if matches!(c.stream_capacity(stream_id), Ok(cap) if cap < frame.encoded_len()) {
+        return false;
+    }
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let Ok(len) = frame.encode(&mut buf) else { return false };
+    c.stream_send(stream_id, &buf[..len], false).is_ok()
+}
 
 #[tokio::main]
 async fn main() {
-    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).expect("new");
-    config.set_application_protos(&[b"solana-tpu"]).expect("set_application_protos");
-    config.load_cert_chain_from_pem_file("cert.crt").expect("load_cert_chain_from_pem_file");
-    config.load_priv_key_from_pem_file("cert.key").expect("load_priv_key_from_pem_file");
+    // Levels and targets via RUST_LOG; AFTERBURNER_LOG_JSON=1 for JSON lines
+    afterburner_common::logging::init(std::env::var_os("AFTERBURNER_LOG_JSON").is_some());
+    if let Err(e) = run() {
+        error!(target: "server", "{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let io = |step: &'static str| move |source: std::io::Error| Error::Io { step, source };
+    let quic = |step: &'static str| move |source: quiche::Error| Error::Quic { step, source };
+
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))
+        .map_err(io("registering SIGINT handler"))?;
+
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(quic("config"))?;
+    config.set_application_protos(&[b"solana-tpu"]).map_err(quic("ALPN"))?;
+    config.load_cert_chain_from_pem_file("cert.crt").map_err(quic("loading cert.crt"))?;
+    config.load_priv_key_from_pem_file("cert.key").map_err(quic("loading cert.key"))?;
     
     config.set_initial_max_data(100_000_000);
     config.set_initial_max_stream_data_bidi_local(10_000_000);
@@ -17,33 +131,55 @@ async fn main() {
     config.set_initial_max_streams_uni(1000);
     config.set_max_ack_delay(0); 
     config.set_ack_delay_exponent(0);
+    config.enable_dgram(true, 16384, 1024);
+    // No-op unless SSLKEYLOGFILE gives the connection somewhere to write
+    config.log_keys();
 
-    let socket = std::net::UdpSocket::bind("10.0.0.11:8004").expect("bind");
-    socket.set_nonblocking(true).expect("set_nonblocking");
-    println!("[SERVER] Listening on 10.0.0.11:8004");
+    let socket = std::net::UdpSocket::bind("10.0.0.11:8004").map_err(io("binding 10.0.0.11:8004"))?;
+    socket.set_nonblocking(true).map_err(io("setting socket non-blocking"))?;
+    let local = socket.local_addr().map_err(io("reading local address"))?;
+    info!(target: "server", "Listening on 10.0.0.11:8004");
 
     let mut buf = [0u8; 65535];
     let mut out = [0u8; 65535];
     let mut rx_buf = [0u8; 65535];
     let mut conn: Option<std::pin::Pin<Box<quiche::Connection>>> = None;
     let mut client_addr: Option<SocketAddr> = None;
+    let mut log_threads: Vec<JoinHandle<()>> = Vec::new();
     
     let mut seq: u64 = 0;
     let mut total_rx_bytes: u64 = 0;
+    // Per transaction stream: bytes of a transaction not yet complete
+    let mut tx_partial: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut stream_txs = TxStats::new();
+    let mut dgram_txs = TxStats::new();
+    let mut control = FrameReader::new();
+    let mut probes = FrameReader::new();
+    // Negotiated from the client's Hello; timestamps start after our Hello answer
+    let mut version: Option<u8> = None;
+    let mut hello_pending = false;
+    let mut codec_errors: u64 = 0;
+    // Probes waiting for stream capacity, with their receive time
+    let mut pending_echoes: VecDeque<(Probe, u64)> = VecDeque::new();
 
-    loop {
+    while !term.load(Ordering::Relaxed) {
         let read_result = socket.recv_from(&mut buf);
         match read_result {
             Ok((len, src)) => {
                 client_addr = Some(src);
-                let recv_info = quiche::RecvInfo { from: src, to: socket.local_addr().expect("local_addr") };
+                let recv_info = quiche::RecvInfo { from: src, to: local };
                 
                 if conn.is_none() {
-                     println!("[SERVER] Client connected from {}", src);
+                     info!(target: "server", "Client connected from {}", src);
                      if let Ok(hdr) = quiche::Header::from_slice(&mut buf[..len], quiche::MAX_CONN_ID_LEN) {
                         let scid = quiche::ConnectionId::from_ref(&hdr.scid);
-                        let c = quiche::accept(&scid, None, socket.local_addr().expect("local_addr"), src, &mut config).expect("accept");
-                        conn = Some(Box::pin(c));
+                        match quiche::accept(&scid, None, local, src, &mut config) {
+                            Ok(mut c) => {
+                                log_threads = attach_debug_logs(&mut c, &scid);
+                                conn = Some(Box::pin(c));
+                            }
+                            Err(e) => warn!(target: "server", "Failed to accept {}: {}", src, e),
+                        }
                     }
                 }
 
@@ -59,41 +195,114 @@ async fn main() {
                     c.on_timeout();
                     
                     if let Some(target) = client_addr {
-                        if c.is_established() {
+                        if c.is_established() && hello_pending {
+                            if let Some(v) = version {
+                                hello_pending = !send_frame(c, TIMESTAMP_STREAM, Frame::Hello { min: v, max: v });
+                            }
+                        }
+                        if c.is_established() && version.is_some() && !hello_pending {
                             // BURST: Send up to 64 packets per iteration
                             const BATCH_LIMIT: usize = 64;
                             let mut sent = 0;
 
                             while sent < BATCH_LIMIT {
-                                let now_ns = SystemTime::now().duration_since(UNIX_EPOCH).expect("duration_since").as_nanos() as u64;
+                                let now_ns = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
                                 
-                                let mut payload = [0u8; 17];
-                                payload[0] = 0xA5; 
-                                payload[1..9].copy_from_slice(&now_ns.to_le_bytes());
-                                payload[9..17].copy_from_slice(&seq.to_le_bytes());
-
-                                match c.stream_send(1, &payload, false) {
-                                    Ok(_) => {
-                                        seq += 1;
-                                        sent += 1;
-                                        if seq % 50_000 == 0 {
-                                            println!("[STATS] Sent: {} | RX: ~{}", seq, total_rx_bytes / 235);
-                                        }
-                                    },
-                                    Err(quiche::Error::Done) => break, // Network buffer full
-                                    Err(_) => break,
+                                if send_frame(c, TIMESTAMP_STREAM, Frame::Timestamp { server_ns: now_ns, seq }) {
+                                    seq += 1;
+                                    sent += 1;
+                                    if seq % 50_000 == 0 {
+                                        info!(target: "stats", "Sent: {} | RX: ~{}", seq, total_rx_bytes / TX_LEN as u64);
+                                    }
+                                } else {
+                                    break; // Network buffer full
                                 }
                             }
                         }
                         
-                        for stream_id in c.readable() {
-                            while let Ok((read_len, _fin)) = c.stream_recv(stream_id, &mut rx_buf) {
-                                if read_len > 0 {
-                                    total_rx_bytes += read_len as u64;
+                        // Once we've closed the connection there is nothing left worth reading
+                        'streams: for stream_id in c.readable() {
+                            if c.local_error().is_some() {
+                                break;
+                            }
+                            let reader = match stream_id {
+                                CONTROL_STREAM => &mut control,
+                                ECHO_STREAM => &mut probes,
+                                _ => {
+                                    let partial = tx_partial.entry(stream_id).or_default();
+                                    let mut finished = false;
+                                    while let Ok((read_len, fin)) = c.stream_recv(stream_id, &mut rx_buf) {
+                                        total_rx_bytes += read_len as u64;
+                                        partial.extend_from_slice(&rx_buf[..read_len]);
+                                        let (txs, _) = partial.as_chunks::<TX_LEN>();
+                                        for tx in txs {
+                                            stream_txs.record(tx);
+                                        }
+                                        let whole = txs.len() * TX_LEN;
+                                        partial.drain(..whole);
+                                        finished |= fin;
+                                    }
+                                    // Submitted transactions come one per stream
+                                    if finished {
+                                        tx_partial.remove(&stream_id);
+                                    }
+                                    continue;
+                                }
+                            };
+                            while let Ok((read_len, _fin)) = c.stream_recv(stream_id, reader.spare()) {
+                                if read_len == 0 { break; }
+                                reader.commit(read_len);
+                                let rx_ns = monotonic_raw_ns();
+                                loop {
+                                    match reader.next_frame() {
+                                        Ok(Some(Frame::Hello { min, max })) if stream_id == CONTROL_STREAM && version.is_none() => {
+                                            match codec::negotiate(min, max) {
+                                                Some(v) => {
+                                                    info!(target: "server", "Protocol version {}", v);
+                                                    version = Some(v);
+                                                    hello_pending = true;
+                                                }
+                                                None => {
+                                                    warn!(target: "server", "No common protocol version (client {}..={})", min, max);
+                                                    c.close(true, ERR_NO_COMMON_VERSION, b"no common protocol version").ok();
+                                                }
+                                            }
+                                        }
+                                        Ok(Some(Frame::Probe(probe))) if stream_id == ECHO_STREAM => {
+                                            pending_echoes.push_back((probe, rx_ns));
+                                        }
+                                        Ok(Some(frame)) => {
+                                            codec_errors += 1;
+                                            warn!(target: "server", "Unexpected frame on stream {}: {:?}", stream_id, frame);
+                                        }
+                                        Ok(None) => break,
+                                        Err(e) => {
+                                            codec_errors += 1;
+                                            warn!(target: "server", "Framing error on stream {}: {:?}", stream_id, e);
+                                            if !matches!(e, codec::CodecError::UnknownType { .. }) {
+                                                c.close(true, ERR_FRAMING, b"framing error").ok();
+                                                break 'streams;
+                                            }
+                                        }
+                                    }
                                 }
                             }
                         }
                         
+                        while let Ok(len) = c.dgram_recv(&mut rx_buf) {
+                            total_rx_bytes += len as u64;
+                            dgram_txs.record(&rx_buf[..len]);
+                        }
+                        
+                        // ECHO: Return probes with our receive/send stamps
+                        while let Some(&(probe, server_rx_ns)) = pending_echoes.front() {
+                            let echo = Frame::Echo { probe, server_rx_ns, server_tx_ns: monotonic_raw_ns() };
+                            if !send_frame(c, ECHO_STREAM, echo) {
+                                break;
+                            }
+                            pending_echoes.pop_front();
+                        }
+                        
                         // FLUSH: Send all queued QUIC packets in one batch
                         while let Ok((write_len, _)) = c.send(&mut out) {
                             socket.send_to(&out[..write_len], target).ok();
@@ -102,9 +311,21 @@ async fn main() {
                 }
                 std::hint::spin_loop();
             },
-            Err(e) => {
-                panic::panic_any(e)
-            }
+            Err(e) => return Err(io("receiving")(e)),
         }
     }
-}
\ No newline at end of file
+
+    stream_txs.print("Stream");
+    dgram_txs.print("Datagram");
+    if codec_errors > 0 {
+        warn!(target: "server", "Framing errors: {}", codec_errors);
+    }
+    info!(target: "server", "Shutting down. Sent: {} | Codec errors: {} | RX: ~{}", seq, codec_errors, total_rx_bytes / TX_LEN as u64);
+
+    // Dropping the connection closes the keylog/qlog writers; wait for them to finish
+    drop(conn);
+    for handle in log_threads {
+        let _ = handle.join();
+    }
+    Ok(())
+}
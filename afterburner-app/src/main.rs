@@ -1,103 +1,335 @@
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::thread::JoinHandle;
 use clap::Parser;
-use aya::{programs::{Xdp, XdpFlags}, maps::XskMap, Ebpf};
+use afterburner::{config, cpu, echo, flood, quic_driver, umem, xdp};
+use afterburner::{EngineBuilder, Error, Flooder};
+use afterburner_common::bgwrite::BackgroundWriter;
+use log::{error, info, warn};
 
-mod xsk;
-mod headers;
-mod quic_driver;
-mod emit;
-mod flood;
+/// Source connection ID; also names the qlog file
+const SCID: [u8; 20] = [0x55; 20];
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// TOML config file; the flags below override its values
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
     #[arg(short, long)]
-    iface: String,
+    iface: Option<String>,
+
+    /// NIC queue to bind the AF_XDP socket to
+    #[arg(long)]
+    queue_id: Option<u32>,
+
+    /// Local IPv4 address and UDP port
+    #[arg(long, value_name = "ADDR")]
+    local: Option<SocketAddrV4>,
+
+    /// Server IPv4 address and UDP port
+    #[arg(long, value_name = "ADDR")]
+    peer: Option<SocketAddrV4>,
+
+    /// XDP attach mode
+    #[arg(long, value_enum)]
+    xdp_mode: Option<xdp::XdpMode>,
+
+    /// Replace a foreign XDP program already attached to the interface. The swap is atomic
+    /// when that program is attached in the mode we want; in another mode it is detached
+    /// first, leaving the interface briefly without XDP
+    #[arg(long)]
+    xdp_force: bool,
+
+    /// Huge page size backing the UMEM [config default: 2m]
+    #[arg(long, value_enum)]
+    hugepages: Option<umem::HugePages>,
+
+    /// Place the UMEM on this NUMA node instead of the NIC's
+    #[arg(long, value_name = "NODE")]
+    numa_node: Option<u32>,
+
+    /// Fail if the UMEM can't get the requested huge pages or NUMA node
+    #[arg(long)]
+    umem_strict: bool,
+
+    /// Pin the hot loop to this CPU core
+    #[arg(long, value_name = "CORE")]
+    cpu_core: Option<usize>,
+
+    /// Run the hot loop as SCHED_FIFO with this priority (1-99)
+    #[arg(long, value_name = "PRIO")]
+    sched_fifo: Option<u8>,
+
+    /// Lock all current and future memory (mlockall)
+    #[arg(long)]
+    mlock: bool,
+
+    /// Load the eBPF object from this path instead of the one embedded at build time
+    #[arg(long)]
+    bpf_object: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on http://<ADDR>/metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Append metrics as JSON lines to this file
+    #[arg(long, value_name = "PATH")]
+    metrics_json: Option<PathBuf>,
+
+    /// Metrics publish/JSON interval in milliseconds [config default: 1000]
+    #[arg(long)]
+    metrics_interval_ms: Option<u64>,
+
+    /// Send transactions on shared streams, on a tracked stream each, or as QUIC DATAGRAM
+    /// frames [config default: stream]
+    #[arg(long, value_enum)]
+    tx_mode: Option<flood::TxMode>,
+
+    /// Latency source: server timestamps (same host only) or round-trip echo probes
+    /// [config default: one-way]
+    #[arg(long, value_enum)]
+    latency_mode: Option<echo::LatencyMode>,
+
+    /// Least time between echo probes in microseconds [config default: 1000]
+    #[arg(long, value_name = "US")]
+    probe_interval_us: Option<u64>,
+
+    /// Break RX latency down into wire->XDP, XDP->userspace and userspace time
+    #[arg(long)]
+    rx_timestamps: bool,
+
+    /// Write the cumulative latency histogram to this file at shutdown
+    #[arg(long, value_name = "PATH")]
+    hist_dump: Option<PathBuf>,
+
+    /// Log as JSON lines instead of text (filter with RUST_LOG, e.g. `info,xsk=debug`)
+    #[arg(long)]
+    log_json: bool,
+
+    /// Capture AF_XDP RX/TX frames to this pcapng file
+    #[arg(long, value_name = "PATH")]
+    pcap: Option<PathBuf>,
+
+    /// Bytes captured per frame
+    #[arg(long, default_value_t = 256)]
+    pcap_snaplen: usize,
+
+    /// Capture one in every N frames per direction
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pcap_sample: u32,
+
+    /// Log TLS secrets to this file (SSLKEYLOGFILE format) for decrypting captures
+    #[arg(long, value_name = "PATH", env = "SSLKEYLOGFILE")]
+    keylog: Option<PathBuf>,
+
+    /// Write a qlog trace of the connection into this directory (needs the `qlog` feature)
+    #[arg(long, value_name = "DIR", env = "QLOGDIR")]
+    qlog_dir: Option<PathBuf>,
 }
 
-fn main() {
-    let args = Args::parse();
-    
-    let term = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term)).expect("register");
-
-    println!("Starting Afterburner QUIC on: {}", args.iface);
-
-    let ebpf_path = std::path::Path::new("target/bpfel-unknown-none/release/afterburner");
-    let mut bpf = Ebpf::load_file(ebpf_path).expect("Ebpf::load_file");
-    
-    let program: &mut Xdp = bpf.program_mut("afterburner").unwrap().try_into().expect("try_into");
-    program.load().expect("load");
-    program.attach(&args.iface, XdpFlags::default()).expect("attach");
-    println!("[XDP] eBPF program attached to {}", args.iface);
-
-    let mut socket = xsk::XdpSocket::new(&args.iface, 0).expect("XdpSocket::new");
-    
-    let mut xsk_map = XskMap::try_from(bpf.map_mut("XSK").unwrap()).expect("XskMap::try_from");
-    xsk_map.set(0, socket.fd, 0).expect("XskMap::set");
-    println!("[XSK] AF_XDP socket registered");
-    
-    let local: SocketAddr = "10.0.0.10:8000".parse().expect("parse local addr");
-    let peer: SocketAddr = "10.0.0.11:8004".parse().expect("parse peer addr");
-    let scid = [0x55; 20];
-    let mut driver = quic_driver::QuicDriver::new(&scid, local, peer);
-    let mut flooder = flood::Flooder::new();
-
-    println!("[RUN] HFT Loop Running (Bidirectional Mode)");
-
-    while !term.load(Ordering::Relaxed) {
-        // Process ALL available RX packets first - prevents backlog and fill ring starvation
-        while let Some((addr, len)) = socket.poll_rx() {
-            let ptr = unsafe { socket.umem_ptr.add(addr as usize) };
-            let slice = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
-            if len > 42 {
-                driver.process_input(&mut slice[42..], local, peer);
-            }
-            // Release frame back to fill ring AFTER processing is complete
-            socket.release_frame(addr);
-        }
-
-        driver.on_timeout();
-        driver.drain_streams();
-        flooder.shoot(&mut driver);
-
-        while let Some(frame) = socket.get_tx_frame() {
-            match driver.write_transmit(&mut frame[42..]) {
-                Some(quic_len) if quic_len > 0 => {
-                    headers::write_headers(frame, quic_len, 8000, 8004);
-                    socket.tx_submit(42 + quic_len);
-                },
-                _ => {
-                    socket.cancel_tx();
-                    break;
-                }
+impl Args {
+    /// Overlay the flags that were given on top of the file/default config.
+    fn apply_to(&self, cfg: &mut config::Config) {
+        let net = &mut cfg.network;
+        if let Some(iface) = &self.iface {
+            net.iface = iface.clone();
+        }
+        if let Some(queue_id) = self.queue_id {
+            net.queue_id = queue_id;
+        }
+        if let Some(local) = self.local {
+            net.local = local;
+        }
+        if let Some(peer) = self.peer {
+            net.peer = peer;
+        }
+        if let Some(mode) = self.xdp_mode {
+            net.xdp_mode = mode;
+        }
+        net.xdp_force |= self.xdp_force;
+        if let Some(huge) = self.hugepages {
+            cfg.umem.hugepages = huge;
+        }
+        if self.numa_node.is_some() {
+            cfg.umem.numa_node = self.numa_node;
+        }
+        cfg.umem.strict |= self.umem_strict;
+        if self.cpu_core.is_some() {
+            cfg.cpu.core = self.cpu_core;
+        }
+        if self.sched_fifo.is_some() {
+            cfg.cpu.fifo_priority = self.sched_fifo;
+        }
+        cfg.cpu.mlock |= self.mlock;
+        if self.metrics_addr.is_some() {
+            cfg.metrics.addr = self.metrics_addr;
+        }
+        if self.metrics_json.is_some() {
+            cfg.metrics.json_path = self.metrics_json.clone();
+        }
+        if let Some(ms) = self.metrics_interval_ms {
+            cfg.metrics.interval_ms = ms;
+        }
+        if let Some(mode) = self.tx_mode {
+            cfg.flood.tx_mode = mode;
+        }
+        if let Some(mode) = self.latency_mode {
+            cfg.flood.latency_mode = mode;
+        }
+        if let Some(us) = self.probe_interval_us {
+            cfg.flood.probe_interval_us = us;
+        }
+    }
+}
+
+/// Defaults, then the `--config` file, then flags; fails listing every problem if the
+/// result is invalid.
+fn load_config(args: &Args) -> Result<config::Config, Error> {
+    let mut cfg = match &args.config {
+        Some(path) => config::Config::load(path).map_err(|e| Error::Config(e.to_string()))?,
+        None => config::Config::default(),
+    };
+    args.apply_to(&mut cfg);
+    let valid = cfg.validate();
+    if args.print_config {
+        // Problems go in as TOML comments, so the dump can still be saved and edited
+        print!("{}", cfg.to_toml());
+        if let Err(e) = &valid {
+            for line in e.to_string().lines() {
+                println!("# {}", line.trim_start());
             }
         }
-        socket.flush_tx(); // Single syscall for entire TX batch
-        
-        std::hint::spin_loop(); 
+        std::process::exit(if valid.is_ok() { 0 } else { 2 });
     }
+    valid.map_err(|e| Error::Config(e.to_string()))?;
+    Ok(cfg)
+}
 
-    println!("Shutting down. Total TX Sent: {}", flooder.tx_count);
-    let _ = driver.conn.close(true, 0, b"done");
-    
-    for _ in 0..16 {
-        if let Some(frame) = socket.get_tx_frame() {
-            match driver.write_transmit(&mut frame[42..]) {
-                Some(quic_len) if quic_len > 0 => {
-                    headers::write_headers(frame, quic_len, 8000, 8004);
-                    socket.tx_submit(42 + quic_len);
-                },
-                _ => {
-                    socket.cancel_tx();
-                    break;
+/// Attach keylog/qlog writers to the connection. Both write through background threads,
+/// whose handles are returned so shutdown can wait for the files to be complete.
+fn attach_debug_logs(driver: &mut quic_driver::QuicDriver, args: &Args, scid: &[u8]) -> Vec<JoinHandle<()>> {
+    let mut threads = Vec::new();
+    if let Some(path) = &args.keylog {
+        match BackgroundWriter::create(path) {
+            Ok((writer, handle)) => {
+                driver.set_keylog(Box::new(writer));
+                threads.push(handle);
+                info!(target: "debug", "Logging TLS keys to {}", path.display());
+            }
+            Err(e) => error!(target: "debug", "Failed to create keylog {}: {}", path.display(), e),
+        }
+    }
+    if let Some(dir) = &args.qlog_dir {
+        #[cfg(feature = "qlog")]
+        {
+            let id: String = scid.iter().map(|b| format!("{:02x}", b)).collect();
+            let path = dir.join(format!("client-{}.sqlog", id));
+            match BackgroundWriter::create(&path) {
+                Ok((writer, handle)) => {
+                    driver.set_qlog(Box::new(writer));
+                    threads.push(handle);
+                    info!(target: "debug", "Writing qlog to {}", path.display());
                 }
+                Err(e) => error!(target: "debug", "Failed to create qlog {}: {}", path.display(), e),
             }
-        } else {
-            break;
+        }
+        #[cfg(not(feature = "qlog"))]
+        {
+            let _ = scid;
+            warn!(target: "debug", "Ignoring qlog dir {}: built without the `qlog` feature", dir.display());
+        }
+    }
+    threads
+}
+
+fn main() {
+    let args = Args::parse();
+    afterburner_common::logging::init(args.log_json);
+    if let Err(e) = run(&args) {
+        error!(target: e.target(), "{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    let cfg = load_config(args)?;
+    let io = |step: &'static str| move |source: std::io::Error| Error::Io { step, source };
+
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))
+        .map_err(io("registering SIGINT handler"))?;
+
+    info!(target: "run", "Starting Afterburner QUIC on: {}", cfg.network.iface);
+    // Before any helper thread exists, so they all inherit the helper cores
+    let mut cpu_setup = cpu::CpuSetup::before_helpers(&cfg.cpu);
+
+    // aya-log forwards the eBPF program's log events from a tokio task
+    let log_rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("aya-log")
+        .enable_all()
+        .build()
+        .map_err(io("starting aya-log runtime"))?;
+
+    let mut builder = EngineBuilder::new(cfg.clone()).rx_timestamps(args.rx_timestamps).scid(SCID);
+    if let Some(path) = &args.bpf_object {
+        builder = builder.ebpf_object(path);
+    }
+    if let Some(path) = &args.pcap {
+        builder = builder.capture(path, args.pcap_snaplen, args.pcap_sample);
+    }
+    let mut engine = builder.build()?;
+    {
+        let _guard = log_rt.enter();
+        if let Err(e) = aya_log::EbpfLogger::init(engine.ebpf_mut()) {
+            warn!(target: "xdp", "eBPF logging unavailable: {}", e);
+        }
+    }
+
+    let metrics = Arc::clone(engine.metrics());
+    let driver = engine.driver_mut();
+    let log_threads = attach_debug_logs(driver, args, &SCID);
+    // Count whatever else the server sends; application code can hook in here
+    let m = Arc::clone(&metrics);
+    driver.on_server_uni(Box::new(move |_, data, _| m.rx_uni_bytes.add(data.len() as u64)));
+    let m = Arc::clone(&metrics);
+    driver.on_bidi(Box::new(move |_, data, _| m.rx_bidi_bytes.add(data.len() as u64)));
+    let m = Arc::clone(&metrics);
+    driver.on_datagram(Box::new(move |_| m.rx_datagrams.inc()));
+    let mut flooder = Flooder::new(&cfg.flood);
+
+    cpu_setup.enter_hot_loop(&cfg.cpu);
+    info!(target: "run", "HFT Loop Running (Bidirectional Mode)");
+
+    engine.run(&term, |driver| {
+        flooder.shoot(driver);
+        metrics.tx_transactions.set(flooder.tx_count);
+    });
+
+    info!(target: "run", "Shutting down. Total TX Sent: {}", flooder.tx_count);
+    let driver = engine.driver_mut();
+    driver.print_latency_summary();
+    if let Some(path) = &args.hist_dump {
+        let result = std::fs::File::create(path)
+            .and_then(|mut f| driver.latency_histogram().write_dump(&mut f, "afterburner latency"));
+        match result {
+            Ok(()) => info!(target: "stats", "Latency histogram written to {}", path.display()),
+            Err(e) => error!(target: "stats", "Failed to write {}: {}", path.display(), e),
         }
     }
-    socket.flush_tx(); // Flush shutdown packets
-}
\ No newline at end of file
+
+    // Dropping the connection closes the keylog/qlog writers; wait for them to finish
+    engine.shutdown();
+    for handle in log_threads {
+        let _ = handle.join();
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! End-to-end test over the veth/netns topology: `stream_server` runs in `ns1`, the AF_XDP
+//! client on `veth0`.
+//!
+//! Needs CAP_NET_ADMIN and CAP_SYS_ADMIN (netns, XDP attach, BPF load) and an embedded eBPF
+//! object, and is skipped without them, so it can stay in the normal `cargo test` run (with
+//! `AFTERBURNER_ALLOW_EMPTY_EBPF=1` the build script embeds an empty object when it can't build
+//! the eBPF program). `cargo xtask itest` runs it as root; `AFTERBURNER_ITEST_SECS` sets how
+//! long the client runs (default 5).
+//!
+//! The client's side is checked from its JSON-lines metrics rather than its log output.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use xtask::netns;
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+
+#[test]
+fn af_xdp_client_against_stream_server() {
+    if !has_caps(&[CAP_NET_ADMIN, CAP_SYS_ADMIN]) {
+        eprintln!("skipping e2e: needs CAP_NET_ADMIN and CAP_SYS_ADMIN (run `sudo -E cargo xtask itest`)");
+        return;
+    }
+    if !afterburner::EngineBuilder::has_embedded_object() {
+        eprintln!("skipping e2e: no eBPF object embedded (install bpf-linker, or run `cargo xtask itest`)");
+        return;
+    }
+    let secs = std::env::var("AFTERBURNER_ITEST_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(5);
+
+    let created_netns = !netns::ns_path().exists();
+    netns::up().expect("netns up");
+    let workdir = cert_dir();
+
+    let mut server = spawn_in_netns(Command::new(env!("CARGO_BIN_EXE_stream_server")).current_dir(&workdir));
+    let server_out = collect_lines(&mut server.0, "server");
+
+    // Give the server time to bind before the client's first Initial
+    thread::sleep(Duration::from_millis(500));
+
+    let metrics_path = workdir.join("metrics.jsonl");
+    let mut client = Proc(
+        Command::new(env!("CARGO_BIN_EXE_afterburner-app"))
+            .args(["--iface", netns::HOST_IF, "--metrics-interval-ms", "200", "--metrics-json"])
+            .arg(&metrics_path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("spawn afterburner-app"),
+    );
+    // Only echoed; the client is checked through its metrics
+    let _client_out = collect_lines(&mut client.0, "client");
+
+    thread::sleep(Duration::from_secs(secs));
+
+    let client_ok = client.interrupt_and_wait(Duration::from_secs(5));
+    server.interrupt_and_wait(Duration::from_secs(2));
+
+    let server_lines: Vec<String> = server_out.try_iter().collect();
+    // The exporter writes a last line on shutdown, so this covers the whole run
+    let metrics = fs::read_to_string(&metrics_path).ok().and_then(|s| s.lines().last().map(str::to_string));
+    let xdp_attached = xdp_attached(netns::HOST_IF);
+
+    let _ = fs::remove_dir_all(&workdir);
+    if created_netns {
+        netns::down().expect("netns down");
+    }
+
+    let metrics: serde_json::Value =
+        serde_json::from_str(&metrics.expect("client wrote no metrics")).expect("metrics line is JSON");
+    let get = |key: &str| metrics[key].as_u64().unwrap_or_else(|| panic!("no {} in metrics: {}", key, metrics));
+
+    // Traffic went through the XDP program into the socket
+    assert!(metrics["xdp"]["redirect"].as_u64() > Some(0), "XDP program redirected nothing: {}", metrics);
+
+    // Handshake and version negotiation: timestamps only flow after both
+    assert!(get("rx_timestamps") > 0, "no timestamps received (handshake or version negotiation failed)");
+    assert_eq!(get("codec_errors"), 0, "framing errors on the timestamp/echo streams");
+    assert_eq!(get("timestamps_lost"), 0, "timestamp stream lost packets");
+
+    // Transactions: sent by the client and received by the server, which has no metrics
+    // export, only its shutdown summary
+    assert!(get("tx_transactions") > 0, "client sent no transactions");
+    let summary = server_lines.iter()
+        .find_map(|l| l.strip_prefix("[SERVER] Shutting down. "))
+        .expect("server printed no shutdown summary");
+    let server_count = |label: &str| summary.split(" | ")
+        .find_map(|field| field.strip_prefix(label))
+        .and_then(|n| n.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| panic!("no {:?} in server summary: {}", label, summary));
+    assert!(server_count("RX: ~") > 0, "server received no transactions");
+    assert_eq!(server_count("Codec errors: "), 0, "framing errors on the client's streams");
+
+    // Clean shutdown
+    assert!(client_ok, "client did not exit cleanly on SIGINT");
+    assert!(!xdp_attached, "XDP program was not detached");
+}
+
+/// Whether `iface` still has an XDP program attached.
+fn xdp_attached(iface: &str) -> bool {
+    let out = Command::new("ip").args(["-details", "link", "show", "dev", iface]).output().expect("run ip link");
+    assert!(out.status.success(), "ip link show {} failed", iface);
+    String::from_utf8_lossy(&out.stdout).contains("prog/xdp")
+}
+
+/// Kills the child if the test panics before it is reaped.
+struct Proc(Child);
+
+impl Proc {
+    /// Send SIGINT and wait up to `timeout`, killing the child after that. Returns whether it
+    /// exited successfully.
+    fn interrupt_and_wait(&mut self, timeout: Duration) -> bool {
+        unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGINT) };
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.0.try_wait().expect("try_wait") {
+                // Let the reader thread drain the pipe
+                thread::sleep(Duration::from_millis(100));
+                return status.success();
+            }
+            if start.elapsed() >= timeout {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Proc {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+fn has_caps(caps: &[u32]) -> bool {
+    let Ok(status) = fs::read_to_string("/proc/self/status") else { return false };
+    let eff = status.lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok())
+        .unwrap_or(0);
+    caps.iter().all(|cap| eff & (1 << cap) != 0)
+}
+
+/// A scratch directory holding the self-signed cert.crt/cert.key that stream_server loads.
+fn cert_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("afterburner-e2e-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("create cert dir");
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).expect("generate cert");
+    fs::write(dir.join("cert.crt"), cert.cert.pem()).expect("write cert.crt");
+    fs::write(dir.join("cert.key"), cert.key_pair.serialize_pem()).expect("write cert.key");
+    dir
+}
+
+fn spawn_in_netns(cmd: &mut Command) -> Proc {
+    let ns = File::open(netns::ns_path()).expect("open netns");
+    let ns_fd = ns.as_raw_fd();
+    // Safety: setns is async-signal-safe and only affects the forked child
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setns(ns_fd, libc::CLONE_NEWNET) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Proc(cmd.stdout(Stdio::piped()).spawn().expect("spawn in netns"))
+}
+
+/// Echo a child's stdout with a prefix and forward each line to the returned channel.
+fn collect_lines(child: &mut Child, tag: &'static str) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout piped");
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("  [{}] {}", tag, line);
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,57 @@
+//! A `Write` sink that hands bytes to a background thread, so debug output (keylog, qlog)
+//! never does file I/O on the caller's thread.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Cloneable writer; the file is flushed and closed once every clone has been dropped.
+#[derive(Clone)]
+pub struct BackgroundWriter {
+    tx: Sender<Vec<u8>>,
+}
+
+impl BackgroundWriter {
+    /// Create (truncate) `path` and start its writer thread. Join the handle after dropping
+    /// the writer to be sure everything reached the file.
+    pub fn create(path: &Path) -> Result<(BackgroundWriter, JoinHandle<()>), io::Error> {
+        let file = File::create(path)?;
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("bgwrite".into())
+            .spawn(move || drain(rx, BufWriter::new(file)))?;
+        Ok((BackgroundWriter { tx }, handle))
+    }
+}
+
+impl Write for BackgroundWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "writer thread exited"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn drain(rx: Receiver<Vec<u8>>, mut out: BufWriter<File>) {
+    // Block for the first chunk, take whatever else is queued, then flush, so the file
+    // stays current without a syscall per write
+    while let Ok(chunk) = rx.recv() {
+        if out.write_all(&chunk).is_err() {
+            return;
+        }
+        while let Ok(chunk) = rx.try_recv() {
+            if out.write_all(&chunk).is_err() {
+                return;
+            }
+        }
+        let _ = out.flush();
+    }
+    let _ = out.flush();
+}
@@ -0,0 +1,381 @@
+//! Framing shared by `stream_server` and the client for everything beyond transactions.
+//!
+//! Each frame is `[Len(2, LE)] [Type(1)] [Payload]`, where `Len` counts the type byte and
+//! the payload. QUIC streams are reliable and ordered, so a frame that doesn't parse is a
+//! protocol error, never something to resync past.
+//!
+//! Version negotiation: once the handshake completes the client sends `Hello { min, max }`
+//! on [`CONTROL_STREAM`]; the server answers with `Hello { min: v, max: v }` for the highest
+//! common version `v` as the first frame on [`TIMESTAMP_STREAM`], or closes the connection
+//! with [`ERR_NO_COMMON_VERSION`].
+
+/// Server-initiated unidirectional stream (RFC 9000 §2.1: low bits `11`): the server's
+/// `Hello`, then `Timestamp`s
+pub const TIMESTAMP_STREAM: u64 = 3;
+/// Client-initiated unidirectional stream carrying the client's `Hello`
+pub const CONTROL_STREAM: u64 = 2;
+/// Client-initiated bidirectional stream carrying `Probe`s and their `Echo`s (the
+/// flooder uses streams 0, 4, 8 and 12)
+pub const ECHO_STREAM: u64 = 16;
+
+/// Highest and lowest protocol versions this build speaks
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Application close codes
+pub const ERR_NO_COMMON_VERSION: u64 = 0x101;
+pub const ERR_FRAMING: u64 = 0x102;
+
+pub const HEADER_LEN: usize = 3;
+/// Largest encoded frame
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + 32;
+
+const TYPE_HELLO: u8 = 0x01;
+const TYPE_TIMESTAMP: u8 = 0x02;
+const TYPE_PROBE: u8 = 0x03;
+const TYPE_ECHO: u8 = 0x04;
+
+/// Client -> server round-trip probe, stamped with the client's monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Probe {
+    pub client_tx_ns: u64,
+    pub seq: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Hello { min: u8, max: u8 },
+    /// Server wall-clock stamp and sequence number
+    Timestamp { server_ns: u64, seq: u64 },
+    Probe(Probe),
+    /// The probe plus the server's receive and send stamps (server monotonic clock)
+    Echo { probe: Probe, server_rx_ns: u64, server_tx_ns: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    /// The frame's type byte is not one we know; the frame is `len` bytes long in total
+    UnknownType { kind: u8, len: usize },
+    /// A known frame type with the wrong length: the stream can't be parsed any further
+    BadLength { kind: u8, len: usize },
+    /// `encode` was given a buffer shorter than the frame
+    BufferTooSmall,
+}
+
+impl Frame {
+    fn kind(&self) -> u8 {
+        match self {
+            Frame::Hello { .. } => TYPE_HELLO,
+            Frame::Timestamp { .. } => TYPE_TIMESTAMP,
+            Frame::Probe(_) => TYPE_PROBE,
+            Frame::Echo { .. } => TYPE_ECHO,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Frame::Hello { .. } => 2,
+            Frame::Timestamp { .. } | Frame::Probe(_) => 16,
+            Frame::Echo { .. } => 32,
+        }
+    }
+
+    /// Total encoded length.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload_len()
+    }
+
+    /// Encode into `buf`, returning the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(CodecError::BufferTooSmall);
+        }
+        buf[..2].copy_from_slice(&((1 + self.payload_len()) as u16).to_le_bytes());
+        buf[2] = self.kind();
+        let p = &mut buf[HEADER_LEN..len];
+        match *self {
+            Frame::Hello { min, max } => {
+                p[0] = min;
+                p[1] = max;
+            }
+            Frame::Timestamp { server_ns: a, seq: b } | Frame::Probe(Probe { client_tx_ns: a, seq: b }) => {
+                p[..8].copy_from_slice(&a.to_le_bytes());
+                p[8..16].copy_from_slice(&b.to_le_bytes());
+            }
+            Frame::Echo { probe, server_rx_ns, server_tx_ns } => {
+                p[..8].copy_from_slice(&probe.client_tx_ns.to_le_bytes());
+                p[8..16].copy_from_slice(&probe.seq.to_le_bytes());
+                p[16..24].copy_from_slice(&server_rx_ns.to_le_bytes());
+                p[24..32].copy_from_slice(&server_tx_ns.to_le_bytes());
+            }
+        }
+        Ok(len)
+    }
+
+    /// Decode the frame at the start of `buf`. Returns `Ok(None)` until the whole frame has
+    /// arrived, otherwise the frame and its encoded length.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>, CodecError> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let body_len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        let len = 2 + body_len;
+        if body_len == 0 {
+            // No room for even the type byte
+            return Err(CodecError::BadLength { kind: 0, len });
+        }
+        let kind = buf[2];
+        let expected = match kind {
+            TYPE_HELLO => 2,
+            TYPE_TIMESTAMP | TYPE_PROBE => 16,
+            TYPE_ECHO => 32,
+            _ => {
+                if buf.len() < len {
+                    return Ok(None);
+                }
+                return Err(CodecError::UnknownType { kind, len });
+            }
+        };
+        if body_len != 1 + expected {
+            return Err(CodecError::BadLength { kind, len });
+        }
+        if buf.len() < len {
+            return Ok(None);
+        }
+        let p = &buf[HEADER_LEN..len];
+        let frame = match kind {
+            TYPE_HELLO => Frame::Hello { min: p[0], max: p[1] },
+            TYPE_TIMESTAMP => Frame::Timestamp { server_ns: read_u64(&p[..8]), seq: read_u64(&p[8..16]) },
+            TYPE_PROBE => Frame::Probe(Probe { client_tx_ns: read_u64(&p[..8]), seq: read_u64(&p[8..16]) }),
+            _ => Frame::Echo {
+                probe: Probe { client_tx_ns: read_u64(&p[..8]), seq: read_u64(&p[8..16]) },
+                server_rx_ns: read_u64(&p[16..24]),
+                server_tx_ns: read_u64(&p[24..32]),
+            },
+        };
+        Ok(Some((frame, len)))
+    }
+}
+
+/// Mock transactions are `[Flag(1)] [Signature(64)] [Message(170)]`
+pub const TX_LEN: usize = 235;
+/// Where the client writes `[Seq(8)] [SendTime(8)]` into the message of each transaction, so
+/// the server can measure one-way latency and loss per transaction on either path
+const TX_STAMP_OFFSET: usize = 65;
+
+/// Stamp a serialized transaction with its sequence number and the client's wall-clock send
+/// time (ns since the Unix epoch).
+pub fn stamp_tx(tx: &mut [u8], seq: u64, sent_ns: u64) {
+    tx[TX_STAMP_OFFSET..TX_STAMP_OFFSET + 8].copy_from_slice(&seq.to_le_bytes());
+    tx[TX_STAMP_OFFSET + 8..TX_STAMP_OFFSET + 16].copy_from_slice(&sent_ns.to_le_bytes());
+}
+
+/// `(seq, sent_ns)` from a transaction stamped with [`stamp_tx`].
+pub fn read_tx_stamp(tx: &[u8]) -> Option<(u64, u64)> {
+    if tx.len() < TX_STAMP_OFFSET + 16 {
+        return None;
+    }
+    Some((read_u64(&tx[TX_STAMP_OFFSET..TX_STAMP_OFFSET + 8]), read_u64(&tx[TX_STAMP_OFFSET + 8..TX_STAMP_OFFSET + 16])))
+}
+
+const READER_CAPACITY: usize = 4096;
+
+/// Reassembles frames from stream reads without allocating: read into [`spare`], mark the
+/// bytes with [`commit`], then pull frames with [`next_frame`].
+///
+/// [`spare`]: FrameReader::spare
+/// [`commit`]: FrameReader::commit
+/// [`next_frame`]: FrameReader::next_frame
+pub struct FrameReader {
+    buf: [u8; READER_CAPACITY],
+    start: usize,
+    end: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        FrameReader { buf: [0; READER_CAPACITY], start: 0, end: 0 }
+    }
+
+    /// Free space to read stream data into.
+    pub fn spare(&mut self) -> &mut [u8] {
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        &mut self.buf[self.end..]
+    }
+
+    /// Mark `n` bytes of [`spare`](FrameReader::spare) as filled.
+    pub fn commit(&mut self, n: usize) {
+        self.end = (self.end + n).min(READER_CAPACITY);
+    }
+
+    /// Next complete frame, or `Ok(None)` until more data arrives. A frame of unknown type
+    /// is consumed and reported as an error; after `BadLength` the stream is unparseable
+    /// and the same error is returned again.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, CodecError> {
+        match Frame::decode(&self.buf[self.start..self.end]) {
+            Ok(Some((frame, len))) => {
+                self.start += len;
+                Ok(Some(frame))
+            }
+            // A frame bigger than the whole buffer will never complete
+            Ok(None) if self.end - self.start == READER_CAPACITY => Err(CodecError::BadLength {
+                kind: self.buf[self.start + 2],
+                len: 2 + u16::from_le_bytes([self.buf[self.start], self.buf[self.start + 1]]) as usize,
+            }),
+            Ok(None) => Ok(None),
+            Err(e @ CodecError::UnknownType { len, .. }) => {
+                self.start += len;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Highest version in both `[min, max]` and the range this build speaks.
+pub fn negotiate(min: u8, max: u8) -> Option<u8> {
+    let v = max.min(PROTOCOL_VERSION);
+    (v >= min.max(MIN_PROTOCOL_VERSION)).then_some(v)
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(b);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frame: Frame) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = frame.encode(&mut buf).unwrap();
+        (buf, len)
+    }
+
+    fn push(reader: &mut FrameReader, bytes: &[u8]) {
+        reader.spare()[..bytes.len()].copy_from_slice(bytes);
+        reader.commit(bytes.len());
+    }
+
+    const PROBE: Probe = Probe { client_tx_ns: 0x0102_0304_0506_0708, seq: 42 };
+
+    #[test]
+    fn every_frame_type_round_trips() {
+        for frame in [
+            Frame::Hello { min: 1, max: 3 },
+            Frame::Timestamp { server_ns: u64::MAX, seq: 7 },
+            Frame::Probe(PROBE),
+            Frame::Echo { probe: PROBE, server_rx_ns: 100, server_tx_ns: 250 },
+        ] {
+            let (buf, len) = encode(frame);
+            assert_eq!(len, frame.encoded_len());
+            assert_eq!(Frame::decode(&buf[..len]), Ok(Some((frame, len))));
+            // Every proper prefix is incomplete rather than an error
+            for cut in 0..len {
+                assert_eq!(Frame::decode(&buf[..cut]), Ok(None));
+            }
+        }
+    }
+
+    #[test]
+    fn encode_needs_room_for_the_whole_frame() {
+        let frame = Frame::Timestamp { server_ns: 1, seq: 2 };
+        let mut buf = [0u8; 10];
+        assert_eq!(frame.encode(&mut buf), Err(CodecError::BufferTooSmall));
+    }
+
+    #[test]
+    fn frame_split_across_pushes() {
+        let frame = Frame::Echo { probe: PROBE, server_rx_ns: 1, server_tx_ns: 2 };
+        let (buf, len) = encode(frame);
+        let mut reader = FrameReader::new();
+        push(&mut reader, &buf[..2]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        push(&mut reader, &buf[2..20]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        push(&mut reader, &buf[20..len]);
+        assert_eq!(reader.next_frame(), Ok(Some(frame)));
+        assert_eq!(reader.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn unknown_type_is_skipped() {
+        let mut reader = FrameReader::new();
+        // Type 0x7f with a 3-byte payload, then a Timestamp
+        push(&mut reader, &[4, 0, 0x7f, 0xaa, 0xbb, 0xcc]);
+        let frame = Frame::Timestamp { server_ns: 5, seq: 6 };
+        let (buf, len) = encode(frame);
+        push(&mut reader, &buf[..len]);
+        assert_eq!(reader.next_frame(), Err(CodecError::UnknownType { kind: 0x7f, len: 6 }));
+        assert_eq!(reader.next_frame(), Ok(Some(frame)));
+    }
+
+    #[test]
+    fn bad_length_is_sticky() {
+        let mut reader = FrameReader::new();
+        // A Timestamp claiming a 3-byte payload instead of 16
+        push(&mut reader, &[4, 0, TYPE_TIMESTAMP, 0, 0, 0]);
+        let err = Err(CodecError::BadLength { kind: TYPE_TIMESTAMP, len: 6 });
+        assert_eq!(reader.next_frame(), err);
+        let (buf, len) = encode(Frame::Hello { min: 1, max: 1 });
+        push(&mut reader, &buf[..len]);
+        assert_eq!(reader.next_frame(), err);
+        assert_eq!(reader.next_frame(), err);
+    }
+
+    #[test]
+    fn zero_length_has_no_type() {
+        assert_eq!(Frame::decode(&[0, 0, TYPE_HELLO]), Err(CodecError::BadLength { kind: 0, len: 2 }));
+    }
+
+    #[test]
+    fn oversize_frame_fails_once_the_reader_is_full() {
+        let mut reader = FrameReader::new();
+        // An unknown type claiming 0xffff bytes can never fit in the reader
+        push(&mut reader, &[0xff, 0xff, 0x7f]);
+        assert_eq!(reader.next_frame(), Ok(None));
+        let filler = [0u8; READER_CAPACITY - 3];
+        push(&mut reader, &filler);
+        let err = Err(CodecError::BadLength { kind: 0x7f, len: 2 + 0xffff });
+        assert_eq!(reader.next_frame(), err);
+        assert_eq!(reader.next_frame(), err);
+    }
+
+    #[test]
+    fn hello_version_negotiation() {
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(0, u8::MAX), Some(PROTOCOL_VERSION));
+        // Only newer versions on offer
+        assert_eq!(negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3), None);
+        // Only older versions on offer
+        assert_eq!(negotiate(0, MIN_PROTOCOL_VERSION - 1), None);
+        // An inverted range never matches
+        assert_eq!(negotiate(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1), None);
+    }
+
+    #[test]
+    fn tx_stamp_lands_in_the_message() {
+        let mut tx = [0u8; TX_LEN];
+        stamp_tx(&mut tx, 9, 0x1122_3344_5566_7788);
+        // Flag and signature are untouched
+        assert!(tx[..TX_STAMP_OFFSET].iter().all(|&b| b == 0));
+        assert_eq!(tx[TX_STAMP_OFFSET..TX_STAMP_OFFSET + 8], 9u64.to_le_bytes());
+        assert_eq!(tx[TX_STAMP_OFFSET + 8..TX_STAMP_OFFSET + 16], 0x1122_3344_5566_7788u64.to_le_bytes());
+        assert!(tx[TX_STAMP_OFFSET + 16..].iter().all(|&b| b == 0));
+        assert_eq!(read_tx_stamp(&tx), Some((9, 0x1122_3344_5566_7788)));
+        assert_eq!(read_tx_stamp(&tx[..TX_STAMP_OFFSET + 15]), None);
+    }
+}
@@ -1 +1,48 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+pub mod bgwrite;
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod logging;
+
+/// eBPF global (`u16`) holding the UDP destination port redirected to AF_XDP; set at load
+/// time so the program follows the configured local address.
+pub const PORT_GLOBAL: &str = "AFTERBURNER_PORT";
+
+/// The XDP program that also reads the NIC's RX timestamp into [`RxMeta`]; it calls an RX
+/// metadata kfunc, so it has to be loaded device-bound
+pub const HW_PROGRAM: &str = "afterburner_hw";
+
+/// Entries in the eBPF `XSK` map, keyed by RX queue; queues at or above this can't be bound
+pub const XSK_MAP_SIZE: u32 = 4;
+
+// Indexes into the eBPF `STATS` per-CPU array.
+/// Packets redirected to the AF_XDP socket
+pub const XDP_STAT_REDIRECT: u32 = 0;
+/// Packets for our port that had no socket to go to (passed to the stack instead)
+pub const XDP_STAT_REDIRECT_FAILED: u32 = 1;
+/// Packets passed to the kernel stack (includes redirect failures)
+pub const XDP_STAT_PASS: u32 = 2;
+/// Packets dropped because they were too short to parse
+pub const XDP_STAT_ABORTED: u32 = 3;
+pub const XDP_STATS_LEN: u32 = 4;
+
+/// Per-packet metadata the XDP program writes in front of redirected frames (via
+/// `bpf_xdp_adjust_meta`), i.e. at `addr - size_of::<RxMeta>()` in the UMEM.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RxMeta {
+    /// [`RX_META_MAGIC`] when the XDP program wrote this frame's metadata
+    pub magic: u32,
+    /// `RX_META_*` flags
+    pub flags: u32,
+    /// `bpf_ktime_get_ns()` (CLOCK_MONOTONIC) when the XDP program ran
+    pub xdp_ns: u64,
+    /// NIC receive timestamp (NIC clock), valid if [`RX_META_HW_TS`] is set
+    pub hw_ns: u64,
+}
+
+pub const RX_META_MAGIC: u32 = 0xAFB0_7E57;
+/// `hw_ns` holds a hardware RX timestamp
+pub const RX_META_HW_TS: u32 = 1;
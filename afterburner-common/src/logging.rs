@@ -0,0 +1,132 @@
+//! Logger setup shared by the client and `stream_server`, plus rate limiting for warnings
+//! that can fire on the hot path.
+//!
+//! Filtering follows `RUST_LOG` (default `info`), e.g. `RUST_LOG=info,xsk=debug`. Targets are
+//! short subsystem names (`xdp`, `xsk`, `quic`, `stats`, ...). Text output keeps the familiar
+//! `[TARGET] message` shape, with the level spelled out for anything but info; JSON output
+//! is one object per line: `{"ts_ms":..,"level":"..","target":"..","msg":".."}`.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[doc(hidden)]
+pub use log as __log;
+use log::{Level, LevelFilter};
+
+/// Install the global logger, writing to stdout. Safe to call more than once.
+pub fn init(json: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(LevelFilter::Info)
+        .parse_default_env()
+        .target(env_logger::Target::Stdout);
+    if json {
+        builder.format(|buf, record| {
+            let ts_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis());
+            let mut line = String::with_capacity(128);
+            line.push_str(&format!(
+                "{{\"ts_ms\":{},\"level\":\"{}\",\"target\":",
+                ts_ms,
+                record.level()
+            ));
+            push_json_str(&mut line, record.target());
+            line.push_str(",\"msg\":");
+            push_json_str(&mut line, &record.args().to_string());
+            line.push('}');
+            writeln!(buf, "{}", line)
+        });
+    } else {
+        builder.format(|buf, record| {
+            let target = record
+                .target()
+                .rsplit("::")
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            match record.level() {
+                Level::Info => writeln!(buf, "[{}] {}", target, record.args()),
+                level => writeln!(buf, "[{}] {}: {}", target, level, record.args()),
+            }
+        });
+    }
+    let _ = builder.try_init();
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Lets one event through per interval and counts the rest. Use through
+/// [`warn_ratelimited!`](crate::warn_ratelimited).
+pub struct RateLimit {
+    /// Nanoseconds since process start of the last event let through, plus one (0 = never)
+    last_ns: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimit {
+    pub const fn new() -> Self {
+        RateLimit {
+            last_ns: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// `Some(n)` if the event may be logged, `n` being how many were suppressed since the
+    /// last one; `None` if it should be dropped.
+    pub fn check(&self, interval: Duration) -> Option<u64> {
+        static START: OnceLock<Instant> = OnceLock::new();
+        let now = START.get_or_init(Instant::now).elapsed().as_nanos() as u64 + 1;
+        let last = self.last_ns.load(Ordering::Relaxed);
+        if last != 0 && now - last < interval.as_nanos() as u64 {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if self
+            .last_ns
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// `log::warn!` at most once per second per call site, noting how many were suppressed.
+#[macro_export]
+macro_rules! warn_ratelimited {
+    (target: $target:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::logging::RateLimit = $crate::logging::RateLimit::new();
+        if let Some(suppressed) = LIMIT.check(::std::time::Duration::from_secs(1)) {
+            if suppressed > 0 {
+                $crate::logging::__log::warn!(target: $target, "{} ({} suppressed)", format_args!($($arg)+), suppressed);
+            } else {
+                $crate::logging::__log::warn!(target: $target, $($arg)+);
+            }
+        }
+    }};
+}
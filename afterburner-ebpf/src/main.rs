@@ -1,12 +1,18 @@
 #![no_std]
 #![no_main]
 
+use afterburner_common::{
+    RxMeta, RX_META_HW_TS, RX_META_MAGIC, XDP_STATS_LEN, XDP_STAT_ABORTED, XDP_STAT_PASS, XDP_STAT_REDIRECT,
+    XDP_STAT_REDIRECT_FAILED, XSK_MAP_SIZE,
+};
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{xdp_action, xdp_md},
+    helpers::{bpf_ktime_get_ns, bpf_xdp_adjust_meta},
     macros::{map, xdp},
-    maps::XskMap,
+    maps::{PerCpuArray, XskMap},
     programs::XdpContext,
 };
+use aya_log_ebpf::{debug, warn};
 use core::mem;
 use network_types::{
     eth::{EthHdr, EtherType},
@@ -15,40 +21,118 @@ use network_types::{
 };
 
 #[map]
-static XSK: XskMap = XskMap::with_max_entries(4, 0);
+static XSK: XskMap = XskMap::with_max_entries(XSK_MAP_SIZE, 0);
+
+/// Overridden by the loader (see `afterburner_common::PORT_GLOBAL`)
+#[no_mangle]
+static AFTERBURNER_PORT: u16 = 8000;
+
+#[map]
+static STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(XDP_STATS_LEN, 0);
+
+extern "C" {
+    /// XDP RX metadata kfunc (Linux 6.3+): the NIC's receive timestamp, 0 on success. Only
+    /// device-bound programs may call it; the loader resolves it against the kernel's BTF.
+    fn bpf_xdp_metadata_rx_timestamp(ctx: *const xdp_md, timestamp: *mut u64) -> i32;
+}
 
 #[xdp]
 pub fn afterburner(ctx: XdpContext) -> u32 {
-    match try_afterburner(ctx) {
+    run::<false>(&ctx)
+}
+
+/// `afterburner` plus the NIC's RX timestamp in `RxMeta`, for a device-bound load
+/// (see `afterburner_common::HW_PROGRAM`)
+#[xdp]
+pub fn afterburner_hw(ctx: XdpContext) -> u32 {
+    run::<true>(&ctx)
+}
+
+#[inline(always)]
+fn run<const HW: bool>(ctx: &XdpContext) -> u32 {
+    let action = match try_afterburner::<HW>(ctx) {
         Ok(ret) => ret,
-        Err(_) => xdp_action::XDP_ABORTED,
+        Err(_) => {
+            warn!(ctx, "truncated packet, aborting");
+            xdp_action::XDP_ABORTED
+        }
+    };
+    count(match action {
+        xdp_action::XDP_REDIRECT => XDP_STAT_REDIRECT,
+        xdp_action::XDP_PASS => XDP_STAT_PASS,
+        _ => XDP_STAT_ABORTED,
+    });
+    action
+}
+
+#[inline(always)]
+fn count(idx: u32) {
+    if let Some(c) = STATS.get_ptr_mut(idx) {
+        unsafe { *c += 1 };
     }
 }
 
 #[inline(always)]
-fn try_afterburner(ctx: XdpContext) -> Result<u32, ()> {
-    let eth = ptr_at::<EthHdr>(&ctx, 0).ok_or(())?;
+fn try_afterburner<const HW: bool>(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth = ptr_at::<EthHdr>(ctx, 0).ok_or(())?;
 
     match eth.ether_type {
         EtherType::Ipv4 => {}
         _ => return Ok(xdp_action::XDP_PASS),
     }
 
-    let ip = ptr_at::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(())?;
+    let ip = ptr_at::<Ipv4Hdr>(ctx, EthHdr::LEN).ok_or(())?;
     if ip.proto != IpProto::Udp {
         return Ok(xdp_action::XDP_PASS);
     }
 
-    let udp = ptr_at::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(())?;
+    let udp = ptr_at::<UdpHdr>(ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(())?;
 
-    if u16::from_be(udp.dest) == 8000 {
-        return Ok(XSK.redirect(0, 0).unwrap_or(xdp_action::XDP_PASS));
+    // Volatile so the compiler can't fold in the default before the loader patches it
+    let port = unsafe { core::ptr::read_volatile(&AFTERBURNER_PORT) };
+    if u16::from_be(udp.dest) == port {
+        stamp_meta::<HW>(ctx);
+        // Each queue has its own slot; a socket only accepts frames from the queue it's bound to
+        let queue = unsafe { (*ctx.ctx).rx_queue_index };
+        return Ok(XSK.redirect(queue, 0).unwrap_or_else(|_| {
+            count(XDP_STAT_REDIRECT_FAILED);
+            debug!(ctx, "no AF_XDP socket on queue {}, passing to the stack", queue);
+            xdp_action::XDP_PASS
+        }));
         // return Ok(xdp_action::XDP_PASS);
     }
 
     Ok(xdp_action::XDP_PASS)
 }
 
+/// Prepend an `RxMeta` with the XDP entry time and, with `HW`, the NIC's RX timestamp if
+/// the driver has one for this frame. Drivers without metadata support (and kernels that
+/// refuse the headroom) just leave the frame without one.
+#[inline(always)]
+fn stamp_meta<const HW: bool>(ctx: &XdpContext) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let mut hw_ns = 0u64;
+    // -ENODATA/-EOPNOTSUPP when the driver has no timestamp for the frame (timestamping
+    // off, or no support); veth instead succeeds with the skb's, which is usually 0
+    let flags = if HW && unsafe { bpf_xdp_metadata_rx_timestamp(ctx.ctx, &mut hw_ns) } == 0 && hw_ns != 0 {
+        RX_META_HW_TS
+    } else {
+        0
+    };
+    let len = mem::size_of::<RxMeta>() as i32;
+    if unsafe { bpf_xdp_adjust_meta(ctx.ctx, -len) } != 0 {
+        return;
+    }
+    // Packet pointers are invalidated by the adjust; re-read and bounds check for the verifier
+    let meta = ctx.metadata();
+    if meta + len as usize > ctx.data() {
+        return;
+    }
+    unsafe {
+        *(meta as *mut RxMeta) = RxMeta { magic: RX_META_MAGIC, flags, xdp_ns: now, hw_ns };
+    }
+}
+
 #[inline(always)]
 fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<&T> {
     let start = ctx.data();
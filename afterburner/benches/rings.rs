@@ -0,0 +1,332 @@
+//! Shared ring-index accesses per packet: the old one-descriptor-at-a-time ring code against
+//! the batched, index-caching rings in `src/xsk_ring.rs`.
+//!
+//! Both drive the same in-memory fill/RX/TX/completion rings, with the kernel's side played
+//! by the same thread: each round it turns `burst` fill entries into RX descriptors, and
+//! completes every TX descriptor. Counted are acquire loads of the other side's index and
+//! release stores of our own, i.e. the accesses that bounce a cache line to the kernel's core
+//! on real hardware.
+//!
+//!     cargo bench -p afterburner --features ring-stats --bench rings
+
+#[path = "../src/xsk_ring.rs"]
+#[allow(dead_code)]
+mod xsk_ring;
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+use xsk_ring::{ConsRing, ProdRing, SyncStats};
+
+const RING_SIZE: u32 = 2048;
+const NUM_FRAMES: u64 = 2 * RING_SIZE as u64;
+const FRAME_SIZE: u64 = 4096;
+const BATCH: u32 = 64;
+const PACKETS: u64 = 1 << 22;
+
+#[derive(Clone, Copy, Default)]
+struct Desc {
+    addr: u64,
+    len: u32,
+}
+
+/// One ring's memory: the two shared indices and the descriptor array, which (as with the
+/// kernel's mmapped rings) is only accessed through raw pointers.
+struct RingMem<T> {
+    producer: AtomicU32,
+    consumer: AtomicU32,
+    desc: *mut T,
+}
+
+impl<T: Copy + Default> RingMem<T> {
+    fn new() -> Box<Self> {
+        let desc = Box::leak(vec![T::default(); RING_SIZE as usize].into_boxed_slice()).as_mut_ptr();
+        Box::new(RingMem { producer: AtomicU32::new(0), consumer: AtomicU32::new(0), desc })
+    }
+
+    fn prod(&self) -> ProdRing<T> {
+        unsafe { ProdRing::new(&self.producer, &self.consumer, self.desc, RING_SIZE) }
+    }
+
+    fn cons(&self) -> ConsRing<T> {
+        unsafe { ConsRing::new(&self.producer, &self.consumer, self.desc, RING_SIZE) }
+    }
+
+    fn slot(&self, idx: u32) -> *mut T {
+        unsafe { self.desc.add((idx & (RING_SIZE - 1)) as usize) }
+    }
+}
+
+struct Rings {
+    fill: Box<RingMem<u64>>,
+    rx: Box<RingMem<Desc>>,
+    tx: Box<RingMem<Desc>>,
+    comp: Box<RingMem<u64>>,
+}
+
+impl Rings {
+    /// Fresh rings with the RX half of the frames on the fill ring.
+    fn new() -> Self {
+        let rings = Rings { fill: RingMem::new(), rx: RingMem::new(), tx: RingMem::new(), comp: RingMem::new() };
+        let mut fill = rings.fill.prod();
+        let idx = fill.reserve(RING_SIZE).unwrap();
+        for i in 0..RING_SIZE {
+            fill.set(idx + i, i as u64 * FRAME_SIZE);
+        }
+        fill.submit(RING_SIZE);
+        rings
+    }
+}
+
+/// The kernel's side of the rings.
+struct Kernel {
+    fill: ConsRing<u64>,
+    rx: ProdRing<Desc>,
+    tx: ConsRing<Desc>,
+    comp: ProdRing<u64>,
+}
+
+impl Kernel {
+    fn new(rings: &Rings) -> Self {
+        Kernel { fill: rings.fill.cons(), rx: rings.rx.prod(), tx: rings.tx.cons(), comp: rings.comp.prod() }
+    }
+
+    /// Receive up to `burst` packets into frames from the fill ring.
+    fn receive(&mut self, burst: u32) {
+        let (fill_idx, n) = self.fill.peek(burst);
+        // Both rings hold every RX frame, so there is always room for what was filled
+        let rx_idx = self.rx.reserve(n).expect("RX space");
+        for i in 0..n {
+            self.rx.set(rx_idx + i, Desc { addr: self.fill.get(fill_idx + i), len: 64 });
+        }
+        self.fill.release(n);
+        self.rx.submit(n);
+    }
+
+    /// Send everything on the TX ring and complete it.
+    fn complete(&mut self) {
+        loop {
+            let (tx_idx, n) = self.tx.peek(u32::MAX);
+            if n == 0 {
+                return;
+            }
+            let comp_idx = self.comp.reserve(n).unwrap();
+            for i in 0..n {
+                self.comp.set(comp_idx + i, black_box(self.tx.get(tx_idx + i)).addr);
+            }
+            self.tx.release(n);
+            self.comp.submit(n);
+        }
+    }
+}
+
+/// Per-packet ring code as `XdpSocket` had it before the batch API: both indices of a ring are
+/// loaded and ours stored for every descriptor, and the completion ring drained on every frame.
+struct Legacy<'a> {
+    rings: &'a Rings,
+    rx_cons: u32,
+    fill_prod: u32,
+    tx_prod: u32,
+    comp_cons: u32,
+    free: Vec<u64>,
+    stats: SyncStats,
+}
+
+impl<'a> Legacy<'a> {
+    fn new(rings: &'a Rings) -> Self {
+        Legacy {
+            rings,
+            rx_cons: 0,
+            fill_prod: rings.fill.producer.load(Ordering::Relaxed),
+            tx_prod: 0,
+            comp_cons: 0,
+            free: (RING_SIZE as u64..NUM_FRAMES).map(|i| i * FRAME_SIZE).collect(),
+            stats: SyncStats::default(),
+        }
+    }
+
+    fn poll_rx(&mut self) -> Option<Desc> {
+        self.stats.loads += 1;
+        if self.rx_cons == self.rings.rx.producer.load(Ordering::Acquire) {
+            return None;
+        }
+        let desc = unsafe { *self.rings.rx.slot(self.rx_cons) };
+        self.rx_cons = self.rx_cons.wrapping_add(1);
+        self.stats.stores += 1;
+        self.rings.rx.consumer.store(self.rx_cons, Ordering::Release);
+        Some(desc)
+    }
+
+    fn release_frame(&mut self, addr: u64) {
+        self.stats.loads += 1;
+        let cons = self.rings.fill.consumer.load(Ordering::Acquire);
+        assert!(self.fill_prod.wrapping_sub(cons) < RING_SIZE);
+        unsafe { *self.rings.fill.slot(self.fill_prod) = addr };
+        self.fill_prod = self.fill_prod.wrapping_add(1);
+        self.stats.stores += 1;
+        self.rings.fill.producer.store(self.fill_prod, Ordering::Release);
+    }
+
+    fn get_tx_frame(&mut self) -> Option<u64> {
+        self.stats.loads += 1;
+        let prod = self.rings.comp.producer.load(Ordering::Acquire);
+        let start = self.comp_cons;
+        while self.comp_cons != prod {
+            self.free.push(unsafe { *self.rings.comp.slot(self.comp_cons) });
+            self.comp_cons = self.comp_cons.wrapping_add(1);
+        }
+        if self.comp_cons != start {
+            self.stats.stores += 1;
+            self.rings.comp.consumer.store(self.comp_cons, Ordering::Release);
+        }
+        self.stats.loads += 1;
+        if self.tx_prod.wrapping_sub(self.rings.tx.consumer.load(Ordering::Acquire)) >= RING_SIZE {
+            return None;
+        }
+        self.free.pop()
+    }
+
+    fn tx_submit(&mut self, addr: u64, len: u32) {
+        unsafe { *self.rings.tx.slot(self.tx_prod) = Desc { addr, len } };
+        self.tx_prod = self.tx_prod.wrapping_add(1);
+        self.stats.stores += 1;
+        self.rings.tx.producer.store(self.tx_prod, Ordering::Release);
+    }
+
+    /// Echo everything received, one descriptor at a time.
+    fn round(&mut self) -> u64 {
+        let mut n = 0;
+        while let Some(desc) = self.poll_rx() {
+            black_box(desc.len);
+            self.release_frame(desc.addr);
+            n += 1;
+        }
+        for _ in 0..n {
+            let addr = self.get_tx_frame().expect("TX frame");
+            self.tx_submit(addr, 64);
+        }
+        n
+    }
+}
+
+/// The same echo through the batch API, as `XdpSocket` now drives the rings.
+struct Batched {
+    rx: ConsRing<Desc>,
+    fill: ProdRing<u64>,
+    tx: ProdRing<Desc>,
+    comp: ConsRing<u64>,
+    free: Vec<u64>,
+}
+
+impl Batched {
+    fn new(rings: &Rings) -> Self {
+        Batched {
+            rx: rings.rx.cons(),
+            fill: rings.fill.prod(),
+            tx: rings.tx.prod(),
+            comp: rings.comp.cons(),
+            free: (RING_SIZE as u64..NUM_FRAMES).map(|i| i * FRAME_SIZE).collect(),
+        }
+    }
+
+    fn round(&mut self) -> u64 {
+        let mut received = 0;
+        loop {
+            let (rx_idx, n) = self.rx.peek(BATCH);
+            if n == 0 {
+                break;
+            }
+            let fill_idx = self.fill.reserve(n).expect("fill space");
+            for i in 0..n {
+                let desc = self.rx.get(rx_idx + i);
+                black_box(desc.len);
+                self.fill.set(fill_idx + i, desc.addr);
+            }
+            self.fill.submit(n);
+            self.rx.release(n);
+            received += n;
+        }
+
+        let mut sent = 0;
+        while sent < received {
+            let (comp_idx, done) = self.comp.peek(u32::MAX);
+            if done > 0 {
+                for i in 0..done {
+                    self.free.push(self.comp.get(comp_idx + i));
+                }
+                self.comp.release(done);
+            }
+            let want = BATCH.min(received - sent).min(self.free.len() as u32);
+            let n = self.tx.free(want).min(want);
+            let tx_idx = self.tx.reserve(n).expect("TX space");
+            for i in 0..n {
+                let addr = self.free.pop().unwrap();
+                self.tx.set(tx_idx + i, Desc { addr, len: 64 });
+            }
+            self.tx.submit(n);
+            sent += n;
+        }
+        received as u64
+    }
+
+    fn stats(&self) -> SyncStats {
+        let sides = [self.rx.stats, self.fill.stats, self.tx.stats, self.comp.stats];
+        sides.iter().fold(SyncStats::default(), |acc, s| SyncStats { loads: acc.loads + s.loads, stores: acc.stores + s.stores })
+    }
+}
+
+struct Sample {
+    ns_per_packet: f64,
+    loads_per_packet: f64,
+    stores_per_packet: f64,
+}
+
+fn run(burst: u32, mut round: impl FnMut() -> u64, kernel: &mut Kernel) -> (u64, f64) {
+    let start = Instant::now();
+    let mut packets = 0;
+    while packets < PACKETS {
+        kernel.receive(burst);
+        packets += round();
+        kernel.complete();
+    }
+    (packets, start.elapsed().as_nanos() as f64)
+}
+
+fn legacy(burst: u32) -> Sample {
+    let rings = Rings::new();
+    let mut kernel = Kernel::new(&rings);
+    let mut app = Legacy::new(&rings);
+    let (packets, ns) = run(burst, || app.round(), &mut kernel);
+    result(packets, ns, app.stats)
+}
+
+fn batched(burst: u32) -> Sample {
+    let rings = Rings::new();
+    let mut kernel = Kernel::new(&rings);
+    let mut app = Batched::new(&rings);
+    let (packets, ns) = run(burst, || app.round(), &mut kernel);
+    result(packets, ns, app.stats())
+}
+
+fn result(packets: u64, ns: f64, stats: SyncStats) -> Sample {
+    let packets = packets as f64;
+    Sample {
+        ns_per_packet: ns / packets,
+        loads_per_packet: stats.loads as f64 / packets,
+        stores_per_packet: stats.stores as f64 / packets,
+    }
+}
+
+fn main() {
+    println!("{} packets per run, RX/TX batch {}", PACKETS, BATCH);
+    println!("{:>6}  {:<8} {:>8} {:>10} {:>10}", "burst", "rings", "ns/pkt", "loads/pkt", "stores/pkt");
+    for burst in [1, 8, 64, 256] {
+        for (name, r) in [("legacy", legacy(burst)), ("batched", batched(burst))] {
+            println!(
+                "{:>6}  {:<8} {:>8.1} {:>10.3} {:>10.3}",
+                burst, name, r.ns_per_packet, r.loads_per_packet, r.stores_per_packet
+            );
+        }
+    }
+}
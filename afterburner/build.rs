@@ -0,0 +1,64 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Builds the eBPF program through xtask and stages the object in `OUT_DIR` so that
+/// `engine.rs` can embed it with `include_bytes_aligned!`.
+///
+/// Set `AFTERBURNER_EBPF_OBJ` to embed a prebuilt object instead (e.g. on machines
+/// without `bpf-linker`). If the eBPF build fails, the build fails too, unless
+/// `AFTERBURNER_ALLOW_EMPTY_EBPF=1` is set: then an empty object is embedded with a warning,
+/// so the tests compile on a stock toolchain, and `EngineBuilder::build` fails unless an
+/// object is loaded from disk.
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR"));
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR"));
+    let root = manifest_dir.parent().expect("Could not find workspace root");
+    let dest = out_dir.join("afterburner");
+
+    println!("cargo:rerun-if-env-changed=AFTERBURNER_EBPF_OBJ");
+    println!("cargo:rerun-if-env-changed=AFTERBURNER_ALLOW_EMPTY_EBPF");
+    if let Ok(prebuilt) = env::var("AFTERBURNER_EBPF_OBJ") {
+        println!("cargo:rerun-if-changed={}", prebuilt);
+        std::fs::copy(&prebuilt, &dest)
+            .unwrap_or_else(|e| panic!("copy AFTERBURNER_EBPF_OBJ={}: {}", prebuilt, e));
+        return;
+    }
+
+    for path in ["afterburner-ebpf/src", "afterburner-ebpf/Cargo.toml", "afterburner-common/src"] {
+        println!("cargo:rerun-if-changed={}", root.join(path).display());
+    }
+
+    if let Err(e) = build_ebpf(root, &out_dir, &dest) {
+        if env::var("AFTERBURNER_ALLOW_EMPTY_EBPF").as_deref() != Ok("1") {
+            panic!("Failed to build eBPF program ({}). Install nightly and bpf-linker (see README), set AFTERBURNER_EBPF_OBJ, or set AFTERBURNER_ALLOW_EMPTY_EBPF=1 to build without one", e);
+        }
+        println!("cargo:warning=Failed to build eBPF program ({}); embedding none, so the engine needs --bpf-object at runtime", e);
+        std::fs::write(&dest, b"").expect("write empty eBPF object");
+    }
+}
+
+fn build_ebpf(root: &Path, out_dir: &Path, dest: &Path) -> Result<(), String> {
+    // Separate target dir: the outer cargo holds the lock on the workspace one
+    let target_dir = out_dir.join("ebpf");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+    let status = Command::new(cargo)
+        .current_dir(root)
+        .args(["run", "--package", "xtask", "--release", "--", "build-ebpf", "--release"])
+        .env("CARGO_TARGET_DIR", &target_dir)
+        // Host build settings must not leak into the BPF cross-compile
+        .env_remove("RUSTC")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .status()
+        .map_err(|e| format!("run cargo: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("xtask build-ebpf exited with {}", status));
+    }
+
+    let obj = target_dir.join("bpfel-unknown-none/release/afterburner");
+    std::fs::copy(&obj, dest).map_err(|e| format!("copy {}: {}", obj.display(), e))?;
+    Ok(())
+}
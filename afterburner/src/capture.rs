@@ -0,0 +1,213 @@
+//! pcapng capture of the AF_XDP data path, which `tcpdump` can't see.
+//!
+//! The hot loop copies (sampled, snaplen-truncated) frames into a fixed ring of slots; a
+//! background thread drains the ring into a pcapng file. When the writer falls behind,
+//! frames are dropped and counted rather than stalling the loop.
+
+use std::cell::UnsafeCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::xsk::FRAME_SIZE;
+
+const RING_SLOTS: usize = 1024;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const EPB_FLAG_INBOUND: u32 = 1;
+const EPB_FLAG_OUTBOUND: u32 = 2;
+
+pub struct CaptureConfig {
+    pub path: PathBuf,
+    pub iface: String,
+    /// Bytes kept per frame
+    pub snaplen: usize,
+    /// Capture one in every `sample` frames per direction
+    pub sample: u32,
+}
+
+struct Slot {
+    ts_ns: u64,
+    orig_len: u32,
+    cap_len: u32,
+    flags: u32,
+    data: [u8; FRAME_SIZE],
+}
+
+/// Single-producer, single-consumer ring of capture slots.
+struct Ring {
+    slots: Box<[UnsafeCell<Slot>]>,
+    /// Next slot the producer fills
+    head: AtomicUsize,
+    /// Next slot the consumer drains
+    tail: AtomicUsize,
+    stop: AtomicBool,
+    written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// Safety: a slot is only touched by the producer while it is outside [tail, head) and only
+// by the consumer while inside it; the head/tail Release/Acquire pairs hand slots over.
+unsafe impl Sync for Ring {}
+
+pub struct Capture {
+    ring: Arc<Ring>,
+    snaplen: usize,
+    sample: u32,
+    rx_seen: u32,
+    tx_seen: u32,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl Capture {
+    pub fn start(cfg: CaptureConfig) -> Result<Capture, io::Error> {
+        let mut out = BufWriter::new(File::create(&cfg.path)?);
+        let snaplen = cfg.snaplen.clamp(1, FRAME_SIZE);
+        write_section_header(&mut out)?;
+        write_interface(&mut out, &cfg.iface, snaplen as u32)?;
+
+        let slots = (0..RING_SLOTS)
+            .map(|_| UnsafeCell::new(Slot { ts_ns: 0, orig_len: 0, cap_len: 0, flags: 0, data: [0; FRAME_SIZE] }))
+            .collect();
+        let ring = Arc::new(Ring {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            stop: AtomicBool::new(false),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let writer_ring = Arc::clone(&ring);
+        let writer = thread::Builder::new()
+            .name("pcap".into())
+            .spawn(move || drain(&writer_ring, out))?;
+
+        Ok(Capture { ring, snaplen, sample: cfg.sample.max(1), rx_seen: 0, tx_seen: 0, writer })
+    }
+
+    #[inline(always)]
+    pub fn rx(&mut self, frame: &[u8]) {
+        self.rx_seen = self.rx_seen.wrapping_add(1);
+        if self.rx_seen.is_multiple_of(self.sample) {
+            self.push(frame, EPB_FLAG_INBOUND);
+        }
+    }
+
+    #[inline(always)]
+    pub fn tx(&mut self, frame: &[u8]) {
+        self.tx_seen = self.tx_seen.wrapping_add(1);
+        if self.tx_seen.is_multiple_of(self.sample) {
+            self.push(frame, EPB_FLAG_OUTBOUND);
+        }
+    }
+
+    fn push(&mut self, frame: &[u8], flags: u32) {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.ring.tail.load(Ordering::Acquire)) >= RING_SLOTS {
+            self.ring.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let cap_len = frame.len().min(self.snaplen);
+        // Safety: the slot at head is outside [tail, head), so the consumer isn't reading it
+        let slot = unsafe { &mut *self.ring.slots[head % RING_SLOTS].get() };
+        slot.ts_ns = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        slot.orig_len = frame.len() as u32;
+        slot.cap_len = cap_len as u32;
+        slot.flags = flags;
+        slot.data[..cap_len].copy_from_slice(&frame[..cap_len]);
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Write out everything queued, close the file and return `(written, dropped)`.
+    pub fn finish(self) -> Result<(u64, u64), io::Error> {
+        self.ring.stop.store(true, Ordering::Release);
+        self.writer.join().map_err(|_| io::Error::other("pcap writer panicked"))??;
+        Ok((self.ring.written.load(Ordering::Relaxed), self.ring.dropped.load(Ordering::Relaxed)))
+    }
+}
+
+fn drain(ring: &Ring, mut out: BufWriter<File>) -> io::Result<()> {
+    loop {
+        let head = ring.head.load(Ordering::Acquire);
+        let mut tail = ring.tail.load(Ordering::Relaxed);
+        if tail == head {
+            if ring.stop.load(Ordering::Acquire) && ring.head.load(Ordering::Acquire) == tail {
+                return out.flush();
+            }
+            out.flush()?;
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        while tail != head {
+            // Safety: slots in [tail, head) were published by the producer's Release store
+            let slot = unsafe { &*ring.slots[tail % RING_SLOTS].get() };
+            write_packet(&mut out, slot)?;
+            tail = tail.wrapping_add(1);
+            ring.tail.store(tail, Ordering::Release);
+            ring.written.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn pad4(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Write one block: type, total length, body, total length.
+fn write_block(out: &mut impl Write, block_type: u32, body: &[&[u8]]) -> io::Result<()> {
+    let body_len: usize = body.iter().map(|b| b.len()).sum();
+    let total = (12 + body_len) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    for part in body {
+        out.write_all(part)?;
+    }
+    out.write_all(&total.to_le_bytes())
+}
+
+fn write_section_header(out: &mut impl Write) -> io::Result<()> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes()); // Byte-order magic
+    body.extend_from_slice(&1u16.to_le_bytes()); // Major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // Section length: unknown
+    write_block(out, 0x0A0D_0D0A, &[&body])
+}
+
+fn write_interface(out: &mut impl Write, iface: &str, snaplen: u32) -> io::Result<()> {
+    let mut body = Vec::with_capacity(32 + iface.len());
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+    body.extend_from_slice(&snaplen.to_le_bytes());
+    // if_name
+    body.extend_from_slice(&2u16.to_le_bytes());
+    body.extend_from_slice(&(iface.len() as u16).to_le_bytes());
+    body.extend_from_slice(iface.as_bytes());
+    body.resize(body.len() + pad4(iface.len()), 0);
+    // if_tsresol: nanoseconds
+    body.extend_from_slice(&9u16.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&[9, 0, 0, 0]);
+    // opt_endofopt
+    body.extend_from_slice(&[0; 4]);
+    write_block(out, 0x0000_0001, &[&body])
+}
+
+fn write_packet(out: &mut impl Write, slot: &Slot) -> io::Result<()> {
+    let cap_len = slot.cap_len as usize;
+    let mut hdr = [0u8; 20];
+    hdr[0..4].copy_from_slice(&0u32.to_le_bytes()); // Interface 0
+    hdr[4..8].copy_from_slice(&((slot.ts_ns >> 32) as u32).to_le_bytes());
+    hdr[8..12].copy_from_slice(&(slot.ts_ns as u32).to_le_bytes());
+    hdr[12..16].copy_from_slice(&slot.cap_len.to_le_bytes());
+    hdr[16..20].copy_from_slice(&slot.orig_len.to_le_bytes());
+    // epb_flags (direction), then opt_endofopt
+    let mut opts = [0u8; 12];
+    opts[0..2].copy_from_slice(&2u16.to_le_bytes());
+    opts[2..4].copy_from_slice(&4u16.to_le_bytes());
+    opts[4..8].copy_from_slice(&slot.flags.to_le_bytes());
+    write_block(out, 0x0000_0006, &[&hdr, &slot.data[..cap_len], &[0; 3][..pad4(cap_len)], &opts])
+}
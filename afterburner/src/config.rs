@@ -0,0 +1,490 @@
+//! Typed run configuration: built-in defaults, overlaid by a TOML file (`--config`), overlaid
+//! by command-line flags. Every section and field is optional in the file; `--print-config`
+//! dumps the effective result in the same format.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use afterburner_common::XSK_MAP_SIZE;
+use serde::{Deserialize, Serialize};
+
+use crate::echo::LatencyMode;
+use crate::flood::TxMode;
+use crate::umem::HugePages;
+use crate::xdp::XdpMode;
+use crate::xsk::FRAME_SIZE;
+
+/// Bidi streams reserved by the client: the flooder's 0, 4, 8, 12 and the echo stream 16.
+const MIN_STREAMS_BIDI: u64 = 5;
+/// Largest batch the flooder can spread over its own streams (0, 4, 8, 12).
+const MAX_BATCH_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub umem: UmemConfig,
+    pub quic: QuicConfig,
+    pub flood: FloodConfig,
+    pub cpu: CpuConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// Interface, addressing and how the XDP program is attached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Required; there is no sensible default
+    pub iface: String,
+    /// RX queue the socket binds to; the XDP program redirects each queue to its own socket
+    pub queue_id: u32,
+    /// Our address; the XDP program redirects UDP to `local.port()` into the socket
+    pub local: SocketAddrV4,
+    pub peer: SocketAddrV4,
+    pub src_mac: MacAddr,
+    /// Broadcast by default, which veth always accepts
+    pub dst_mac: MacAddr,
+    pub xdp_mode: XdpMode,
+    /// Replace a foreign XDP program already attached to the interface
+    pub xdp_force: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            iface: String::new(),
+            queue_id: 0,
+            local: SocketAddrV4::new([10, 0, 0, 10].into(), 8000),
+            peer: SocketAddrV4::new([10, 0, 0, 11].into(), 8004),
+            src_mac: MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            dst_mac: MacAddr([0xff; 6]),
+            xdp_mode: XdpMode::Auto,
+            xdp_force: false,
+        }
+    }
+}
+
+/// Shared packet memory and ring depths. Frames move between RX and TX on demand (see
+/// `frame_pool.rs`), within the two reserves. See `umem.rs` for placement and backing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UmemConfig {
+    /// Bytes; a multiple of `frame_size`
+    pub size: usize,
+    pub frame_size: usize,
+    /// Entries in each of the fill, completion, RX and TX rings
+    pub ring_size: u32,
+    pub hugepages: HugePages,
+    /// NUMA node to place the UMEM on; defaults to the NIC's node
+    pub numa_node: Option<u32>,
+    /// Fail instead of falling back to regular pages or another node
+    pub strict: bool,
+    /// Frames RX always keeps on the fill and RX rings
+    pub rx_reserve: u32,
+    /// Free frames the fill ring never takes, so TX always finds some
+    pub tx_reserve: u32,
+}
+
+impl UmemConfig {
+    pub fn num_frames(&self) -> usize {
+        self.size / self.frame_size
+    }
+}
+
+impl Default for UmemConfig {
+    fn default() -> Self {
+        // 32MB = 8192 frames, starting 4096 RX / 4096 TX; 4096-entry rings suit veth
+        UmemConfig {
+            size: 32 * 1024 * 1024,
+            frame_size: FRAME_SIZE,
+            ring_size: 4096,
+            hugepages: HugePages::Size2M,
+            numa_node: None,
+            strict: false,
+            rx_reserve: 512,
+            tx_reserve: 512,
+        }
+    }
+}
+
+/// QUIC transport parameters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuicConfig {
+    pub alpn: String,
+    /// 0 acks immediately
+    pub max_ack_delay_ms: u64,
+    pub ack_delay_exponent: u64,
+    /// 0 disables the idle timeout
+    pub max_idle_timeout_ms: u64,
+    /// Connection-level flow control window
+    pub initial_max_data: u64,
+    /// Per-stream flow control window (bidi and uni)
+    pub initial_max_stream_data: u64,
+    pub initial_max_streams_bidi: u64,
+    pub initial_max_streams_uni: u64,
+    /// DATAGRAM send and receive queue length
+    pub dgram_queue_len: usize,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        QuicConfig {
+            alpn: "solana-tpu".to_string(),
+            max_ack_delay_ms: 0,
+            ack_delay_exponent: 0,
+            max_idle_timeout_ms: 0,
+            initial_max_data: 100_000_000,
+            initial_max_stream_data: 10_000_000,
+            initial_max_streams_bidi: 1000,
+            initial_max_streams_uni: 1000,
+            dgram_queue_len: 1024,
+        }
+    }
+}
+
+/// What the flooder sends and how latency is measured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodConfig {
+    pub tx_mode: TxMode,
+    /// Transactions per loop iteration; in stream mode one per stream (0, 4, 8, 12)
+    pub batch_size: usize,
+    pub latency_mode: LatencyMode,
+    /// Least time between echo probes, in microseconds
+    pub probe_interval_us: u64,
+}
+
+impl FloodConfig {
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_micros(self.probe_interval_us)
+    }
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            tx_mode: TxMode::Stream,
+            batch_size: MAX_BATCH_SIZE,
+            latency_mode: LatencyMode::OneWay,
+            probe_interval_us: 1000,
+        }
+    }
+}
+
+/// Where threads run and how the hot loop is scheduled (see `cpu.rs`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuConfig {
+    /// Pin the hot loop to this core
+    pub core: Option<usize>,
+    /// Cores for the helper threads; defaults to every allowed core except `core`
+    pub helper_cores: Option<Vec<usize>>,
+    /// Run the hot loop as SCHED_FIFO with this priority (1-99)
+    pub fifo_priority: Option<u8>,
+    /// `mlockall` current and future memory
+    pub mlock: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve Prometheus text on `http://<addr>/metrics`
+    pub addr: Option<SocketAddr>,
+    /// Append one JSON object per interval to this file
+    pub json_path: Option<PathBuf>,
+    pub interval_ms: u64,
+}
+
+impl MetricsConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { addr: None, json_path: None, interval_ms: 1000 }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config serializes to TOML")
+    }
+
+    /// Check everything up front, so a bad config reports all its problems in one go.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, msg: String| {
+            if !ok {
+                errors.push(msg);
+            }
+        };
+
+        let net = &self.network;
+        check(!net.iface.is_empty(), "network.iface: required (set it in the config file or pass --iface)".into());
+        check(!net.local.ip().is_unspecified(), format!("network.local: {} is not a usable source address", net.local));
+        check(net.local.port() != 0, "network.local: port must not be 0".into());
+        check(!net.peer.ip().is_unspecified() && net.peer.port() != 0, format!("network.peer: {} is not a usable destination", net.peer));
+        check(net.src_mac.0[0] & 1 == 0, format!("network.src_mac: {} is a multicast address", net.src_mac));
+        check(
+            net.queue_id < XSK_MAP_SIZE,
+            format!("network.queue_id: {} must be below {} (the XDP program's socket map size)", net.queue_id, XSK_MAP_SIZE),
+        );
+
+        let umem = &self.umem;
+        check(
+            umem.frame_size.is_power_of_two() && (2048..=FRAME_SIZE).contains(&umem.frame_size),
+            format!("umem.frame_size: {} must be a power of two between 2048 and {}", umem.frame_size, FRAME_SIZE),
+        );
+        check(umem.ring_size.is_power_of_two(), format!("umem.ring_size: {} must be a power of two", umem.ring_size));
+        if umem.frame_size > 0 {
+            check(
+                umem.size.is_multiple_of(umem.frame_size) && umem.num_frames() >= 2,
+                format!("umem.size: {} must be a non-zero multiple of umem.frame_size ({}) holding at least 2 frames", umem.size, umem.frame_size),
+            );
+            check(
+                umem.rx_reserve as u64 + umem.tx_reserve as u64 <= umem.num_frames() as u64,
+                format!(
+                    "umem.rx_reserve + umem.tx_reserve: {} + {} exceed the {} frames",
+                    umem.rx_reserve,
+                    umem.tx_reserve,
+                    umem.num_frames()
+                ),
+            );
+        }
+        check(
+            umem.rx_reserve >= 1 && umem.rx_reserve <= umem.ring_size,
+            format!("umem.rx_reserve: {} must be between 1 and umem.ring_size ({})", umem.rx_reserve, umem.ring_size),
+        );
+
+        if let Some(node) = umem.numa_node {
+            check(
+                Path::new(&format!("/sys/devices/system/node/node{}", node)).exists(),
+                format!("umem.numa_node: node {} does not exist", node),
+            );
+        }
+
+        let quic = &self.quic;
+        check(!quic.alpn.is_empty() && quic.alpn.len() < 256, "quic.alpn: must be 1 to 255 bytes".into());
+        check(quic.max_ack_delay_ms < 1 << 14, format!("quic.max_ack_delay_ms: {} exceeds the protocol limit of 16383", quic.max_ack_delay_ms));
+        check(quic.ack_delay_exponent <= 20, format!("quic.ack_delay_exponent: {} exceeds the protocol limit of 20", quic.ack_delay_exponent));
+        check(
+            quic.initial_max_streams_bidi >= MIN_STREAMS_BIDI,
+            format!("quic.initial_max_streams_bidi: need at least {} (flooder streams plus the echo stream)", MIN_STREAMS_BIDI),
+        );
+        check(quic.initial_max_streams_uni >= 1, "quic.initial_max_streams_uni: need at least 1 for the server's timestamp stream".into());
+        check(quic.initial_max_stream_data > 0, "quic.initial_max_stream_data: must not be 0".into());
+        check(
+            quic.initial_max_data >= quic.initial_max_stream_data,
+            format!(
+                "quic.initial_max_data: {} is smaller than quic.initial_max_stream_data ({})",
+                quic.initial_max_data, quic.initial_max_stream_data
+            ),
+        );
+        check(quic.dgram_queue_len > 0, "quic.dgram_queue_len: must not be 0".into());
+
+        let flood = &self.flood;
+        check(
+            (1..=MAX_BATCH_SIZE).contains(&flood.batch_size),
+            format!("flood.batch_size: {} must be between 1 and {}", flood.batch_size, MAX_BATCH_SIZE),
+        );
+        check(flood.probe_interval_us > 0, "flood.probe_interval_us: must not be 0".into());
+
+        let cpu = &self.cpu;
+        let cores = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        let exists = |core: usize| cores <= 0 || core < cores as usize;
+        if let Some(core) = cpu.core {
+            check(exists(core), format!("cpu.core: {} does not exist ({} cores)", core, cores));
+        }
+        if let Some(helpers) = &cpu.helper_cores {
+            check(!helpers.is_empty(), "cpu.helper_cores: must not be empty (leave it out for the default)".into());
+            for &c in helpers.iter().filter(|&&c| !exists(c)) {
+                check(false, format!("cpu.helper_cores: {} does not exist ({} cores)", c, cores));
+            }
+            if let Some(core) = cpu.core {
+                check(!helpers.contains(&core), format!("cpu.helper_cores: includes the hot-loop core {}", core));
+            }
+        }
+        if let Some(priority) = cpu.fifo_priority {
+            check((1..=99).contains(&priority), format!("cpu.fifo_priority: {} must be between 1 and 99", priority));
+        }
+
+        check(self.metrics.interval_ms > 0, "metrics.interval_ms: must not be 0".into());
+
+        if errors.is_empty() { Ok(()) } else { Err(ValidationErrors(errors)) }
+    }
+}
+
+/// Every problem found by [`Config::validate`], one per line when displayed.
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<String>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.0.len();
+        write!(f, "invalid configuration ({} error{}):", n, if n == 1 { "" } else { "s" })?;
+        for e in &self.0 {
+            write!(f, "\n  {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Ethernet address, written as `02:00:00:00:00:01` in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in mac.iter_mut() {
+            *byte = parts
+                .next()
+                .filter(|p| p.len() == 2)
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+                .ok_or_else(|| format!("invalid MAC address '{}'", s))?;
+        }
+        if parts.next().is_some() {
+            return Err(format!("invalid MAC address '{}'", s));
+        }
+        Ok(MacAddr(mac))
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MacAddr> for String {
+    fn from(mac: MacAddr) -> String {
+        mac.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> Config {
+        let mut cfg = Config::default();
+        cfg.network.iface = "veth0".into();
+        cfg
+    }
+
+    #[test]
+    fn defaults_with_an_interface_are_valid() {
+        assert!(valid().validate().is_ok());
+        let err = Config::default().validate().unwrap_err();
+        assert_eq!(err.0.len(), 1);
+        assert!(err.0[0].starts_with("network.iface:"));
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let mut cfg = valid();
+        cfg.network.queue_id = XSK_MAP_SIZE;
+        cfg.network.src_mac = MacAddr([0x01, 0, 0, 0, 0, 1]);
+        cfg.umem.ring_size = 1000;
+        cfg.quic.alpn = String::new();
+        cfg.flood.batch_size = 0;
+        cfg.metrics.interval_ms = 0;
+
+        let err = cfg.validate().unwrap_err();
+        let fields: Vec<&str> = err.0.iter().map(|e| e.split(':').next().unwrap()).collect();
+        assert_eq!(fields, [
+            "network.src_mac",
+            "network.queue_id",
+            "umem.ring_size",
+            "quic.alpn",
+            "flood.batch_size",
+            "metrics.interval_ms",
+        ]);
+        let shown = err.to_string();
+        assert!(shown.starts_with("invalid configuration (6 errors):"));
+        assert_eq!(shown.lines().count(), 7);
+    }
+
+    #[test]
+    fn mac_addresses_parse_and_print() {
+        let mac: MacAddr = "02:00:5e:AB:cd:ef".parse().unwrap();
+        assert_eq!(mac, MacAddr([0x02, 0x00, 0x5e, 0xab, 0xcd, 0xef]));
+        assert_eq!(mac.to_string(), "02:00:5e:ab:cd:ef");
+
+        for bad in [
+            "",
+            "02:00:5e:ab:cd",
+            "02:00:5e:ab:cd:ef:01",
+            "02:00:5e:ab:cd:e",
+            "02-00-5e-ab-cd-ef",
+            "02:00:5e:ab:cd:eg",
+            "2:00:5e:ab:cd:ef0",
+        ] {
+            assert_eq!(bad.parse::<MacAddr>(), Err(format!("invalid MAC address '{}'", bad)));
+        }
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let mut cfg = valid();
+        cfg.network.dst_mac = MacAddr([0x02, 0, 0, 0, 0, 0x22]);
+        cfg.network.xdp_mode = XdpMode::Skb;
+        cfg.umem.numa_node = Some(1);
+        cfg.quic.alpn = "test".into();
+        cfg.flood.latency_mode = LatencyMode::Echo;
+        cfg.cpu.core = Some(3);
+        cfg.cpu.helper_cores = Some(vec![0, 1]);
+        cfg.metrics.addr = Some("127.0.0.1:9100".parse().unwrap());
+
+        let text = cfg.to_toml();
+        let back: Config = toml::from_str(&text).unwrap();
+        assert_eq!(back.to_toml(), text);
+        assert_eq!(back.network.dst_mac, cfg.network.dst_mac);
+        assert_eq!(back.network.xdp_mode, XdpMode::Skb);
+        assert_eq!(back.umem.numa_node, Some(1));
+        assert_eq!(back.flood.latency_mode, LatencyMode::Echo);
+        assert_eq!(back.cpu.helper_cores, Some(vec![0, 1]));
+        assert_eq!(back.metrics.addr, cfg.metrics.addr);
+        assert!(text.contains("dst_mac = \"02:00:00:00:00:22\""));
+    }
+
+    #[test]
+    fn unknown_fields_and_bad_macs_are_rejected() {
+        assert!(toml::from_str::<Config>("[network]\nifac = \"eth0\"\n").is_err());
+        let err = toml::from_str::<Config>("[network]\nsrc_mac = \"02:00\"\n").unwrap_err();
+        assert!(err.to_string().contains("invalid MAC address '02:00'"));
+        // Every field is optional
+        let partial: Config = toml::from_str("[network]\niface = \"eth0\"\n").unwrap();
+        assert_eq!(partial.network.iface, "eth0");
+        assert_eq!(partial.umem.ring_size, UmemConfig::default().ring_size);
+    }
+}
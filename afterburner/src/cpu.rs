@@ -0,0 +1,167 @@
+//! Core pinning, real-time scheduling and memory locking for the hot loop.
+//!
+//! Threads inherit their creator's CPU mask, so setup happens in two steps:
+//! [`CpuSetup::before_helpers`] locks memory and restricts the main thread to the helper
+//! cores before any helper thread (metrics, capture, log writers, aya-log) is spawned, and
+//! [`CpuSetup::enter_hot_loop`] then moves the main thread alone onto its dedicated core and
+//! switches it to SCHED_FIFO. Failures are reported and the run continues unpinned.
+
+use std::{fs, io, mem};
+
+use log::{info, warn};
+
+use crate::config::CpuConfig;
+
+#[derive(Debug, Default)]
+pub struct CpuSetup {
+    helpers: Option<Vec<usize>>,
+    locked: bool,
+    hot_core: Option<usize>,
+    fifo: Option<u8>,
+}
+
+impl CpuSetup {
+    /// Lock memory and confine this thread (and so every thread it spawns from here on) to
+    /// the helper cores: `cpu.helper_cores`, or by default every allowed core but the hot one.
+    pub fn before_helpers(cfg: &CpuConfig) -> Self {
+        let mut setup = CpuSetup::default();
+
+        if cfg.mlock {
+            // MCL_FUTURE also covers the UMEM and ring mappings made later
+            if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0 {
+                setup.locked = true;
+            } else {
+                warn!(target: "cpu", "mlockall failed: {} (raise RLIMIT_MEMLOCK or run as root)", io::Error::last_os_error());
+            }
+        }
+
+        let helpers = match (&cfg.helper_cores, cfg.core) {
+            (Some(cores), _) => Some(cores.clone()),
+            (None, Some(hot)) => match affinity() {
+                Ok(allowed) => Some(allowed.into_iter().filter(|&c| c != hot).collect()),
+                Err(e) => {
+                    warn!(target: "cpu", "Failed to read CPU affinity: {}", e);
+                    None
+                }
+            },
+            (None, None) => None,
+        };
+        // With a single allowed core there is nowhere else to put the helpers
+        if let Some(cores) = helpers.filter(|c| !c.is_empty()) {
+            match set_affinity(&cores) {
+                Ok(()) => setup.helpers = Some(cores),
+                Err(e) => warn!(target: "cpu", "Failed to pin helper threads to CPUs {}: {}", format_cpu_list(&cores), e),
+            }
+        }
+        setup
+    }
+
+    /// Pin the calling thread to `cpu.core` and apply `cpu.fifo_priority`. Call once all
+    /// helper threads have been spawned.
+    pub fn enter_hot_loop(&mut self, cfg: &CpuConfig) {
+        if let Some(core) = cfg.core {
+            match set_affinity(&[core]) {
+                Ok(()) => self.hot_core = Some(core),
+                Err(e) => warn!(target: "cpu", "Failed to pin to CPU {}: {}", core, e),
+            }
+        }
+        if let Some(priority) = cfg.fifo_priority {
+            let param = libc::sched_param { sched_priority: priority as i32 };
+            if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } == 0 {
+                self.fifo = Some(priority);
+            } else {
+                warn!(target: "cpu", "SCHED_FIFO {} failed: {} (needs CAP_SYS_NICE)", priority, io::Error::last_os_error());
+            }
+        }
+        self.report();
+    }
+
+    fn report(&self) {
+        match self.hot_core {
+            Some(core) => {
+                let isolated = cpu_list_contains("/sys/devices/system/cpu/isolated", core);
+                let nohz = cpu_list_contains("/sys/devices/system/cpu/nohz_full", core);
+                info!(target: "cpu", "Hot loop on CPU {} (isolcpus: {}, nohz_full: {})", core, yes_no(isolated), yes_no(nohz));
+                if !isolated || !nohz {
+                    warn!(target: "cpu", "CPU {core} still takes scheduler work and/or timer ticks; boot with \
+                          isolcpus={core} nohz_full={core} for stable latency");
+                }
+            }
+            None => info!(target: "cpu", "Hot loop not pinned (set cpu.core or --cpu-core)"),
+        }
+        if let Some(cores) = &self.helpers {
+            info!(target: "cpu", "Helper threads on CPUs {}", format_cpu_list(cores));
+        }
+        info!(
+            target: "cpu",
+            "Scheduling: {} | Memory: {}",
+            self.fifo.map_or("SCHED_OTHER".to_string(), |p| format!("SCHED_FIFO {}", p)),
+            if self.locked { "locked" } else { "not locked" },
+        );
+    }
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b { "yes" } else { "no" }
+}
+
+/// CPUs the calling thread may run on.
+fn affinity() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize).filter(|&c| libc::CPU_ISSET(c, &set)).collect())
+    }
+}
+
+fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &core in cores {
+            libc::CPU_SET(core, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn cpu_list_contains(path: &str, core: usize) -> bool {
+    fs::read_to_string(path).map(|s| parse_cpu_list(&s).contains(&core)).unwrap_or(false)
+}
+
+/// Parse the kernel's CPU list format (`0,2-5,8`); malformed entries are skipped.
+fn parse_cpu_list(s: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        let range: Option<(usize, usize)> = match part.split_once('-') {
+            Some((lo, hi)) => lo.trim().parse().ok().zip(hi.trim().parse().ok()),
+            None => part.trim().parse().ok().map(|c| (c, c)),
+        };
+        if let Some((lo, hi)) = range {
+            cpus.extend(lo..=hi);
+        }
+    }
+    cpus
+}
+
+/// Inverse of [`parse_cpu_list`], for log messages.
+fn format_cpu_list(cores: &[usize]) -> String {
+    let mut sorted = cores.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i];
+        while i + 1 < sorted.len() && sorted[i + 1] == sorted[i] + 1 {
+            i += 1;
+        }
+        parts.push(if sorted[i] == start { start.to_string() } else { format!("{}-{}", start, sorted[i]) });
+        i += 1;
+    }
+    parts.join(",")
+}
@@ -0,0 +1,289 @@
+//! Loading the XDP program device-bound, so it can read the NIC's RX timestamp through the
+//! XDP RX metadata kfunc `bpf_xdp_metadata_rx_timestamp` (Linux 6.3+).
+//!
+//! aya can't do this itself: it neither resolves kfunc calls nor loads programs bound to a
+//! device. So the object's kfunc calls are patched before aya parses it
+//! ([`resolve_kfuncs`]), aya creates the maps as usual, and [`load`] relocates
+//! [`HW_PROGRAM`] against aya's maps with `aya_obj` and loads it with `BPF_PROG_LOAD` bound
+//! to the interface. A device-bound program only attaches in native mode; the caller falls
+//! back to the plain program when any of this fails.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::{io, mem};
+
+use afterburner_common::HW_PROGRAM;
+use aya::maps::Map;
+use aya::Ebpf;
+use aya_obj::btf::{Btf, BtfKind};
+use log::{debug, info, warn};
+use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget};
+
+use crate::xdp::BPF_XDP;
+
+/// The kfunc [`HW_PROGRAM`] calls
+const RX_TIMESTAMP_KFUNC: &str = "bpf_xdp_metadata_rx_timestamp";
+
+/// `BPF_PROG_LOAD` (linux/bpf.h)
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+/// `BPF_F_XDP_DEV_BOUND_ONLY`: bound to `prog_ifindex`, may call the RX metadata kfuncs
+const BPF_F_XDP_DEV_BOUND_ONLY: u32 = 1 << 6;
+
+/// `BPF_JMP | BPF_CALL`
+const CALL: u8 = 0x85;
+/// `BPF_PSEUDO_KFUNC_CALL`: the call's `imm` is a BTF function id
+const PSEUDO_KFUNC_CALL: u8 = 2;
+/// `BPF_ALU64 | BPF_MOV | BPF_K` into r0
+const MOV64_R0_IMM: u8 = 0xb7;
+
+/// Instruction size in bytes
+const INS_SIZE: usize = 8;
+/// Verifier log kept for the error when a load fails
+const LOG_SIZE: usize = 1 << 20;
+
+const SIOCGHWTSTAMP: libc::c_ulong = 0x89b1;
+const SIOCSHWTSTAMP: libc::c_ulong = 0x89b0;
+const HWTSTAMP_FILTER_NONE: i32 = 0;
+const HWTSTAMP_FILTER_ALL: i32 = 1;
+
+/// Patch every call to the RX timestamp kfunc in `object`. With `resolve`, the calls are
+/// pointed at the kernel's kfunc (by its id in `/sys/kernel/btf/vmlinux`) and `true` is
+/// returned; otherwise, or if the kernel lacks the kfunc, they become `r0 = -EOPNOTSUPP`,
+/// which leaves the object loadable by aya (it can't relocate the unresolved call) and
+/// [`HW_PROGRAM`] without a timestamp.
+pub(crate) fn resolve_kfuncs(object: &[u8], resolve: bool) -> Result<(Vec<u8>, bool), io::Error> {
+    let calls = kfunc_calls(object)?;
+    let btf_id = if resolve && !calls.is_empty() {
+        kfunc_btf_id()
+            .inspect_err(|e| warn!(target: "xdp", "No {} in the kernel ({}); hardware RX timestamps need Linux 6.3+",
+                RX_TIMESTAMP_KFUNC, e))
+            .ok()
+    } else {
+        None
+    };
+
+    let mut patched = object.to_vec();
+    for offset in calls {
+        let ins = &mut patched[offset..offset + INS_SIZE];
+        match btf_id {
+            Some(id) => {
+                ins[0] = CALL;
+                ins[1] = PSEUDO_KFUNC_CALL << 4;
+                ins[2..4].fill(0);
+                ins[4..8].copy_from_slice(&id.to_le_bytes());
+            }
+            None => {
+                ins[0] = MOV64_R0_IMM;
+                ins[1..4].fill(0);
+                ins[4..8].copy_from_slice(&(-libc::EOPNOTSUPP).to_le_bytes());
+            }
+        }
+    }
+    Ok((patched, btf_id.is_some()))
+}
+
+/// File offsets of the calls to [`RX_TIMESTAMP_KFUNC`]: the instructions relocated against
+/// it as an undefined symbol.
+fn kfunc_calls(object: &[u8]) -> Result<Vec<usize>, io::Error> {
+    let invalid = |e: object::Error| io::Error::new(io::ErrorKind::InvalidData, format!("parsing eBPF object: {}", e));
+    let file = object::File::parse(object).map_err(invalid)?;
+    let mut calls = Vec::new();
+    for section in file.sections() {
+        let Some((start, size)) = section.file_range() else { continue };
+        for (offset, reloc) in section.relocations() {
+            let RelocationTarget::Symbol(index) = reloc.target() else { continue };
+            let symbol = file.symbol_by_index(index).map_err(invalid)?;
+            if !symbol.is_undefined() || symbol.name() != Ok(RX_TIMESTAMP_KFUNC) {
+                continue;
+            }
+            if offset % INS_SIZE as u64 != 0 || offset + INS_SIZE as u64 > size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "bad relocation for {} at {:#x} in section {}", RX_TIMESTAMP_KFUNC, offset, section.index().0)));
+            }
+            calls.push((start + offset) as usize);
+        }
+    }
+    Ok(calls)
+}
+
+fn kfunc_btf_id() -> Result<u32, io::Error> {
+    let btf = Btf::from_sys_fs().map_err(io::Error::other)?;
+    btf.id_by_type_name_kind(RX_TIMESTAMP_KFUNC, BtfKind::Func).map_err(io::Error::other)
+}
+
+/// Load [`HW_PROGRAM`] from `object` (as patched by [`resolve_kfuncs`]) bound to
+/// `ifindex`, using the maps aya created for `bpf`. The program is named like the plain
+/// one, so stale-program detection treats both alike.
+pub(crate) fn load(bpf: &Ebpf, object: &[u8], ifindex: u32) -> Result<OwnedFd, io::Error> {
+    let mut obj = aya_obj::Object::parse(object).map_err(io::Error::other)?;
+    let fds: HashMap<&str, RawFd> = bpf.maps().map(|(name, map)| (name, map_fd(map))).collect();
+    let maps = obj.maps.clone();
+    let maps = maps.iter()
+        .map(|(name, map)| {
+            let fd = fds.get(name.as_str()).copied().ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound, format!("map {} was not created by the loader", name)))?;
+            Ok((name.as_str(), fd, map))
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    let text_sections: HashSet<usize> = obj.functions.keys().map(|(section, _)| *section).collect();
+    obj.relocate_maps(maps.into_iter(), &text_sections).map_err(io::Error::other)?;
+    obj.relocate_calls(&text_sections).map_err(io::Error::other)?;
+
+    let program = obj.programs.get(HW_PROGRAM).ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound, format!("no {} program in the eBPF object", HW_PROGRAM)))?;
+    let insns = &obj.functions[&program.function_key()].instructions;
+    prog_load(insns.as_ptr() as u64, insns.len() as u32, &obj.license, ifindex)
+        .map_err(|e| io::Error::new(e.kind(), format!("loading {} bound to the interface: {}", HW_PROGRAM, e)))
+}
+
+fn map_fd(map: &Map) -> RawFd {
+    let data = match map {
+        Map::Array(m) | Map::BloomFilter(m) | Map::CpuMap(m) | Map::DevMap(m) | Map::DevMapHash(m)
+        | Map::HashMap(m) | Map::LpmTrie(m) | Map::LruHashMap(m) | Map::PerCpuArray(m)
+        | Map::PerCpuHashMap(m) | Map::PerCpuLruHashMap(m) | Map::PerfEventArray(m)
+        | Map::ProgramArray(m) | Map::Queue(m) | Map::RingBuf(m) | Map::SockHash(m)
+        | Map::SockMap(m) | Map::Stack(m) | Map::StackTraceMap(m) | Map::Unsupported(m)
+        | Map::XskMap(m) => m,
+    };
+    data.fd().as_fd().as_raw_fd()
+}
+
+/// `BPF_PROG_LOAD` with a device-bound XDP program. Retried with the verifier log on
+/// failure, for the error.
+fn prog_load(insns: u64, insn_cnt: u32, license: &CString, ifindex: u32) -> Result<OwnedFd, io::Error> {
+    #[repr(C)]
+    struct ProgLoadAttr {
+        prog_type: u32,
+        insn_cnt: u32,
+        insns: u64,
+        license: u64,
+        log_level: u32,
+        log_size: u32,
+        log_buf: u64,
+        kern_version: u32,
+        prog_flags: u32,
+        prog_name: [u8; 16],
+        prog_ifindex: u32,
+        expected_attach_type: u32,
+    }
+    let mut prog_name = [0u8; 16];
+    let name = crate::xdp::PROGRAM_NAME.as_bytes();
+    prog_name[..name.len()].copy_from_slice(name);
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt,
+        insns,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: BPF_F_XDP_DEV_BOUND_ONLY,
+        prog_name,
+        prog_ifindex: ifindex,
+        expected_attach_type: BPF_XDP,
+    };
+    let load = |attr: &ProgLoadAttr| unsafe {
+        libc::syscall(libc::SYS_bpf, BPF_PROG_LOAD, attr as *const _, mem::size_of::<ProgLoadAttr>())
+    };
+
+    let fd = load(&attr);
+    if fd >= 0 {
+        return Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+    }
+    let err = io::Error::last_os_error();
+    let mut log = vec![0u8; LOG_SIZE];
+    attr.log_level = 1;
+    attr.log_size = log.len() as u32;
+    attr.log_buf = log.as_mut_ptr() as u64;
+    let fd = load(&attr);
+    if fd >= 0 {
+        return Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+    }
+    let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+    let log = String::from_utf8_lossy(&log[..end]);
+    let lines: Vec<&str> = log.lines().collect();
+    if lines.is_empty() {
+        return Err(err);
+    }
+    let tail = lines[lines.len().saturating_sub(3)..].join(" / ");
+    Err(io::Error::new(err.kind(), format!("{} (verifier: {})", err, tail)))
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct HwTstampConfig {
+    flags: i32,
+    tx_type: i32,
+    rx_filter: i32,
+}
+
+/// RX hardware timestamping turned on by us, turned back off on drop. Most NICs only put
+/// a timestamp in the XDP metadata while it is on.
+pub(crate) struct RxHwTstamp {
+    iface: String,
+    prev: HwTstampConfig,
+}
+
+impl RxHwTstamp {
+    /// Turn on RX timestamping of all packets on `iface`, unless something (e.g. ptp4l)
+    /// already has it on. `None` if it was on, or the driver can't (veth can't).
+    pub(crate) fn enable(iface: &str) -> Option<RxHwTstamp> {
+        let mut prev = HwTstampConfig::default();
+        if let Err(e) = hwtstamp_ioctl(iface, SIOCGHWTSTAMP, &mut prev) {
+            debug!(target: "xdp", "No hardware timestamping on {}: {}", iface, e);
+            return None;
+        }
+        if prev.rx_filter != HWTSTAMP_FILTER_NONE {
+            return None;
+        }
+        let mut cfg = HwTstampConfig { rx_filter: HWTSTAMP_FILTER_ALL, ..prev };
+        match hwtstamp_ioctl(iface, SIOCSHWTSTAMP, &mut cfg) {
+            Ok(()) => {
+                info!(target: "xdp", "Enabled RX hardware timestamping on {}", iface);
+                Some(RxHwTstamp { iface: iface.to_owned(), prev })
+            }
+            Err(e) => {
+                warn!(target: "xdp", "Failed to enable RX hardware timestamping on {}: {}", iface, e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for RxHwTstamp {
+    fn drop(&mut self) {
+        let mut prev = self.prev;
+        if let Err(e) = hwtstamp_ioctl(&self.iface, SIOCSHWTSTAMP, &mut prev) {
+            warn!(target: "xdp", "Failed to restore hardware timestamping on {}: {}", self.iface, e);
+        }
+    }
+}
+
+/// `SIOCGHWTSTAMP`/`SIOCSHWTSTAMP` on `iface`.
+fn hwtstamp_ioctl(iface: &str, request: libc::c_ulong, cfg: &mut HwTstampConfig) -> Result<(), io::Error> {
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        data: *mut HwTstampConfig,
+        _pad: [u8; 16],
+    }
+    if iface.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+    }
+    let mut req = IfReq { name: [0; libc::IFNAMSIZ], data: cfg, _pad: [0; 16] };
+    req.name[..iface.len()].copy_from_slice(iface.as_bytes());
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        if libc::ioctl(fd.as_raw_fd(), request as _, &mut req) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Round-trip latency probes, independent of clock skew between client and server.
+//!
+//! For a probe sent at client time t1, received by the server at t2, echoed at t3 and
+//! received back at t4, the round trip is `(t4 - t1) - (t3 - t2)`: two intervals each
+//! measured on a single host. One-way delays additionally need the server's clock offset,
+//! which is estimated NTP-style as `((t2 - t1) + (t3 - t4)) / 2` from the lowest-RTT
+//! sample of the first [`OFFSET_WARMUP`] echoes (the one least skewed by queueing on either
+//! path). The offset is then fixed, so every one-way sample is against the same estimate;
+//! none are recorded during the warmup.
+//!
+//! Probes are separate frames on their own stream rather than stamps on the transactions:
+//! echoing every transaction would double the return traffic and tie the measurement to
+//! the transport mode, while probes measure the same path at a fixed, low rate.
+
+use afterburner_common::codec::Probe;
+use crate::histogram::Histogram;
+
+/// Echoes the clock offset is estimated from before one-way delays are recorded
+pub const OFFSET_WARMUP: u32 = 32;
+
+/// Where latency samples come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum LatencyMode {
+    /// Server wall-clock stamp vs. client wall clock; only meaningful on a single host
+    OneWay,
+    /// Round trip of client-stamped probes echoed by the server, plus one-way estimates
+    Echo,
+}
+
+/// `CLOCK_MONOTONIC_RAW` in nanoseconds: not slewed by NTP, so stamps taken on this
+/// host can be subtracted safely.
+#[inline(always)]
+pub fn monotonic_raw_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Clock offset and one-way delay estimates from echoed probes.
+pub struct EchoTracker {
+    next_seq: u64,
+    /// When the last probe was stamped
+    last_probe_ns: Option<u64>,
+    /// Server clock minus client clock, from the best warmup sample
+    offset_ns: i64,
+    best_rtt_ns: u64,
+    /// Echoes left before the offset is fixed
+    warmup_left: u32,
+    pub forward: Histogram,
+    pub reverse: Histogram,
+}
+
+impl Default for EchoTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EchoTracker {
+    pub fn new() -> Self {
+        EchoTracker {
+            next_seq: 0,
+            last_probe_ns: None,
+            offset_ns: 0,
+            best_rtt_ns: u64::MAX,
+            warmup_left: OFFSET_WARMUP,
+            forward: Histogram::new(),
+            reverse: Histogram::new(),
+        }
+    }
+
+    /// Stamp the next probe with the current time, unless the last one is less than
+    /// `interval_ns` old.
+    pub fn next_probe(&mut self, interval_ns: u64) -> Option<Probe> {
+        let now = monotonic_raw_ns();
+        if self.last_probe_ns.is_some_and(|last| now.saturating_sub(last) < interval_ns) {
+            return None;
+        }
+        self.last_probe_ns = Some(now);
+        let probe = Probe { client_tx_ns: now, seq: self.next_seq };
+        self.next_seq += 1;
+        Some(probe)
+    }
+
+    /// Record an echo of `probe` that the server received at `t2` and sent at `t3` (server
+    /// clock), returning the round trip in nanoseconds.
+    pub fn on_echo(&mut self, probe: &Probe, t2: u64, t3: u64) -> u64 {
+        self.record_echo(probe.client_tx_ns, t2, t3, monotonic_raw_ns())
+    }
+
+    /// [`on_echo`](Self::on_echo) for an echo of a probe stamped `t1` that arrived at `t4`.
+    fn record_echo(&mut self, t1: u64, t2: u64, t3: u64, t4: u64) -> u64 {
+        let server_hold = t3.saturating_sub(t2);
+        let rtt = t4.saturating_sub(t1).saturating_sub(server_hold);
+
+        if self.warmup_left > 0 {
+            if rtt < self.best_rtt_ns {
+                self.best_rtt_ns = rtt;
+                self.offset_ns = ((t2 as i64 - t1 as i64) + (t3 as i64 - t4 as i64)) / 2;
+            }
+            self.warmup_left -= 1;
+            return rtt;
+        }
+        let forward = t2 as i64 - t1 as i64 - self.offset_ns;
+        let reverse = t4 as i64 - t3 as i64 + self.offset_ns;
+        self.forward.record(forward.max(0) as u64);
+        self.reverse.record(reverse.max(0) as u64);
+        rtt
+    }
+
+    /// The fixed clock offset, once the warmup is over.
+    pub fn offset_ns(&self) -> Option<i64> {
+        (self.warmup_left == 0).then_some(self.offset_ns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1_000_000_000;
+
+    /// Echo a probe sent at `t1` over a path with the given one-way delays, to a server
+    /// whose clock is `offset` ahead that holds it for `hold`.
+    fn echo(tracker: &mut EchoTracker, t1: u64, offset: i64, forward: u64, hold: u64, reverse: u64) -> u64 {
+        let t2 = t1.checked_add_signed(offset).unwrap() + forward;
+        let t3 = t2 + hold;
+        let t4 = t3.checked_add_signed(-offset).unwrap() + reverse;
+        tracker.record_echo(t1, t2, t3, t4)
+    }
+
+    fn warm_up(tracker: &mut EchoTracker, offset: i64) {
+        for i in 0..OFFSET_WARMUP as u64 {
+            echo(tracker, T0 + i * 1_000_000, offset, 20_000, 5_000, 20_000);
+        }
+    }
+
+    #[test]
+    fn rtt_leaves_out_the_server_hold_and_clock_offset() {
+        let mut tracker = EchoTracker::new();
+        assert_eq!(echo(&mut tracker, T0, 0, 30_000, 7_000, 50_000), 80_000);
+        assert_eq!(echo(&mut tracker, T0, 3_000_000_000, 30_000, 7_000, 50_000), 80_000);
+        assert_eq!(echo(&mut tracker, T0, -500_000_000, 30_000, 7_000, 50_000), 80_000);
+    }
+
+    #[test]
+    fn offset_comes_from_the_lowest_rtt_warmup_echo() {
+        let offset = 2_500_000;
+        let mut tracker = EchoTracker::new();
+        // Queueing on one path skews the estimate by half the asymmetry...
+        echo(&mut tracker, T0, offset, 90_000, 5_000, 20_000);
+        echo(&mut tracker, T0 + 1_000_000, offset, 20_000, 5_000, 60_000);
+        // ...so the symmetric, fastest echo wins, wherever it falls in the warmup
+        echo(&mut tracker, T0 + 2_000_000, offset, 15_000, 5_000, 15_000);
+        for i in 3..OFFSET_WARMUP as u64 {
+            echo(&mut tracker, T0 + i * 1_000_000, offset, 40_000, 5_000, 16_000);
+        }
+        assert_eq!(tracker.offset_ns(), Some(offset));
+    }
+
+    #[test]
+    fn warmup_records_no_one_way_samples() {
+        let mut tracker = EchoTracker::new();
+        for i in 0..OFFSET_WARMUP as u64 {
+            assert_eq!(tracker.offset_ns(), None);
+            echo(&mut tracker, T0 + i * 1_000_000, 1_000, 20_000, 5_000, 20_000);
+        }
+        assert_eq!(tracker.offset_ns(), Some(1_000));
+        assert_eq!(tracker.forward.count(), 0);
+        assert_eq!(tracker.reverse.count(), 0);
+    }
+
+    #[test]
+    fn one_way_delays_after_warmup() {
+        let offset = -700_000_000;
+        let mut tracker = EchoTracker::new();
+        warm_up(&mut tracker, offset);
+
+        let t1 = T0 + 100_000_000;
+        assert_eq!(echo(&mut tracker, t1, offset, 25_000, 5_000, 60_000), 85_000);
+        assert_eq!(echo(&mut tracker, t1 + 1_000_000, offset, 35_000, 5_000, 10_000), 45_000);
+        assert_eq!(tracker.forward.count(), 2);
+        assert_eq!((tracker.forward.min(), tracker.forward.max()), (25_000, 35_000));
+        assert_eq!((tracker.reverse.min(), tracker.reverse.max()), (10_000, 60_000));
+        // The offset stays fixed after the warmup, even for a faster echo
+        echo(&mut tracker, t1 + 2_000_000, offset, 1_000, 5_000, 1_000);
+        assert_eq!(tracker.offset_ns(), Some(offset));
+    }
+
+    #[test]
+    fn one_way_delays_below_the_offset_error_clamp_to_zero() {
+        let mut tracker = EchoTracker::new();
+        warm_up(&mut tracker, 0);
+        // The server's clock stepped 50us back since the warmup
+        echo(&mut tracker, T0 + 100_000_000, -50_000, 20_000, 5_000, 20_000);
+        assert_eq!(tracker.forward.max(), 0);
+        assert_eq!(tracker.reverse.min(), 70_000);
+    }
+
+    #[test]
+    fn probes_are_rate_limited_and_numbered() {
+        let mut tracker = EchoTracker::new();
+        let first = tracker.next_probe(u64::MAX).unwrap();
+        assert!(tracker.next_probe(u64::MAX).is_none());
+        let second = tracker.next_probe(0).unwrap();
+        assert_eq!((first.seq, second.seq), (0, 1));
+        assert!(second.client_tx_ns >= first.client_tx_ns);
+    }
+}
@@ -1,8 +1,17 @@
+//! The transaction payload the flooder sends.
+
+/// Stand-in for a signed Solana transaction: fixed signature and message bytes.
 pub struct MockTransaction {
     pub signature: [u8; 64],
     pub message: [u8; 170],
 }
 
+impl Default for MockTransaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MockTransaction {
     pub fn new() -> Self {
         MockTransaction {
@@ -0,0 +1,529 @@
+//! The whole data path as one value: the XDP program, the AF_XDP socket and the QUIC
+//! connection, driven by a poll loop. The `afterburner-app` CLI is a thin layer over this;
+//! an embedding process builds an [`Engine`] the same way and queues its own transactions
+//! from the `feed` callback instead of running the flooder, or has
+//! [`EngineBuilder::spawn`] run the loop on a pinned thread of its own and submits to it
+//! from anywhere through a [`Submitter`](crate::submit::Submitter).
+
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use afterburner_common::RxMeta;
+use aya::maps::{PerCpuArray, XskMap};
+use aya::programs::Xdp;
+use aya::{Ebpf, EbpfLoader};
+use log::{error, info, warn};
+
+use crate::capture::{Capture, CaptureConfig};
+use crate::config::{Config, NetworkConfig};
+use crate::cpu::CpuSetup;
+use crate::dev_bound::{self, RxHwTstamp};
+use crate::error::{BoxError, Error};
+use crate::headers;
+use crate::metrics::{self, ExportConfig, Exporter, Metrics, XdpCounters};
+use crate::quic_driver::QuicDriver;
+use crate::rx_timing::{self, RxTiming};
+use crate::submit::{self, Completions, Dispatcher, Submitter};
+use crate::xdp::{self, Attachment, XdpMode};
+use crate::xsk::{XdpSocket, XskStats};
+
+/// Descriptors taken from the RX ring per peek
+const RX_BATCH: u32 = 64;
+/// TX descriptors reserved per batch
+const TX_BATCH: u32 = 64;
+/// Default for [`EngineBuilder::submit_queue`]
+const SUBMIT_QUEUE: usize = 4096;
+
+/// Sets up an [`Engine`]: everything but the config is optional.
+///
+/// `build` spawns the metrics exporter and capture writer threads, so with CPU pinning
+/// configured, call [`CpuSetup::before_helpers`](crate::cpu::CpuSetup::before_helpers) first.
+pub struct EngineBuilder {
+    cfg: Config,
+    ebpf_object: Option<PathBuf>,
+    capture: Option<(PathBuf, usize, u32)>,
+    rx_timestamps: bool,
+    scid: [u8; 20],
+    submit_queue: usize,
+}
+
+impl EngineBuilder {
+    pub fn new(cfg: Config) -> Self {
+        EngineBuilder {
+            cfg,
+            ebpf_object: None,
+            capture: None,
+            rx_timestamps: false,
+            scid: [0x55; 20],
+            submit_queue: SUBMIT_QUEUE,
+        }
+    }
+
+    /// Load the eBPF object from `path` instead of the one embedded at build time.
+    pub fn ebpf_object(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ebpf_object = Some(path.into());
+        self
+    }
+
+    /// Whether the build script embedded an eBPF object. Without one (it couldn't build
+    /// the eBPF program), `build` fails unless [`ebpf_object`](Self::ebpf_object) is set.
+    pub fn has_embedded_object() -> bool {
+        !embedded_object().is_empty()
+    }
+
+    /// Capture one in every `sample` RX/TX frames, `snaplen` bytes each, to a pcapng file.
+    pub fn capture(mut self, path: impl Into<PathBuf>, snaplen: usize, sample: u32) -> Self {
+        self.capture = Some((path.into(), snaplen, sample));
+        self
+    }
+
+    /// Break RX latency down into wire->XDP, XDP->userspace and userspace time. wire->XDP
+    /// needs native XDP (the program is then loaded bound to the interface, to read the
+    /// NIC's RX timestamp) and a NIC that puts the timestamp in the XDP metadata.
+    pub fn rx_timestamps(mut self, on: bool) -> Self {
+        self.rx_timestamps = on;
+        self
+    }
+
+    /// Source connection ID of the QUIC connection.
+    pub fn scid(mut self, scid: [u8; 20]) -> Self {
+        self.scid = scid;
+        self
+    }
+
+    /// Transactions [`spawn`](Self::spawn)'s submission queue holds before
+    /// [`Submitter::submit`] reports it full.
+    pub fn submit_queue(mut self, len: usize) -> Self {
+        self.submit_queue = len.max(1);
+        self
+    }
+
+    /// Build and run the engine on a thread of its own, and hand out the submission side.
+    ///
+    /// The thread does the whole [`CpuSetup`] from `cpu` in the config: the engine's helper
+    /// threads go to the helper cores and the thread itself to `cpu.core`, leaving the
+    /// caller's threads where they are. `setup` runs on it after the build and before the
+    /// loop, e.g. to register response handlers on [`Engine::driver_mut`]; the driver's
+    /// [`on_tx_outcome`](QuicDriver::on_tx_outcome) handler is taken by the completion
+    /// channel. Build errors are returned here.
+    pub fn spawn(
+        self,
+        setup: impl FnOnce(&mut Engine) + Send + 'static,
+    ) -> Result<(EngineThread, Submitter, Completions), Error> {
+        let (submitter, submissions, done, completions) = submit::channel(self.submit_queue);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started_tx, started) = mpsc::sync_channel(1);
+
+        let thread_stop = Arc::clone(&stop);
+        let queue_len = self.submit_queue;
+        let thread = std::thread::Builder::new()
+            .name("afterburner".into())
+            .spawn(move || {
+                let cpu = self.cfg.cpu.clone();
+                let mut cpu_setup = CpuSetup::before_helpers(&cpu);
+                let mut engine = match self.build() {
+                    Ok(engine) => engine,
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                        return;
+                    }
+                };
+                setup(&mut engine);
+                engine.driver.on_tx_outcome(submit::outcome_handler(done.clone()));
+                cpu_setup.enter_hot_loop(&cpu);
+                let _ = started_tx.send(Ok(()));
+
+                let mut dispatcher = Dispatcher::new(submissions, done, engine.peer, queue_len);
+                engine.run(&thread_stop, |driver| dispatcher.poll(driver));
+                dispatcher.shutdown(Instant::now());
+                engine.shutdown();
+            })
+            .map_err(|source| Error::Io { step: "spawning engine thread", source })?;
+
+        match started.recv() {
+            Ok(Ok(())) => Ok((EngineThread { stop, thread: Some(thread) }, submitter, completions)),
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            }
+            // The thread panicked in build or setup
+            Err(_) => {
+                let _ = thread.join();
+                Err(Error::Io { step: "starting engine thread", source: std::io::Error::other("engine thread panicked") })
+            }
+        }
+    }
+
+    /// Validate the config, load and attach the XDP program, create and register the socket
+    /// and start the QUIC handshake. On error, whatever was set up is torn down again.
+    pub fn build(self) -> Result<Engine, Error> {
+        let cfg = self.cfg;
+        cfg.validate().map_err(|e| Error::Config(e.to_string()))?;
+        let net = cfg.network.clone();
+        let io = |step: &'static str| move |source: std::io::Error| Error::Io { step, source };
+        let bpf_err = |step: &'static str| move |source: BoxError| Error::Bpf { step, source };
+
+        let port = net.local.port();
+        let mut loader = EbpfLoader::new();
+        loader.set_global(afterburner_common::PORT_GLOBAL, &port, true);
+        let file;
+        let object = match &self.ebpf_object {
+            Some(path) => {
+                file = std::fs::read(path).map_err(|e| bpf_err("loading eBPF object")(e.into()))?;
+                &file[..]
+            }
+            None if !Self::has_embedded_object() => {
+                return Err(bpf_err("loading eBPF object")(
+                    "none was embedded at build time (see the build warning); pass an object file instead".into(),
+                ));
+            }
+            None => embedded_object(),
+        };
+        // The device-bound program only attaches in native mode
+        let hw_timestamps = self.rx_timestamps && matches!(net.xdp_mode, XdpMode::Auto | XdpMode::Drv);
+        let (object, kfuncs) = dev_bound::resolve_kfuncs(object, hw_timestamps)
+            .map_err(|e| bpf_err("loading eBPF object")(e.into()))?;
+        let mut bpf = loader.load(&object).map_err(|e| bpf_err("loading eBPF object")(e.into()))?;
+
+        // From here on the program detaches when `bpf` (or the attachment) drops, so error
+        // returns don't leave it behind
+        let bound = kfuncs.then(|| {
+            xdp::ifindex(&net.iface)
+                .and_then(|ifindex| dev_bound::load(&bpf, &object, ifindex))
+                .and_then(|program| xdp::attach_bound(program.as_fd(), &net.iface, net.xdp_force))
+                .inspect_err(|e| warn!(target: "xdp",
+                    "No device-bound XDP program ({}); attaching the plain one, without hardware RX timestamps", e))
+                .ok()
+        }).flatten();
+        let hw_tstamp = bound.as_ref().and_then(|_| RxHwTstamp::enable(&net.iface));
+        let attachment = match bound {
+            Some(attachment) => attachment,
+            None => {
+                let program = xdp_program(&mut bpf)?;
+                program.load().map_err(|e| bpf_err("loading XDP program")(e.into()))?;
+                xdp::attach(program, &net.iface, net.xdp_mode, net.xdp_force)
+                    .map_err(|source| Error::Attach { iface: net.iface.clone(), source })?
+            }
+        };
+        info!(target: "xdp", "eBPF program attached to {}", net.iface);
+
+        let socket = XdpSocket::new(&net.iface, net.queue_id, &cfg.umem)?;
+
+        let map = bpf.map_mut("XSK").ok_or_else(|| bpf_err("finding XSK map")("not in the eBPF object".into()))?;
+        let mut xsk_map = XskMap::try_from(map).map_err(|e| bpf_err("opening XSK map")(e.into()))?;
+        xsk_map
+            .set(net.queue_id, socket.as_raw_fd(), 0)
+            .map_err(|e| bpf_err("registering AF_XDP socket in XSK map")(e.into()))?;
+        info!(target: "xsk", "AF_XDP socket registered for queue {}", net.queue_id);
+
+        let metrics = Arc::new(Metrics::default());
+        let xdp_counters = bpf.take_map("STATS")
+            .and_then(|map| PerCpuArray::try_from(map).ok())
+            .map(XdpCounters::new);
+        let metrics_interval = cfg.metrics.interval();
+        let exporter = metrics::spawn_exporter(Arc::clone(&metrics), xdp_counters, ExportConfig {
+            prometheus: cfg.metrics.addr,
+            json_path: cfg.metrics.json_path.clone(),
+            interval: metrics_interval,
+        }).map_err(io("starting metrics exporter"))?;
+
+        let local = SocketAddr::V4(net.local);
+        let peer = SocketAddr::V4(net.peer);
+        let driver = QuicDriver::new(&self.scid, local, peer, &cfg.quic, cfg.flood.latency_mode, Arc::clone(&metrics))?;
+
+        let capture = match self.capture {
+            Some((path, snaplen, sample)) => {
+                let capture_cfg = CaptureConfig { path: path.clone(), iface: net.iface.clone(), snaplen, sample };
+                Some((Capture::start(capture_cfg).map_err(io("starting pcap capture"))?, path))
+            }
+            None => None,
+        };
+
+        Ok(Engine {
+            last_stats: socket.stats(),
+            socket,
+            driver,
+            metrics,
+            exporter,
+            net,
+            local,
+            peer,
+            capture,
+            rx_timings: self.rx_timestamps.then(RxTiming::new),
+            rx_metas: Vec::with_capacity(RX_BATCH as usize),
+            tx_spare: vec![0; cfg.umem.frame_size].into_boxed_slice(),
+            held_tx: None,
+            metrics_interval,
+            last_publish: Instant::now(),
+            bpf,
+            attachment,
+            hw_tstamp,
+        })
+    }
+}
+
+/// An engine running on its own thread, from [`EngineBuilder::spawn`]. Dropping it stops
+/// the loop and waits for [`Engine::shutdown`] to finish; by then anything not yet written
+/// completes as [`DropReason::Shutdown`](submit::DropReason::Shutdown), and anything written
+/// but not acknowledged as [`DropReason::ConnectionClosed`](submit::DropReason::ConnectionClosed).
+pub struct EngineThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EngineThread {
+    /// Stop the loop, close the connection and detach, and wait for all of it.
+    pub fn shutdown(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for EngineThread {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+/// A running data path. Drive it with [`run`](Engine::run) (or [`poll`](Engine::poll) from
+/// a loop of your own) and end it with [`shutdown`](Engine::shutdown).
+pub struct Engine {
+    socket: XdpSocket,
+    driver: QuicDriver,
+    metrics: Arc<Metrics>,
+    /// Stops with the engine, so a new one can bind the same address
+    exporter: Option<Exporter>,
+    net: NetworkConfig,
+    local: SocketAddr,
+    peer: SocketAddr,
+    /// The capture and the file it writes to
+    capture: Option<(Capture, PathBuf)>,
+    rx_timings: Option<RxTiming>,
+    rx_metas: Vec<Option<RxMeta>>,
+    /// A frame-sized buffer for `held_tx`
+    tx_spare: Box<[u8]>,
+    /// Length of the packet in `tx_spare`, which the connection had ready when TX was out
+    /// of frames or ring slots; sent ahead of anything else once a frame is free
+    held_tx: Option<usize>,
+    metrics_interval: Duration,
+    last_publish: Instant,
+    last_stats: XskStats,
+    /// Dropped after the socket; detaches the XDP program if `shutdown` didn't
+    bpf: Ebpf,
+    attachment: Attachment,
+    /// RX hardware timestamping we turned on for the device-bound program, off again on drop
+    hw_tstamp: Option<RxHwTstamp>,
+}
+
+impl Engine {
+    /// The QUIC connection, e.g. to register response handlers or debug log writers.
+    pub fn driver_mut(&mut self) -> &mut QuicDriver {
+        &mut self.driver
+    }
+
+    /// The metrics registry the engine writes to.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The loaded eBPF object, e.g. for `aya_log::EbpfLogger::init`.
+    pub fn ebpf_mut(&mut self) -> &mut Ebpf {
+        &mut self.bpf
+    }
+
+    /// Poll until `stop` is set. `feed` runs once per iteration, between reading the
+    /// connection and transmitting, and is where transactions get queued.
+    pub fn run(&mut self, stop: &AtomicBool, mut feed: impl FnMut(&mut QuicDriver)) {
+        while !stop.load(Ordering::Relaxed) {
+            self.poll(&mut feed);
+            std::hint::spin_loop();
+        }
+    }
+
+    /// One iteration: drain the RX ring, run the connection's timers and streams, call
+    /// `feed`, then put everything the connection has to send on the TX ring.
+    pub fn poll(&mut self, feed: impl FnOnce(&mut QuicDriver)) {
+        self.receive();
+        if let Some(t) = self.rx_timings.as_mut() {
+            t.maybe_print();
+        }
+
+        self.driver.on_timeout();
+        self.driver.drain_streams();
+        feed(&mut self.driver);
+
+        let (packets, bytes) = self.transmit(u32::MAX);
+        self.metrics.tx_packets.add(packets as u64);
+        self.metrics.tx_bytes.add(bytes);
+        self.socket.flush_tx(); // Single syscall for entire TX batch
+
+        if self.last_publish.elapsed() >= self.metrics_interval {
+            self.last_publish = Instant::now();
+            self.publish();
+        }
+    }
+
+    /// Close the connection, flush what that queued, export the final metrics, finish the
+    /// capture and detach the XDP program.
+    pub fn shutdown(mut self) {
+        self.driver.close(b"done");
+        self.transmit(16);
+        self.socket.flush_tx(); // Flush shutdown packets
+
+        self.publish();
+        if let Some(exporter) = self.exporter.take() {
+            exporter.stop();
+        }
+
+        if let Some((c, path)) = self.capture.take() {
+            match c.finish() {
+                Ok((written, dropped)) => info!(target: "pcap", "{} frames written to {} ({} dropped)", written, path.display(), dropped),
+                Err(e) => error!(target: "pcap", "Failed to write {}: {}", path.display(), e),
+            }
+        }
+
+        // Detach explicitly rather than relying on process teardown
+        let detached = match self.attachment {
+            Attachment::Link(link_id) => xdp_program(&mut self.bpf).and_then(|program| {
+                program.detach(link_id).map_err(|e| Error::Bpf { step: "detaching XDP program", source: e.into() })
+            }),
+            Attachment::Replaced(replaced) => {
+                replaced.detach().map_err(|source| Error::Io { step: "detaching XDP program", source })
+            }
+            Attachment::Bound(link) => {
+                drop(link);
+                Ok(())
+            }
+        };
+        match detached {
+            Ok(()) => info!(target: "xdp", "eBPF program detached from {}", self.net.iface),
+            Err(e) => error!(target: "xdp", "Failed to detach from {}: {}", self.net.iface, e),
+        }
+        // After the program that reads the timestamps is gone
+        drop(self.hw_tstamp.take());
+    }
+
+    /// Process ALL available RX packets - prevents backlog and fill ring starvation.
+    fn receive(&mut self) {
+        loop {
+            let batch = self.socket.rx_batch(RX_BATCH);
+            if batch.is_empty() {
+                break;
+            }
+            let polled_ns = self.rx_timings.as_ref().map(|_| rx_timing::monotonic_ns());
+            for mut frame in &batch {
+                let len = frame.data().len();
+                self.metrics.rx_packets.inc();
+                self.metrics.rx_bytes.add(len as u64);
+                // Before process_input: quiche decrypts in place
+                if let Some((c, _)) = self.capture.as_mut() {
+                    c.rx(frame.data());
+                }
+                if len > 42 {
+                    self.driver.process_input(&mut frame.data_mut()[42..], self.local, self.peer);
+                }
+                if polled_ns.is_some() {
+                    self.rx_metas.push(frame.meta().copied());
+                }
+            }
+            // Release frames back to fill ring AFTER processing is complete
+            drop(batch);
+            if let (Some(t), Some(polled_ns)) = (self.rx_timings.as_mut(), polled_ns) {
+                for meta in self.rx_metas.drain(..) {
+                    t.record(meta.as_ref(), polled_ns);
+                }
+            }
+        }
+    }
+
+    /// Queue up to `max` packets from the connection on the TX ring (without kicking the
+    /// NIC), a batch of reserved descriptors at a time. Returns packets and bytes queued.
+    fn transmit(&mut self, max: u32) -> (u32, u64) {
+        let (mut packets, mut bytes) = (0, 0);
+        while packets < max {
+            let batch = self.socket.tx_batch(TX_BATCH.min(max - packets));
+            loop {
+                let Some(mut frame) = batch.next_frame() else {
+                    // Out of frames or ring slots. Only a packet that is actually ready counts
+                    // as a shortage, so take it from the connection and hold it for later.
+                    if batch.is_short() && self.held_tx.is_none() {
+                        self.held_tx = self.driver.write_transmit(&mut self.tx_spare[42..]).filter(|&len| len > 0);
+                        if self.held_tx.is_some() {
+                            batch.missed();
+                        }
+                    }
+                    break;
+                };
+                let buf = frame.buf_mut();
+                let quic_len = match self.held_tx.take() {
+                    Some(len) => {
+                        buf[42..42 + len].copy_from_slice(&self.tx_spare[42..42 + len]);
+                        len
+                    }
+                    None => match self.driver.write_transmit(&mut buf[42..]).filter(|&len| len > 0) {
+                        Some(len) => len,
+                        None => break,
+                    },
+                };
+                headers::write_headers(buf, quic_len, &self.net);
+                if let Some((c, _)) = self.capture.as_mut() {
+                    c.tx(&buf[..42 + quic_len]);
+                }
+                frame.send(42 + quic_len);
+                bytes += (42 + quic_len) as u64;
+            }
+            let (reserved, sent) = (batch.len(), batch.sent());
+            drop(batch);
+            packets += sent;
+            // Out of frames or ring space, or nothing left to send
+            if reserved == 0 || sent < reserved {
+                break;
+            }
+        }
+        (packets, bytes)
+    }
+
+    /// Copy connection stats, ring gauges and socket counters into the metrics registry.
+    fn publish(&mut self) {
+        let metrics = &self.metrics;
+        self.driver.publish_stats();
+        let rings = self.socket.ring_occupancy();
+        metrics.rx_ring.set(rings.rx as f64);
+        metrics.fill_ring.set(rings.fill as f64);
+        metrics.tx_ring.set(rings.tx as f64);
+        metrics.comp_ring.set(rings.comp as f64);
+        metrics.free_frames.set(rings.free_frames as f64);
+        metrics.rx_frames.set(rings.rx_frames as f64);
+        let stats = self.socket.stats();
+        metrics.record_xsk(&stats);
+        stats.log_changes(&self.last_stats);
+        self.last_stats = stats;
+    }
+}
+
+/// The object embedded by the build script; empty if it couldn't build one and
+/// `AFTERBURNER_ALLOW_EMPTY_EBPF=1` was set.
+fn embedded_object() -> &'static [u8] {
+    aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/afterburner"))
+}
+
+/// The `afterburner` XDP program in the loaded object.
+fn xdp_program(bpf: &mut Ebpf) -> Result<&mut Xdp, Error> {
+    let program = bpf
+        .program_mut("afterburner")
+        .ok_or_else(|| Error::Bpf { step: "finding XDP program", source: "not in the eBPF object".into() })?;
+    program
+        .try_into()
+        .map_err(|e: aya::programs::ProgramError| Error::Bpf { step: "finding XDP program", source: e.into() })
+}
@@ -0,0 +1,74 @@
+//! Everything that can stop afterburner from starting, with the step that failed and what it
+//! was working on. Each class of failure exits with its own status, so a supervisor can tell
+//! a bad config (don't restart) from a busy queue or a missing driver feature.
+
+use std::{fmt, io};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file can't be read or parsed, or the result doesn't validate
+    Config(String),
+    /// Loading the eBPF object or program, or setting up its maps
+    Bpf { step: &'static str, source: BoxError },
+    /// Attaching the XDP program to the interface
+    Attach { iface: String, source: io::Error },
+    /// Creating, mapping or binding the AF_XDP socket and its UMEM
+    Socket { step: &'static str, iface: String, queue_id: u32, source: io::Error },
+    /// Configuring or opening the QUIC connection
+    Quic { step: &'static str, source: quiche::Error },
+    /// Other I/O around the data path: signal handlers, threads, sockets, exporters, capture
+    Io { step: &'static str, source: io::Error },
+}
+
+impl Error {
+    /// Process exit status for this class of failure (documented in the README).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Bpf { .. } => 3,
+            Error::Attach { .. } => 4,
+            Error::Socket { .. } => 5,
+            Error::Quic { .. } => 6,
+            Error::Io { .. } => 7,
+        }
+    }
+
+    /// Log target the error is reported under, matching where the step logs otherwise.
+    pub fn target(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Bpf { .. } | Error::Attach { .. } => "xdp",
+            Error::Socket { .. } => "xsk",
+            Error::Quic { .. } => "quic",
+            Error::Io { .. } => "run",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => f.write_str(msg),
+            Error::Bpf { step, source } => write!(f, "{}: {}", step, source),
+            Error::Attach { iface, source } => write!(f, "attaching XDP program to {}: {}", iface, source),
+            Error::Socket { step, iface, queue_id, source } => {
+                write!(f, "AF_XDP socket on {} queue {}: {}: {}", iface, queue_id, step, source)
+            }
+            Error::Quic { step, source } => write!(f, "QUIC {}: {}", step, source),
+            Error::Io { step, source } => write!(f, "{}: {}", step, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(_) => None,
+            Error::Bpf { source, .. } => Some(source.as_ref()),
+            Error::Attach { source, .. } | Error::Socket { source, .. } | Error::Io { source, .. } => Some(source),
+            Error::Quic { source, .. } => Some(source),
+        }
+    }
+}
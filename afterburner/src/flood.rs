@@ -0,0 +1,103 @@
+//! Load generator: a batch of mock transactions per loop iteration, stamped with a sequence
+//! number and send time so the server can measure loss and latency.
+
+use std::time::Duration;
+
+use afterburner_common::codec;
+use crate::config::FloodConfig;
+use crate::quic_driver::QuicDriver;
+use crate::emit::MockTransaction;
+
+/// How transactions are carried to the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TxMode {
+    /// Reliable, flow-controlled bidi streams 0, 4, 8 and 12
+    Stream,
+    /// A unidirectional stream per transaction, tracked until acknowledged (see `track.rs`)
+    UniStream,
+    /// Unreliable QUIC DATAGRAM frames (RFC 9221), one transaction each
+    Datagram,
+}
+
+/// Sends `flood.batch_size` transactions per `shoot`, on streams 0, 4, 8 and 12, on a
+/// stream each or as datagrams.
+pub struct Flooder {
+    /// Transactions queued so far, and the next sequence number
+    pub tx_count: u64,
+    batch_size: usize,
+    mode: TxMode,
+    probe_interval: Duration,
+    mock_tx: MockTransaction,
+    buf: [u8; 1024],
+}
+
+impl Flooder {
+    pub fn new(cfg: &FloodConfig) -> Self {
+        Flooder {
+            tx_count: 0,
+            batch_size: cfg.batch_size,
+            mode: cfg.tx_mode,
+            probe_interval: cfg.probe_interval(),
+            mock_tx: MockTransaction::new(),
+            buf: [0u8; 1024],
+        }
+    }
+
+    /// Queue the next batch on the connection; nothing until the handshake is done.
+    pub fn shoot(&mut self, driver: &mut QuicDriver) {
+        if !driver.conn.is_established() {
+            return;
+        }
+
+        let len = self.mock_tx.serialize(&mut self.buf);
+        // Out of credit, a stream or queue space: a probe would only compete for it
+        let mut starved = false;
+
+        for i in 0..self.batch_size {
+            // tx_count doubles as the sequence number: it only advances on success, so the
+            // server sees gaps only for transactions that really went missing
+            let now_ns = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64;
+            codec::stamp_tx(&mut self.buf[..len], self.tx_count, now_ns);
+            let payload = &self.buf[..len];
+
+            match self.mode {
+                TxMode::Stream => match driver.send_stream_tx((i * 4) as u64, payload) {
+                    Ok(true) => self.tx_count += 1,
+                    Ok(false) => {}
+                    Err(quiche::Error::Done) => {
+                        starved = true;
+                        break;
+                    }
+                    Err(_) => {}
+                },
+                // tx_count is the tag, so outcomes can be matched to the server's sequence numbers
+                TxMode::UniStream => match driver.send_tracked_tx(self.tx_count, payload) {
+                    Ok(_) => self.tx_count += 1,
+                    // Out of flow control credit or streams until the server raises its limits
+                    Err(_) => {
+                        starved = true;
+                        break;
+                    }
+                },
+                TxMode::Datagram => match driver.send_dgram_tx(payload) {
+                    Ok(()) => self.tx_count += 1,
+                    // Send queue full, or the peer didn't negotiate DATAGRAM support
+                    Err(_) => {
+                        starved = true;
+                        break;
+                    }
+                },
+            }
+        }
+
+        // A round-trip probe every `flood.probe_interval_us` (echo mode only)
+        if !starved {
+            driver.send_probe(self.probe_interval);
+        }
+    }
+}
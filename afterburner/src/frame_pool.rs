@@ -0,0 +1,258 @@
+//! One allocator for every UMEM frame, shared by RX and TX.
+//!
+//! Frames that aren't in a kernel ring sit on a single free list. RX owns the frames on the
+//! fill and RX rings (and in an unreleased `RxBatch`) and is topped up to `rx_target` frames.
+//! The target grows while RX batches come back full and shrinks whenever TX has a packet
+//! ready and no free frame for it, so frames drift to whichever side is busier. Two floors bound this:
+//! RX never holds fewer than `rx_reserve` frames (TX can't take frames RX needs to get back
+//! to it), and the fill ring never takes the last `tx_reserve` free frames.
+
+pub struct FramePool {
+    free: Vec<u64>,
+    frame_size: u64,
+    /// Frames on the fill and RX rings or in an RX batch
+    rx_owned: u32,
+    /// Frames RX is topped up to
+    rx_target: u32,
+    rx_reserve: u32,
+    tx_reserve: u32,
+    /// Most frames RX may hold: the fill ring's capacity
+    rx_max: u32,
+}
+
+impl FramePool {
+    /// All `num_frames` frames free, with RX initially aiming for half of them.
+    pub fn new(num_frames: u32, frame_size: usize, ring_size: u32, rx_reserve: u32, tx_reserve: u32) -> Self {
+        let rx_max = ring_size.min(num_frames.saturating_sub(tx_reserve)).max(rx_reserve);
+        FramePool {
+            // Popped from the back, so the lowest frames are handed out first
+            free: (0..num_frames as u64).rev().map(|i| i * frame_size as u64).collect(),
+            frame_size: frame_size as u64,
+            rx_owned: 0,
+            rx_target: (num_frames / 2).clamp(rx_reserve, rx_max),
+            rx_reserve,
+            tx_reserve,
+            rx_max,
+        }
+    }
+
+    /// Frames to move to the fill ring now: up to the RX target, but only out of the frames
+    /// beyond the TX reserve unless RX is below its own reserve.
+    #[inline(always)]
+    pub fn rx_deficit(&self) -> u32 {
+        let free = self.free.len() as u32;
+        let spare = free.saturating_sub(self.tx_reserve).max(self.rx_reserve.saturating_sub(self.rx_owned));
+        self.rx_target.saturating_sub(self.rx_owned).min(spare).min(free)
+    }
+
+    /// A free frame for the fill ring.
+    #[inline(always)]
+    pub fn take_rx(&mut self) -> Option<u64> {
+        let addr = self.free.pop()?;
+        self.rx_owned += 1;
+        Some(addr)
+    }
+
+    /// A received frame is done with and goes back to the free list.
+    #[inline(always)]
+    pub fn rx_done(&mut self, addr: u64) {
+        self.rx_owned -= 1;
+        self.free.push(self.frame_start(addr));
+    }
+
+    /// The kernel handed back a descriptor whose address isn't a frame of ours: RX no longer
+    /// holds the frame, but there is no address to put back on the free list.
+    #[inline(always)]
+    pub fn rx_lost(&mut self) {
+        self.rx_owned -= 1;
+    }
+
+    /// A received frame moved to the TX ring as is; it comes back through `tx_done`.
+    #[inline(always)]
+    pub fn rx_forwarded(&mut self) {
+        self.rx_owned -= 1;
+    }
+
+    /// Frames TX may take now.
+    #[inline(always)]
+    pub fn tx_available(&self) -> u32 {
+        (self.free.len() as u32).saturating_sub(self.rx_reserve.saturating_sub(self.rx_owned))
+    }
+
+    /// A free frame for TX; check `tx_available` first.
+    #[inline(always)]
+    pub fn take_tx(&mut self) -> Option<u64> {
+        self.free.pop()
+    }
+
+    /// A TX frame completed or was never sent.
+    #[inline(always)]
+    pub fn tx_done(&mut self, addr: u64) {
+        self.free.push(self.frame_start(addr));
+    }
+
+    /// RX is busy: let it hold `n` more frames.
+    #[inline(always)]
+    pub fn grow_rx(&mut self, n: u32) {
+        self.rx_target = (self.rx_target + n).min(self.rx_max);
+    }
+
+    /// TX had `n` packets ready and no free frame for them: stop refilling that many RX
+    /// frames. Not for speculative asks; `tx_available` alone never moves the target.
+    #[inline(always)]
+    pub fn shrink_rx(&mut self, n: u32) {
+        self.rx_target = self.rx_target.saturating_sub(n).max(self.rx_reserve);
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn rx_frames(&self) -> u32 {
+        self.rx_owned
+    }
+
+    /// The kernel may hand back an address inside the frame (headroom, metadata).
+    #[inline(always)]
+    fn frame_start(&self, addr: u64) -> u64 {
+        addr - addr % self.frame_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 2048;
+
+    /// 16 frames, fill ring of 16, RX reserve 2, TX reserve 4.
+    fn pool() -> FramePool {
+        FramePool::new(16, FRAME, 16, 2, 4)
+    }
+
+    fn take_tx(pool: &mut FramePool, n: u32) {
+        for _ in 0..n {
+            assert!(pool.tx_available() > 0);
+            pool.take_tx().unwrap();
+        }
+    }
+
+    #[test]
+    fn rx_starts_at_half_and_fills_to_target() {
+        let mut pool = pool();
+        assert_eq!(pool.rx_deficit(), 8);
+        for _ in 0..8 {
+            pool.take_rx().unwrap();
+        }
+        assert_eq!(pool.rx_frames(), 8);
+        assert_eq!(pool.free_frames(), 8);
+        assert_eq!(pool.rx_deficit(), 0);
+    }
+
+    #[test]
+    fn rx_target_grows_to_ring_and_tx_reserve_limit() {
+        let mut pool = pool();
+        pool.grow_rx(3);
+        assert_eq!(pool.rx_deficit(), 11);
+        // Capped by the 16 frames minus the TX reserve
+        pool.grow_rx(100);
+        assert_eq!(pool.rx_deficit(), 12);
+
+        // And by the fill ring's size
+        let mut small_ring = FramePool::new(16, FRAME, 4, 2, 4);
+        small_ring.grow_rx(100);
+        assert_eq!(small_ring.rx_deficit(), 4);
+    }
+
+    #[test]
+    fn rx_target_shrinks_to_rx_reserve() {
+        let mut pool = pool();
+        pool.shrink_rx(5);
+        assert_eq!(pool.rx_deficit(), 3);
+        pool.shrink_rx(100);
+        assert_eq!(pool.rx_deficit(), 2);
+    }
+
+    #[test]
+    fn fill_ring_leaves_tx_reserve() {
+        let mut pool = pool();
+        take_tx(&mut pool, 10);
+        // 6 free: only the 2 beyond the TX reserve go to RX
+        assert_eq!(pool.rx_deficit(), 2);
+        pool.take_rx().unwrap();
+        pool.take_rx().unwrap();
+        assert_eq!(pool.rx_deficit(), 0);
+        assert_eq!(pool.tx_available(), 4);
+    }
+
+    #[test]
+    fn tx_leaves_rx_reserve() {
+        let mut pool = pool();
+        // RX holds nothing yet, so TX can't take the 2 frames it must get back to
+        assert_eq!(pool.tx_available(), 14);
+        take_tx(&mut pool, 14);
+        assert_eq!(pool.tx_available(), 0);
+        // RX may dip into the TX reserve to reach its own reserve, but no further
+        assert_eq!(pool.rx_deficit(), 2);
+        pool.take_rx().unwrap();
+        assert_eq!(pool.tx_available(), 0);
+        pool.take_rx().unwrap();
+        assert_eq!(pool.rx_deficit(), 0);
+        assert_eq!(pool.tx_available(), 0);
+    }
+
+    #[test]
+    fn frames_return_by_their_start_address() {
+        let mut pool = pool();
+        let addr = pool.take_rx().unwrap();
+        assert_eq!(addr, 0);
+        // The kernel reports addresses past the headroom and metadata
+        pool.rx_done(addr + 256);
+        assert_eq!(pool.rx_frames(), 0);
+        assert_eq!(pool.take_tx(), Some(0));
+        pool.tx_done(FRAME as u64 - 1);
+        assert_eq!(pool.take_tx(), Some(0));
+    }
+
+    #[test]
+    fn forwarded_frame_moves_from_rx_to_tx() {
+        let mut pool = pool();
+        let addr = pool.take_rx().unwrap();
+        pool.rx_forwarded();
+        // Owned by neither side's free list until TX completes it
+        assert_eq!(pool.rx_frames(), 0);
+        assert_eq!(pool.free_frames(), 15);
+        pool.tx_done(addr + 256);
+        assert_eq!(pool.free_frames(), 16);
+        assert_eq!(pool.take_rx(), Some(addr));
+    }
+
+    #[test]
+    fn idle_tx_polls_leave_rx_target() {
+        let mut pool = pool();
+        for _ in 0..8 {
+            pool.take_rx().unwrap();
+        }
+        take_tx(&mut pool, 6);
+        // Fewer than a full TX batch free, polled many times with nothing to send
+        for _ in 0..1000 {
+            assert_eq!(pool.tx_available(), 2);
+        }
+        assert_eq!(pool.rx_target, 8);
+        // A packet that really found no frame takes one from RX
+        pool.shrink_rx(1);
+        assert_eq!(pool.rx_target, 7);
+    }
+
+    #[test]
+    fn lost_rx_frame_releases_rx_ownership_only() {
+        let mut pool = pool();
+        for _ in 0..8 {
+            pool.take_rx().unwrap();
+        }
+        pool.rx_lost();
+        assert_eq!(pool.rx_frames(), 7);
+        assert_eq!(pool.free_frames(), 8);
+        assert_eq!(pool.rx_deficit(), 1);
+    }
+}
@@ -0,0 +1,54 @@
+//! Callbacks for data the peer sends us beyond the driver's own timestamp and echo streams.
+//!
+//! Register them on the driver with [`QuicDriver::on_server_uni`], [`QuicDriver::on_bidi`]
+//! and [`QuicDriver::on_datagram`]. Streams without a handler are still read (so flow
+//! control keeps moving) and their data discarded. To consume responses on another thread,
+//! register a closure that forwards into an `mpsc::Sender`.
+//!
+//! [`QuicDriver::on_server_uni`]: crate::quic_driver::QuicDriver::on_server_uni
+//! [`QuicDriver::on_bidi`]: crate::quic_driver::QuicDriver::on_bidi
+//! [`QuicDriver::on_datagram`]: crate::quic_driver::QuicDriver::on_datagram
+
+/// Called with `(stream_id, data, fin)` for every read; `data` may be empty when only the
+/// fin arrives.
+pub type StreamHandler = Box<dyn FnMut(u64, &[u8], bool) + Send>;
+/// Called with the payload of each received DATAGRAM frame.
+pub type DatagramHandler = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Which handler a stream belongs to, from the two low bits of its ID (RFC 9000 §2.1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    /// Client- or server-initiated bidirectional: responses on our transaction streams
+    Bidi,
+    /// Server-initiated unidirectional
+    ServerUni,
+    /// Client-initiated unidirectional: never readable by us
+    ClientUni,
+}
+
+impl StreamKind {
+    pub fn of(stream_id: u64) -> StreamKind {
+        match stream_id & 0x3 {
+            0 | 1 => StreamKind::Bidi,
+            3 => StreamKind::ServerUni,
+            _ => StreamKind::ClientUni,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Handlers {
+    pub server_uni: Option<StreamHandler>,
+    pub bidi: Option<StreamHandler>,
+    pub datagram: Option<DatagramHandler>,
+}
+
+impl Handlers {
+    pub fn for_stream(&mut self, stream_id: u64) -> Option<&mut StreamHandler> {
+        match StreamKind::of(stream_id) {
+            StreamKind::Bidi => self.bidi.as_mut(),
+            StreamKind::ServerUni => self.server_uni.as_mut(),
+            StreamKind::ClientUni => None,
+        }
+    }
+}
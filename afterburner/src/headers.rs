@@ -1,14 +1,19 @@
-pub fn write_headers(frame: &mut [u8], payload_len: usize, src_port: u16, dst_port: u16) {
+//! Ethernet, IPv4 and UDP headers for frames sent straight onto the TX ring, which bypass
+//! the kernel's stack.
+
+use crate::config::NetworkConfig;
+
+/// Write Ethernet/IPv4/UDP headers for a `payload_len` byte datagram from `net.local` to
+/// `net.peer` into the first 42 bytes of `frame`.
+pub fn write_headers(frame: &mut [u8], payload_len: usize, net: &NetworkConfig) {
     // ---------------------------------------------------------
     // 1. ETHERNET HEADER (14 Bytes)
     // ---------------------------------------------------------
-    // Dest MAC: FF:FF:FF:FF:FF:FF (Broadcast - guarantees acceptance on veth)
-    frame[0] = 0xFF; frame[1] = 0xFF; frame[2] = 0xFF;
-    frame[3] = 0xFF; frame[4] = 0xFF; frame[5] = 0xFF;
+    // Dest MAC (broadcast by default - guarantees acceptance on veth)
+    frame[0..6].copy_from_slice(&net.dst_mac.0);
 
-    // Src MAC: 02:00:00:00:00:01 (Arbitrary Local)
-    frame[6] = 0x02; frame[7] = 0x00; frame[8] = 0x00;
-    frame[9] = 0x00; frame[10] = 0x00; frame[11] = 0x01;
+    // Src MAC (02:00:00:00:00:01 by default - arbitrary local)
+    frame[6..12].copy_from_slice(&net.src_mac.0);
 
     // EtherType: IPv4 (0x0800)
     frame[12] = 0x08; frame[13] = 0x00;
@@ -30,10 +35,10 @@ pub fn write_headers(frame: &mut [u8], payload_len: usize, src_port: u16, dst_po
     frame[23] = 17;   // Protocol (UDP)
     frame[24] = 0x00; frame[25] = 0x00; // Checksum (Placeholder)
 
-    // Src IP: 10.0.0.10
-    frame[26] = 10; frame[27] = 0; frame[28] = 0; frame[29] = 10;
-    // Dst IP: 10.0.0.11
-    frame[30] = 10; frame[31] = 0; frame[32] = 0; frame[33] = 11;
+    // Src IP
+    frame[26..30].copy_from_slice(&net.local.ip().octets());
+    // Dst IP
+    frame[30..34].copy_from_slice(&net.peer.ip().octets());
 
     // Calculate IP Checksum
     let checksum = ipv4_checksum(&frame[14..34]);
@@ -44,6 +49,7 @@ pub fn write_headers(frame: &mut [u8], payload_len: usize, src_port: u16, dst_po
     // 3. UDP HEADER (8 Bytes)
     // ---------------------------------------------------------
     let udp_len = (8 + payload_len) as u16;
+    let (src_port, dst_port) = (net.local.port(), net.peer.port());
 
     frame[34] = (src_port >> 8) as u8;
     frame[35] = (src_port & 0xFF) as u8;
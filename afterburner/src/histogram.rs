@@ -0,0 +1,267 @@
+//! Fixed-memory HDR-style histogram for latency samples.
+//!
+//! Values are bucketed log-linearly: every power-of-two range is split into 64 linear
+//! sub-buckets, giving < 1.6% relative error from 1ns up to ~18 minutes. All memory is
+//! allocated up front, so `record` never allocates and is a handful of instructions.
+
+use std::io::{self, Write};
+
+/// Sub-buckets per power of two (2^SUB_BITS for the first, half of that for the rest)
+const SUB_BITS: u32 = 7;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+const SUB_HALF: usize = SUB_COUNT / 2;
+/// Largest trackable value is 2^MAX_BITS - 1 ns; larger samples are clamped
+const MAX_BITS: u32 = 40;
+const LEN: usize = SUB_COUNT + (MAX_BITS - SUB_BITS) as usize * SUB_HALF;
+
+/// Percentiles printed in the shutdown dump; fixed so dumps diff line by line.
+const DUMP_PERCENTILES: [f64; 17] = [
+    0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0,
+    95.0, 99.0, 99.5, 99.9, 99.99, 99.999, 100.0,
+];
+
+pub struct Histogram {
+    counts: Box<[u64; LEN]>,
+    total: u64,
+    /// Of the recorded values, not clamped
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram { counts: Box::new([0; LEN]), total: 0, sum: 0, min: u64::MAX, max: 0 }
+    }
+
+    #[inline(always)]
+    fn index(v: u64) -> usize {
+        let v = v.min((1 << MAX_BITS) - 1);
+        if v < SUB_COUNT as u64 {
+            return v as usize;
+        }
+        let msb = 63 - v.leading_zeros();
+        let shift = msb - (SUB_BITS - 1);
+        shift as usize * SUB_HALF + (v >> shift) as usize
+    }
+
+    /// Lowest value that maps to bucket `idx`.
+    fn lowest(idx: usize) -> u64 {
+        if idx < SUB_COUNT {
+            return idx as u64;
+        }
+        let shift = idx / SUB_HALF - 1;
+        ((idx % SUB_HALF + SUB_HALF) as u64) << shift
+    }
+
+    /// Highest value that maps to bucket `idx`.
+    fn highest(idx: usize) -> u64 {
+        if idx < SUB_COUNT {
+            return idx as u64;
+        }
+        let shift = idx / SUB_HALF - 1;
+        Self::lowest(idx) + (1 << shift) - 1
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, v: u64) {
+        self.counts[Self::index(v)] += 1;
+        self.total += 1;
+        self.sum = self.sum.wrapping_add(v);
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.total == 0 { 0 } else { self.min }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Value at percentile `p` (0-100), reported as the top of its bucket and capped at
+    /// the recorded max.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let target = ((p / 100.0) * self.total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, &c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= target {
+                return Self::highest(idx).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Add all of `other`'s samples to `self`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.total += other.total;
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+        self.total = 0;
+        self.sum = 0;
+        self.min = u64::MAX;
+        self.max = 0;
+    }
+
+    /// Write a text dump: a fixed percentile ladder followed by the raw non-empty buckets,
+    /// so two runs can be compared with `diff`.
+    pub fn write_dump(&self, out: &mut impl Write, title: &str) -> Result<(), io::Error> {
+        writeln!(out, "# {} ({} samples, ns)", title, self.total)?;
+        writeln!(out, "# {:>10} {:>14}", "percentile", "value")?;
+        for p in DUMP_PERCENTILES {
+            writeln!(out, "{:>12} {:>14}", p, self.percentile(p))?;
+        }
+        writeln!(out, "#")?;
+        writeln!(out, "# {:>14} {:>14} {:>12}", "bucket_low", "bucket_high", "count")?;
+        for (idx, &c) in self.counts.iter().enumerate() {
+            if c > 0 {
+                writeln!(out, "{:>16} {:>14} {:>12}", Self::lowest(idx), Self::highest(idx), c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_are_exact() {
+        for v in 0..SUB_COUNT as u64 {
+            let idx = Histogram::index(v);
+            assert_eq!(idx, v as usize);
+            assert_eq!((Histogram::lowest(idx), Histogram::highest(idx)), (v, v));
+        }
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        // First log-linear range: buckets two wide
+        let first = SUB_COUNT as u64;
+        assert_eq!(Histogram::index(first), SUB_COUNT);
+        assert_eq!(Histogram::index(first + 1), SUB_COUNT);
+        assert_eq!(Histogram::index(first + 2), SUB_COUNT + 1);
+        assert_eq!(Histogram::index(2 * first - 1), SUB_COUNT + SUB_HALF - 1);
+        // Next power of two starts a new range with buckets twice as wide
+        assert_eq!(Histogram::index(2 * first), SUB_COUNT + SUB_HALF);
+        assert_eq!(Histogram::lowest(SUB_COUNT + SUB_HALF), 2 * first);
+        assert_eq!(Histogram::highest(SUB_COUNT + SUB_HALF), 2 * first + 3);
+    }
+
+    #[test]
+    fn every_value_falls_inside_its_bucket() {
+        let mut v = 1u64;
+        while v < 1 << MAX_BITS {
+            for x in [v - 1, v, v + 1, v + v / 3] {
+                let x = x.min((1 << MAX_BITS) - 1);
+                let idx = Histogram::index(x);
+                let (lo, hi) = (Histogram::lowest(idx), Histogram::highest(idx));
+                assert!(lo <= x && x <= hi, "{} not in bucket {} [{}, {}]", x, idx, lo, hi);
+                // Width stays within the advertised relative error
+                assert!((hi - lo) * SUB_HALF as u64 <= lo.max(1), "bucket {} [{}, {}] too wide", idx, lo, hi);
+            }
+            v *= 2;
+        }
+    }
+
+    #[test]
+    fn values_at_and_above_max_are_clamped() {
+        let top = (1u64 << MAX_BITS) - 1;
+        assert_eq!(Histogram::index(top), LEN - 1);
+        assert_eq!(Histogram::index(top + 1), LEN - 1);
+        assert_eq!(Histogram::index(u64::MAX), LEN - 1);
+        assert_eq!(Histogram::highest(LEN - 1), top);
+
+        let mut hist = Histogram::new();
+        hist.record(1 << MAX_BITS);
+        hist.record(u64::MAX);
+        assert_eq!(hist.count(), 2);
+        // The max is the real sample; percentiles stop at the top bucket
+        assert_eq!(hist.max(), u64::MAX);
+        assert_eq!(hist.percentile(100.0), top);
+    }
+
+    #[test]
+    fn empty_histogram_reports_zero() {
+        let hist = Histogram::new();
+        assert_eq!(hist.count(), 0);
+        assert_eq!(hist.min(), 0);
+        assert_eq!(hist.max(), 0);
+        assert_eq!(hist.percentile(0.0), 0);
+        assert_eq!(hist.percentile(50.0), 0);
+        assert_eq!(hist.percentile(100.0), 0);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut hist = Histogram::new();
+        for v in 1..=100 {
+            hist.record(v);
+        }
+        assert_eq!(hist.percentile(0.0), 1);
+        assert_eq!(hist.percentile(50.0), 50);
+        assert_eq!(hist.percentile(99.0), 99);
+        assert_eq!(hist.percentile(100.0), 100);
+        assert_eq!((hist.min(), hist.max(), hist.sum()), (1, 100, 5050));
+
+        // Above the exact range a percentile is the top of its bucket, capped at the max
+        let mut hist = Histogram::new();
+        hist.record(1000);
+        assert_eq!(hist.percentile(0.0), 1000);
+        hist.record(1001);
+        assert_eq!(hist.percentile(0.0), 1001);
+        assert_eq!(hist.percentile(100.0), 1001);
+    }
+
+    #[test]
+    fn reset_clears_everything() {
+        let mut hist = Histogram::new();
+        hist.record(5);
+        hist.record(5000);
+        hist.reset();
+        assert_eq!((hist.count(), hist.sum(), hist.min(), hist.max()), (0, 0, 0, 0));
+        assert_eq!(hist.percentile(100.0), 0);
+        hist.record(7);
+        assert_eq!((hist.min(), hist.max(), hist.percentile(0.0)), (7, 7, 7));
+    }
+
+    #[test]
+    fn merge_adds_samples() {
+        let mut a = Histogram::new();
+        let mut b = Histogram::new();
+        a.record(10);
+        b.record(20);
+        b.record(30);
+        a.merge(&b);
+        assert_eq!((a.count(), a.sum(), a.min(), a.max()), (3, 60, 10, 30));
+        assert_eq!(a.percentile(50.0), 20);
+    }
+}
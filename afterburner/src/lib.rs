@@ -0,0 +1,91 @@
+//! Afterburner sends QUIC transactions from userspace straight to the NIC: an XDP program
+//! redirects the server's UDP packets to an AF_XDP socket, and a quiche connection reads
+//! and writes its packets in the socket's UMEM, with no kernel network stack in between.
+//!
+//! [`EngineBuilder`] sets the whole path up from a [`Config`], and [`Engine`] drives it;
+//! transactions are queued on the connection from the loop's `feed` callback:
+//!
+//! ```no_run
+//! use std::sync::atomic::AtomicBool;
+//! use afterburner::{Config, EngineBuilder};
+//!
+//! # fn main() -> Result<(), afterburner::Error> {
+//! let mut cfg = Config::default();
+//! cfg.network.iface = "eth0".into();
+//! let mut engine = EngineBuilder::new(cfg).build()?;
+//!
+//! let stop = AtomicBool::new(false);
+//! let tx = [0u8; 235];
+//! engine.run(&stop, |driver| {
+//!     if driver.conn.is_established() {
+//!         let _ = driver.send_stream_tx(0, &tx);
+//!     }
+//! });
+//! engine.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To submit from other threads instead, [`EngineBuilder::spawn`] runs the loop on a pinned
+//! thread of its own and returns a cloneable [`Submitter`] and a channel reporting when
+//! each transaction was written to a stream, acknowledged, or dropped:
+//!
+//! ```no_run
+//! use std::time::{Duration, Instant};
+//! use afterburner::submit::TxStatus;
+//! use afterburner::{Config, EngineBuilder, Transaction};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let cfg = Config::load("afterburner.toml".as_ref())?;
+//! let (engine, submitter, completions) = EngineBuilder::new(cfg).spawn(|_| {})?;
+//!
+//! let tx = Transaction::new(vec![0u8; 235]).deadline(Instant::now() + Duration::from_millis(400));
+//! let id = submitter.submit(tx)?;
+//! for c in completions.iter() {
+//!     if c.id == id && !matches!(c.status, TxStatus::Written { .. }) {
+//!         println!("{}: {:?}", c.id, c.status);
+//!         break;
+//!     }
+//! }
+//! engine.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The pieces are public for callers that want their own loop: [`XdpSocket`] for the
+//! socket and its frame batches, [`headers::write_headers`] for the frame headers,
+//! [`QuicDriver`] for the connection and [`Flooder`] for the built-in load generator.
+//!
+//! The eBPF object is built and embedded by this crate's build script (see the README);
+//! [`EngineBuilder::ebpf_object`] loads one from disk instead.
+
+pub mod capture;
+pub mod config;
+pub mod cpu;
+pub mod echo;
+pub mod emit;
+pub mod engine;
+pub mod error;
+pub mod flood;
+pub mod handlers;
+pub mod headers;
+pub mod histogram;
+pub mod metrics;
+pub mod quic_driver;
+pub mod rx_timing;
+pub mod submit;
+pub mod track;
+pub mod umem;
+pub mod xdp;
+pub mod xsk;
+mod dev_bound;
+mod frame_pool;
+mod xsk_ring;
+
+pub use config::Config;
+pub use engine::{Engine, EngineBuilder, EngineThread};
+pub use error::Error;
+pub use flood::Flooder;
+pub use quic_driver::QuicDriver;
+pub use submit::{Submitter, Transaction};
+pub use xsk::XdpSocket;
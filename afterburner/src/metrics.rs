@@ -0,0 +1,593 @@
+//! Lock-free metrics registry and its off-thread exporters.
+//!
+//! The hot loop is the only writer of each metric, so updates are plain relaxed
+//! load/store pairs rather than `lock`-prefixed read-modify-writes. Exporters read the
+//! values from their own thread and render them as Prometheus text (`/metrics`) or as
+//! JSON lines appended to a file.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use afterburner_common::{
+    XDP_STATS_LEN, XDP_STAT_ABORTED, XDP_STAT_PASS, XDP_STAT_REDIRECT, XDP_STAT_REDIRECT_FAILED,
+};
+use aya::maps::{MapData, PerCpuArray};
+use log::{error, info};
+use crate::histogram::Histogram;
+use crate::xsk::XskStats;
+
+/// Monotonic counter. Single writer.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline(always)]
+    pub fn add(&self, n: u64) {
+        self.0.store(self.0.load(Ordering::Relaxed).wrapping_add(n), Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Mirror a total that is already counted elsewhere.
+    #[inline(always)]
+    pub fn set(&self, v: u64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Point-in-time value. Single writer.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    #[inline(always)]
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Quantiles exported for a latency series, as `(label, quantile)`.
+const QUANTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)];
+
+/// Latency quantiles over the whole run, exported as a Prometheus summary. The engine
+/// thread computes them from its HDR [`Histogram`](crate::histogram::Histogram) and
+/// publishes them every metrics interval, so they carry its < 1.6% error rather than a
+/// bucket width. Single writer.
+#[derive(Default)]
+pub struct Quantiles {
+    count: Counter,
+    sum_ns: Counter,
+    values_ns: [Counter; QUANTILES.len()],
+}
+
+impl Quantiles {
+    pub fn publish(&self, hist: &Histogram) {
+        self.count.set(hist.count());
+        self.sum_ns.set(hist.sum());
+        for (value, (_, q)) in self.values_ns.iter().zip(QUANTILES) {
+            value.set(hist.percentile(q * 100.0));
+        }
+    }
+}
+
+/// Everything the engine exports. One instance, shared via `Arc`.
+#[derive(Default)]
+pub struct Metrics {
+    // AF_XDP data path
+    pub tx_packets: Counter,
+    pub tx_bytes: Counter,
+    pub rx_packets: Counter,
+    pub rx_bytes: Counter,
+    pub rx_ring: Gauge,
+    pub fill_ring: Gauge,
+    pub tx_ring: Gauge,
+    pub comp_ring: Gauge,
+    pub free_frames: Gauge,
+    pub rx_frames: Gauge,
+    pub xsk_rx_dropped: Counter,
+    pub xsk_rx_ring_full: Counter,
+    pub xsk_fill_ring_empty: Counter,
+    pub xsk_rx_invalid_descs: Counter,
+    pub xsk_rx_invalid_addrs: Counter,
+    pub xsk_tx_invalid_descs: Counter,
+    pub xsk_tx_ring_empty: Counter,
+    pub xsk_tx_ring_full: Counter,
+    pub xsk_tx_frames_exhausted: Counter,
+    pub xsk_forward_dropped: Counter,
+
+    // Application
+    pub tx_transactions: Counter,
+    pub tx_acked: Counter,
+    pub tx_stopped: Counter,
+    pub tx_failed: Counter,
+    pub tx_unacked_closed: Counter,
+    pub tx_in_flight: Gauge,
+    pub time_to_ack_p50_us: Gauge,
+    pub time_to_ack_p99_us: Gauge,
+    pub rx_timestamps: Counter,
+    pub timestamps_lost: Counter,
+    pub codec_errors: Counter,
+    pub rx_uni_bytes: Counter,
+    pub rx_bidi_bytes: Counter,
+    pub rx_datagrams: Counter,
+    /// One-way latency from the server's timestamps (`--latency-mode one-way`)
+    pub latency: Quantiles,
+    /// Round trip of echoed probes (`--latency-mode echo`)
+    pub rtt: Quantiles,
+
+    // quiche Stats / PathStats
+    pub quic_sent: Counter,
+    pub quic_recv: Counter,
+    pub quic_lost: Counter,
+    pub quic_retrans: Counter,
+    pub quic_sent_bytes: Counter,
+    pub quic_recv_bytes: Counter,
+    pub rtt_us: Gauge,
+    pub min_rtt_us: Gauge,
+    pub rttvar_us: Gauge,
+    pub cwnd_bytes: Gauge,
+    pub delivery_rate: Gauge,
+}
+
+impl Metrics {
+    fn counters(&self) -> [(&'static str, &'static str, &Counter); 31] {
+        [
+            ("tx_packets", "Packets submitted to the AF_XDP TX ring", &self.tx_packets),
+            ("tx_bytes", "Bytes submitted to the AF_XDP TX ring", &self.tx_bytes),
+            ("rx_packets", "Packets received from the AF_XDP RX ring", &self.rx_packets),
+            ("rx_bytes", "Bytes received from the AF_XDP RX ring", &self.rx_bytes),
+            ("xsk_rx_dropped", "Packets the kernel dropped for the socket (XDP_STATISTICS)", &self.xsk_rx_dropped),
+            ("xsk_rx_ring_full", "Packets dropped because the RX ring was full", &self.xsk_rx_ring_full),
+            ("xsk_fill_ring_empty", "Times the kernel found the fill ring empty", &self.xsk_fill_ring_empty),
+            ("xsk_rx_invalid_descs", "Invalid descriptors the kernel saw on RX", &self.xsk_rx_invalid_descs),
+            ("xsk_rx_invalid_addrs", "RX descriptors pointing outside the UMEM", &self.xsk_rx_invalid_addrs),
+            ("xsk_tx_invalid_descs", "Invalid descriptors the kernel saw on TX", &self.xsk_tx_invalid_descs),
+            ("xsk_tx_ring_empty", "Times the kernel found the TX ring empty when woken", &self.xsk_tx_ring_empty),
+            ("xsk_tx_ring_full", "Packets ready to send that found the TX ring full", &self.xsk_tx_ring_full),
+            ("xsk_tx_frames_exhausted", "Packets ready to send that found no free frame", &self.xsk_tx_frames_exhausted),
+            ("xsk_forward_dropped", "Forwarded frames dropped on a full TX ring", &self.xsk_forward_dropped),
+            ("tx_transactions", "Transactions accepted by stream_send", &self.tx_transactions),
+            ("tx_acked", "Tracked transactions the server acknowledged in full", &self.tx_acked),
+            ("tx_stopped", "Tracked transactions whose stream the server stopped", &self.tx_stopped),
+            ("tx_failed", "Tracked transactions quiche refused part of", &self.tx_failed),
+            ("tx_unacked_closed", "Tracked transactions unacknowledged when the connection closed", &self.tx_unacked_closed),
+            ("rx_timestamps", "Timestamp records received from the server", &self.rx_timestamps),
+            ("timestamps_lost", "Gaps in the server timestamp sequence", &self.timestamps_lost),
+            ("codec_errors", "Malformed or unexpected frames from the server", &self.codec_errors),
+            ("rx_uni_bytes", "Bytes on other server-initiated unidirectional streams", &self.rx_uni_bytes),
+            ("rx_bidi_bytes", "Response bytes on bidirectional streams", &self.rx_bidi_bytes),
+            ("rx_datagrams", "QUIC DATAGRAM frames received", &self.rx_datagrams),
+            ("quic_sent", "QUIC packets sent", &self.quic_sent),
+            ("quic_recv", "QUIC packets received", &self.quic_recv),
+            ("quic_lost", "QUIC packets declared lost", &self.quic_lost),
+            ("quic_retrans", "QUIC packets retransmitted", &self.quic_retrans),
+            ("quic_sent_bytes", "QUIC bytes sent", &self.quic_sent_bytes),
+            ("quic_recv_bytes", "QUIC bytes received", &self.quic_recv_bytes),
+        ]
+    }
+
+    fn gauges(&self) -> [(&'static str, &'static str, &Gauge); 14] {
+        [
+            ("rx_ring", "Descriptors waiting in the RX ring", &self.rx_ring),
+            ("fill_ring", "Frames available to the kernel in the fill ring", &self.fill_ring),
+            ("tx_ring", "Descriptors waiting in the TX ring", &self.tx_ring),
+            ("comp_ring", "Descriptors waiting in the completion ring", &self.comp_ring),
+            ("free_frames", "UMEM frames free for RX or TX", &self.free_frames),
+            ("rx_frames", "UMEM frames held by RX (fill and RX rings)", &self.rx_frames),
+            ("tx_in_flight", "Tracked transactions waiting for their ack", &self.tx_in_flight),
+            ("time_to_ack_p50_us", "Median time from stream write to ack of tracked transactions", &self.time_to_ack_p50_us),
+            ("time_to_ack_p99_us", "99th percentile time from stream write to ack of tracked transactions", &self.time_to_ack_p99_us),
+            ("rtt_us", "Smoothed RTT of the active path", &self.rtt_us),
+            ("min_rtt_us", "Minimum RTT of the active path", &self.min_rtt_us),
+            ("rttvar_us", "RTT variation of the active path", &self.rttvar_us),
+            ("cwnd_bytes", "Congestion window of the active path", &self.cwnd_bytes),
+            ("delivery_rate", "Estimated delivery rate (bytes/s)", &self.delivery_rate),
+        ]
+    }
+
+    /// Mirror the socket's health counters.
+    pub fn record_xsk(&self, stats: &XskStats) {
+        let targets = [
+            &self.xsk_rx_dropped,
+            &self.xsk_rx_ring_full,
+            &self.xsk_fill_ring_empty,
+            &self.xsk_rx_invalid_descs,
+            &self.xsk_rx_invalid_addrs,
+            &self.xsk_tx_invalid_descs,
+            &self.xsk_tx_ring_empty,
+            &self.xsk_tx_ring_full,
+            &self.xsk_tx_frames_exhausted,
+            &self.xsk_forward_dropped,
+        ];
+        for (counter, (_, value)) in targets.into_iter().zip(stats.counters()) {
+            counter.set(value);
+        }
+    }
+
+    /// Render in the Prometheus text exposition format.
+    pub fn render_prometheus(&self, xdp: Option<&[u64; XDP_STATS_LEN as usize]>, out: &mut String) {
+        for (name, help, c) in self.counters() {
+            let _ = writeln!(out, "# HELP afterburner_{name}_total {help}\n# TYPE afterburner_{name}_total counter");
+            let _ = writeln!(out, "afterburner_{name}_total {}", c.get());
+        }
+        for (name, help, g) in self.gauges() {
+            let _ = writeln!(out, "# HELP afterburner_{name} {help}\n# TYPE afterburner_{name} gauge");
+            let _ = writeln!(out, "afterburner_{name} {}", g.get());
+        }
+
+        render_summary(out, "latency_seconds", "Server timestamp to client receive latency", &self.latency);
+        render_summary(out, "rtt_seconds", "Round trip of echo probes, excluding server hold time", &self.rtt);
+
+        if let Some(xdp) = xdp {
+            let _ = writeln!(out, "# HELP afterburner_xdp_packets_total Packets seen by the XDP program, by outcome");
+            let _ = writeln!(out, "# TYPE afterburner_xdp_packets_total counter");
+            for (action, idx) in XDP_ACTIONS {
+                let _ = writeln!(out, "afterburner_xdp_packets_total{{action=\"{action}\"}} {}", xdp[idx as usize]);
+            }
+        }
+    }
+
+    /// Render as a single JSON object (no trailing newline).
+    pub fn render_json(&self, xdp: Option<&[u64; XDP_STATS_LEN as usize]>, out: &mut String) {
+        let ts_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let _ = write!(out, "{{\"ts_ms\":{ts_ms}");
+        for (name, _, c) in self.counters() {
+            let _ = write!(out, ",\"{name}\":{}", c.get());
+        }
+        for (name, _, g) in self.gauges() {
+            let _ = write!(out, ",\"{name}\":{}", json_f64(g.get()));
+        }
+
+        json_quantiles(out, "latency_us", &self.latency);
+        json_quantiles(out, "echo_rtt_us", &self.rtt);
+
+        if let Some(xdp) = xdp {
+            out.push_str(",\"xdp\":{");
+            for (i, (action, idx)) in XDP_ACTIONS.iter().enumerate() {
+                let _ = write!(out, "{}\"{action}\":{}", if i == 0 { "" } else { "," }, xdp[*idx as usize]);
+            }
+            out.push('}');
+        }
+        out.push('}');
+    }
+}
+
+const XDP_ACTIONS: [(&str, u32); 4] = [
+    ("redirect", XDP_STAT_REDIRECT),
+    ("redirect_failed", XDP_STAT_REDIRECT_FAILED),
+    ("pass", XDP_STAT_PASS),
+    ("aborted", XDP_STAT_ABORTED),
+];
+
+fn render_summary(out: &mut String, name: &str, help: &str, q: &Quantiles) {
+    let _ = writeln!(out, "# HELP afterburner_{name} {help}\n# TYPE afterburner_{name} summary");
+    for (value, (_, quantile)) in q.values_ns.iter().zip(QUANTILES) {
+        let _ = writeln!(out, "afterburner_{name}{{quantile=\"{quantile}\"}} {}", value.get() as f64 / 1e9);
+    }
+    let _ = writeln!(out, "afterburner_{name}_sum {}", q.sum_ns.get() as f64 / 1e9);
+    let _ = writeln!(out, "afterburner_{name}_count {}", q.count.get());
+}
+
+fn json_quantiles(out: &mut String, name: &str, q: &Quantiles) {
+    let _ = write!(out, ",\"{name}\":{{\"count\":{}", q.count.get());
+    for (value, (label, _)) in q.values_ns.iter().zip(QUANTILES) {
+        let _ = write!(out, ",\"{label}\":{}", value.get() as f64 / 1000.0);
+    }
+    out.push('}');
+}
+
+/// JSON has no NaN/Inf.
+fn json_f64(v: f64) -> f64 {
+    if v.is_finite() { v } else { 0.0 }
+}
+
+/// The eBPF program's per-CPU `STATS` counters.
+pub struct XdpCounters(PerCpuArray<MapData, u64>);
+
+impl XdpCounters {
+    pub fn new(map: PerCpuArray<MapData, u64>) -> Self {
+        XdpCounters(map)
+    }
+
+    /// Sum each counter across CPUs.
+    fn read(&self) -> [u64; XDP_STATS_LEN as usize] {
+        std::array::from_fn(|i| {
+            self.0.get(&(i as u32), 0).map(|per_cpu| per_cpu.iter().sum()).unwrap_or(0)
+        })
+    }
+}
+
+/// Where to export metrics to.
+pub struct ExportConfig {
+    /// Serve Prometheus text on `http://<addr>/metrics`
+    pub prometheus: Option<SocketAddr>,
+    /// Append one JSON object per `interval` to this file
+    pub json_path: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+/// The running exporter thread. Stopping it (or dropping it) writes a last JSON line and
+/// releases the listening socket and the file.
+pub struct Exporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Stop the thread and wait for it.
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+/// Start the exporter thread. Returns `None` if nothing is configured.
+pub fn spawn_exporter(metrics: Arc<Metrics>, xdp: Option<XdpCounters>, cfg: ExportConfig) -> Result<Option<Exporter>, io::Error> {
+    if cfg.prometheus.is_none() && cfg.json_path.is_none() {
+        return Ok(None);
+    }
+
+    let listener = match cfg.prometheus {
+        Some(addr) => {
+            let l = TcpListener::bind(addr)?;
+            l.set_nonblocking(true)?;
+            info!(target: "metrics", "Prometheus endpoint on http://{}/metrics", addr);
+            Some(l)
+        }
+        None => None,
+    };
+    let mut json = match &cfg.json_path {
+        Some(path) => {
+            let f = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            info!(target: "metrics", "Writing JSON lines to {} every {:?}", path.display(), cfg.interval);
+            Some(io::BufWriter::new(f))
+        }
+        None => None,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    let handle = std::thread::Builder::new().name("metrics".into()).spawn(move || {
+        let mut buf = String::with_capacity(16 * 1024);
+        let mut last_json = Instant::now();
+        let mut scrapes: Vec<Scrape> = Vec::new();
+        loop {
+            let stopping = stopped.load(Ordering::Relaxed);
+            if let Some(l) = &listener {
+                while let Ok((stream, _)) = l.accept() {
+                    if scrapes.len() < MAX_SCRAPES {
+                        scrapes.extend(Scrape::new(stream).ok());
+                    }
+                }
+                scrapes.retain_mut(|s| s.started.elapsed() < SCRAPE_TIMEOUT && s.read().is_ok());
+                // Rendered once per tick, however many scrapes are waiting for it
+                if scrapes.iter().any(Scrape::wants_body) {
+                    buf.clear();
+                    metrics.render_prometheus(xdp.as_ref().map(XdpCounters::read).as_ref(), &mut buf);
+                }
+                scrapes.retain_mut(|s| matches!(s.write(&buf), Ok(false)));
+            }
+            if let Some(w) = &mut json {
+                // One last line on the way out, so the final interval isn't lost
+                if stopping || last_json.elapsed() >= cfg.interval {
+                    last_json = Instant::now();
+                    buf.clear();
+                    metrics.render_json(xdp.as_ref().map(XdpCounters::read).as_ref(), &mut buf);
+                    buf.push('\n');
+                    if w.write_all(buf.as_bytes()).and_then(|_| w.flush()).is_err() {
+                        error!(target: "metrics", "JSON write failed, disabling");
+                        json = None;
+                    }
+                }
+            }
+            if stopping {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    })?;
+    Ok(Some(Exporter { stop, handle: Some(handle) }))
+}
+
+/// Scrapes served at once; further connections are closed unanswered
+const MAX_SCRAPES: usize = 16;
+/// A scrape that hasn't sent its request and taken the response by then is dropped
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request header accepted
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// One HTTP request on a non-blocking socket, advanced a little on every exporter tick so
+/// a slow scraper never holds up the JSON lines. `/metrics` (or `/`) gets the rendered
+/// body, anything else a 404.
+struct Scrape {
+    stream: TcpStream,
+    started: Instant,
+    /// The request as read so far, until its header is complete
+    req: Vec<u8>,
+    /// The response and how much of it is written, once the request is in
+    resp: Option<(Vec<u8>, usize)>,
+}
+
+impl Scrape {
+    fn new(stream: TcpStream) -> Result<Scrape, io::Error> {
+        stream.set_nonblocking(true)?;
+        Ok(Scrape { stream, started: Instant::now(), req: Vec::new(), resp: None })
+    }
+
+    fn request_complete(&self) -> bool {
+        self.req.windows(4).any(|w| w == b"\r\n\r\n")
+    }
+
+    /// The request is in and the response not built yet.
+    fn wants_body(&self) -> bool {
+        self.resp.is_none() && self.request_complete()
+    }
+
+    /// Read whatever has arrived of the request.
+    fn read(&mut self) -> Result<(), io::Error> {
+        let mut chunk = [0u8; 1024];
+        while self.resp.is_none() && !self.request_complete() {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.req.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            if self.req.len() > MAX_REQUEST {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request header too long"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write as much of the response as the socket takes, building it from `body` once the
+    /// request is in; `true` when all of it is written.
+    fn write(&mut self, body: &str) -> Result<bool, io::Error> {
+        if self.resp.is_none() {
+            if !self.request_complete() {
+                return Ok(false);
+            }
+            let resp = if self.req.starts_with(b"GET /metrics") || self.req.starts_with(b"GET / ") {
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(), body).into_bytes()
+            } else {
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+            };
+            self.resp = Some((resp, 0));
+        }
+        let (resp, written) = self.resp.as_mut().expect("built above");
+        while *written < resp.len() {
+            match self.stream.write(&resp[*written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => *written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Metrics {
+        let metrics = Metrics::default();
+        metrics.tx_packets.add(42);
+        metrics.xsk_rx_dropped.set(7);
+        metrics.rtt_us.set(125.5);
+        metrics.delivery_rate.set(f64::NAN);
+        let mut hist = Histogram::new();
+        for v in [1_000, 2_000, 3_000, 4_000] {
+            hist.record(v);
+        }
+        metrics.latency.publish(&hist);
+        metrics
+    }
+
+    const XDP: [u64; XDP_STATS_LEN as usize] = [10, 1, 2, 0];
+
+    #[test]
+    fn prometheus_text() {
+        let mut out = String::new();
+        sample().render_prometheus(Some(&XDP), &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert!(lines.contains(&"# HELP afterburner_tx_packets_total Packets submitted to the AF_XDP TX ring"));
+        assert!(lines.contains(&"# TYPE afterburner_tx_packets_total counter"));
+        assert!(lines.contains(&"afterburner_tx_packets_total 42"));
+        assert!(lines.contains(&"afterburner_xsk_rx_dropped_total 7"));
+        assert!(lines.contains(&"# TYPE afterburner_rtt_us gauge"));
+        assert!(lines.contains(&"afterburner_rtt_us 125.5"));
+
+        assert!(lines.contains(&"# TYPE afterburner_latency_seconds summary"));
+        let quantiles: Vec<&str> = lines.iter().copied()
+            .filter(|l| l.starts_with("afterburner_latency_seconds{quantile="))
+            .collect();
+        assert_eq!(quantiles.len(), QUANTILES.len());
+        assert!(quantiles[0].starts_with("afterburner_latency_seconds{quantile=\"0.5\"} 0.000002"));
+        assert!(lines.contains(&"afterburner_latency_seconds_sum 0.00001"));
+        assert!(lines.contains(&"afterburner_latency_seconds_count 4"));
+        assert!(lines.contains(&"afterburner_rtt_seconds_count 0"));
+
+        assert!(lines.contains(&"# TYPE afterburner_xdp_packets_total counter"));
+        assert!(lines.contains(&"afterburner_xdp_packets_total{action=\"redirect\"} 10"));
+        assert!(lines.contains(&"afterburner_xdp_packets_total{action=\"aborted\"} 0"));
+
+        // Every sample has its HELP and TYPE, and counters end in _total
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert_eq!(lines[i - 1].split(' ').nth(2), Some(name));
+                assert_eq!(kind == "counter", name.ends_with("_total"), "{line}");
+                assert!(lines[i + 1].starts_with(name), "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn prometheus_without_xdp() {
+        let mut out = String::new();
+        sample().render_prometheus(None, &mut out);
+        assert!(!out.contains("afterburner_xdp_packets_total"));
+    }
+
+    #[test]
+    fn json_line() {
+        let mut out = String::new();
+        sample().render_json(Some(&XDP), &mut out);
+        assert!(!out.contains('\n'));
+
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        let obj = json.as_object().unwrap();
+        assert!(obj["ts_ms"].as_u64().unwrap() > 0);
+        assert_eq!(obj["tx_packets"], 42);
+        assert_eq!(obj["xsk_rx_dropped"], 7);
+        assert_eq!(obj["rtt_us"], 125.5);
+        assert_eq!(obj["delivery_rate"], 0.0);
+        assert_eq!(obj["latency_us"]["count"], 4);
+        let p50 = obj["latency_us"]["p50"].as_f64().unwrap();
+        assert!((p50 - 2.0).abs() < 2.0 * 0.016, "{p50}");
+        assert_eq!(obj["echo_rtt_us"]["count"], 0);
+        for (label, _) in QUANTILES {
+            assert!(obj["latency_us"][label].is_number(), "{label}");
+            assert!(obj["echo_rtt_us"][label].is_number(), "{label}");
+        }
+        assert_eq!(obj["xdp"], serde_json::json!({ "redirect": 10, "redirect_failed": 1, "pass": 2, "aborted": 0 }));
+
+        let metrics = Metrics::default();
+        let expected = 1 + metrics.counters().len() + metrics.gauges().len() + 2 + 1;
+        assert_eq!(obj.len(), expected);
+    }
+}
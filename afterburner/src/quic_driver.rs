@@ -0,0 +1,490 @@
+//! The client side of the QUIC connection: quiche plus afterburner's own streams (the
+//! server's timestamps, echo probes, the control Hello), latency accounting and callbacks
+//! for everything else the server sends.
+//!
+//! The driver never touches a socket; packets come in through `process_input` and go out
+//! through `write_transmit`, which the engine connects to the AF_XDP rings.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use afterburner_common::codec::{
+    self, CodecError, Frame, FrameReader, CONTROL_STREAM, ECHO_STREAM, ERR_FRAMING,
+    ERR_NO_COMMON_VERSION, MAX_FRAME_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, TIMESTAMP_STREAM,
+};
+use log::{error, info, warn};
+use crate::config::QuicConfig;
+use crate::echo::{EchoTracker, LatencyMode};
+use crate::error::Error;
+use crate::handlers::{DatagramHandler, Handlers, StreamHandler};
+use crate::histogram::Histogram;
+use crate::metrics::Metrics;
+use crate::track::{OutcomeHandler, TxTracker};
+
+/// Largest forward jump in the timestamp sequence counted as loss.
+const MAX_SEQ_GAP: u64 = 1_000_000;
+
+/// One client connection and its latency bookkeeping.
+pub struct QuicDriver {
+    /// The quiche connection, for anything the driver doesn't wrap
+    pub conn: Pin<Box<quiche::Connection>>,
+    stream_buf: [u8; 65535], 
+    closed_seen: bool,
+    established_seen: bool,
+    close_timer: Option<std::time::Instant>,
+    ts_reader: FrameReader,
+    echo_reader: FrameReader,
+    /// Protocol version from the server's Hello
+    version: Option<u8>,
+    /// A stream stopped parsing; the connection is being closed
+    framing_failed: bool,
+    /// Since the last stats line
+    interval_hist: Histogram,
+    /// The whole run; published to the metrics registry
+    total_hist: Histogram,
+    last_seq: Option<u64>,
+    lost_packets: u64,
+    last_stats_time: std::time::Instant,
+    total_rx_msgs: u64,
+    mode: LatencyMode,
+    echo: EchoTracker,
+    tracker: TxTracker,
+    handlers: Handlers,
+    metrics: Arc<Metrics>,
+}
+
+impl QuicDriver {
+    /// Start the handshake with `peer` (no packet is produced until `write_transmit`).
+    pub fn new(
+        scid: &[u8],
+        local: SocketAddr,
+        peer: SocketAddr,
+        cfg: &QuicConfig,
+        mode: LatencyMode,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let quic = |step: &'static str| move |source: quiche::Error| Error::Quic { step, source };
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(quic("config"))?;
+        
+        config.verify_peer(false);
+        config.set_application_protos(&[cfg.alpn.as_bytes()]).map_err(quic("ALPN"))?;
+        config.set_max_ack_delay(cfg.max_ack_delay_ms);
+        config.set_ack_delay_exponent(cfg.ack_delay_exponent);
+        config.set_max_idle_timeout(cfg.max_idle_timeout_ms);
+        config.set_disable_active_migration(true);
+        config.enable_early_data();
+        config.set_initial_max_data(cfg.initial_max_data);
+        config.set_initial_max_stream_data_bidi_local(cfg.initial_max_stream_data);
+        config.set_initial_max_stream_data_bidi_remote(cfg.initial_max_stream_data);
+        config.set_initial_max_stream_data_uni(cfg.initial_max_stream_data);
+        config.set_initial_max_streams_bidi(cfg.initial_max_streams_bidi);
+        config.set_initial_max_streams_uni(cfg.initial_max_streams_uni);
+        config.enable_dgram(true, cfg.dgram_queue_len, cfg.dgram_queue_len);
+        // No-op until set_keylog() gives the connection somewhere to write
+        config.log_keys();
+
+        let scid_obj = quiche::ConnectionId::from_ref(scid);
+        let conn = quiche::connect(None, &scid_obj, local, peer, &mut config).map_err(quic("connect"))?;
+
+        Ok(QuicDriver {
+            conn: Box::pin(conn),
+            stream_buf: [0; 65535],
+            closed_seen: false,
+            established_seen: false,
+            close_timer: None,
+            ts_reader: FrameReader::new(),
+            echo_reader: FrameReader::new(),
+            version: None,
+            framing_failed: false,
+            interval_hist: Histogram::new(),
+            total_hist: Histogram::new(),
+            last_seq: None,
+            lost_packets: 0,
+            last_stats_time: std::time::Instant::now(),
+            total_rx_msgs: 0,
+            mode,
+            echo: EchoTracker::new(),
+            tracker: TxTracker::new(),
+            handlers: Handlers::default(),
+            metrics,
+        })
+    }
+
+    /// Feed one received UDP payload to the connection; sends our Hello once established.
+    pub fn process_input(&mut self, data: &mut [u8], local: SocketAddr, peer: SocketAddr) {
+        let recv_info = quiche::RecvInfo { from: peer, to: local };
+        self.tracker.on_rx();
+        if self.conn.recv(data, recv_info).is_ok()
+            && self.conn.is_established()
+            && !self.established_seen
+        {
+            info!(target: "quic", "Connection established");
+            self.established_seen = true;
+            self.send_frame(CONTROL_STREAM, Frame::Hello { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION }, true);
+        }
+    }
+
+    /// Write the next packet to send into `frame`; `None` when there is nothing to send.
+    pub fn write_transmit(&mut self, frame: &mut [u8]) -> Option<usize> {
+        match self.conn.send(frame) {
+            Ok((written, _)) => Some(written),
+            Err(_) => None, 
+        }
+    }
+
+    /// Read the timestamp, echo and application streams and datagrams, recording latency
+    /// and dispatching to the registered handlers, and report tracked transactions that
+    /// were acknowledged.
+    pub fn drain_streams(&mut self) {
+        if self.conn.is_established() && !self.framing_failed {
+            // Server-initiated uni stream: the server's Hello, then timestamps
+            loop {
+                match Self::next_frame(&mut self.conn, &mut self.ts_reader, TIMESTAMP_STREAM) {
+                    Ok(Some(frame)) => self.on_timestamp_frame(frame),
+                    Ok(None) => break,
+                    Err(e) => if !self.on_codec_error(TIMESTAMP_STREAM, e) { break },
+                }
+            }
+            
+            // Echoed probes (client-initiated bidi stream)
+            loop {
+                match Self::next_frame(&mut self.conn, &mut self.echo_reader, ECHO_STREAM) {
+                    Ok(Some(Frame::Echo { probe, server_rx_ns, server_tx_ns })) => {
+                        let rtt = self.echo.on_echo(&probe, server_rx_ns, server_tx_ns);
+                        self.record_latency(rtt);
+                    }
+                    Ok(Some(frame)) => self.protocol_error(ECHO_STREAM, &frame),
+                    Ok(None) => break,
+                    Err(e) => if !self.on_codec_error(ECHO_STREAM, e) { break },
+                }
+            }
+            
+            if self.last_stats_time.elapsed() >= std::time::Duration::from_millis(500) && self.interval_hist.count() > 0 {
+                self.print_interval_stats();
+                self.interval_hist.reset();
+                self.last_stats_time = std::time::Instant::now();
+            }
+            
+            // Everything else goes to the registered handlers (or is drained and dropped)
+            for stream_id in self.conn.readable() {
+                if stream_id == TIMESTAMP_STREAM || stream_id == ECHO_STREAM { continue; }
+                let mut handler = self.handlers.for_stream(stream_id);
+                while let Ok((read_len, fin)) = self.conn.stream_recv(stream_id, &mut self.stream_buf) {
+                    if let Some(h) = handler.as_mut() {
+                        h(stream_id, &self.stream_buf[..read_len], fin);
+                    }
+                    if read_len == 0 || fin { break; }
+                }
+            }
+            while let Ok(len) = self.conn.dgram_recv(&mut self.stream_buf) {
+                if let Some(h) = self.handlers.datagram.as_mut() {
+                    h(&self.stream_buf[..len]);
+                }
+            }
+        }
+        
+        if self.conn.is_closed() && !self.closed_seen {
+            self.closed_seen = true;
+            if let Some(err) = self.conn.peer_error() {
+                info!(target: "close", "Peer: is_app={} code={} reason={:?}", 
+                    err.is_app, err.error_code, String::from_utf8_lossy(&err.reason));
+            } else if let Some(err) = self.conn.local_error() {
+                info!(target: "close", "Local: is_app={} code={} reason={:?}", 
+                    err.is_app, err.error_code, String::from_utf8_lossy(&err.reason));
+            } else if self.conn.is_timed_out() {
+                info!(target: "close", "Timed out");
+            } else {
+                info!(target: "close", "Unknown reason");
+            }
+            self.close_timer = Some(std::time::Instant::now());
+        }
+        self.tracker.poll(&mut self.conn);
+    }
+
+    /// Copy quiche's connection and active-path stats and the latency quantiles into the
+    /// metrics registry.
+    pub fn publish_stats(&self) {
+        let m = &self.metrics;
+        let s = self.conn.stats();
+        m.quic_sent.set(s.sent as u64);
+        m.quic_recv.set(s.recv as u64);
+        m.quic_lost.set(s.lost as u64);
+        m.quic_retrans.set(s.retrans as u64);
+        m.quic_sent_bytes.set(s.sent_bytes);
+        m.quic_recv_bytes.set(s.recv_bytes);
+
+        if let Some(p) = self.conn.path_stats().find(|p| p.active) {
+            m.rtt_us.set(p.rtt.as_secs_f64() * 1e6);
+            m.min_rtt_us.set(p.min_rtt.map_or(0.0, |d| d.as_secs_f64() * 1e6));
+            m.rttvar_us.set(p.rttvar.as_secs_f64() * 1e6);
+            m.cwnd_bytes.set(p.cwnd as f64);
+            m.delivery_rate.set(p.delivery_rate as f64);
+        }
+
+        match self.mode {
+            LatencyMode::OneWay => m.latency.publish(&self.total_hist),
+            LatencyMode::Echo => m.rtt.publish(&self.total_hist),
+        }
+
+        let t = self.tracker.stats();
+        m.tx_acked.set(t.acked);
+        m.tx_stopped.set(t.stopped);
+        m.tx_failed.set(t.failed);
+        m.tx_unacked_closed.set(t.closed);
+        m.tx_in_flight.set(t.in_flight as f64);
+        let us = |ns: u64| ns as f64 / 1000.0;
+        m.time_to_ack_p50_us.set(us(self.tracker.time_to_ack.percentile(50.0)));
+        m.time_to_ack_p99_us.set(us(self.tracker.time_to_ack.percentile(99.0)));
+    }
+
+    /// Write TLS secrets in SSLKEYLOGFILE format, for decrypting captures in Wireshark.
+    /// Must be called before the handshake progresses.
+    pub fn set_keylog(&mut self, writer: Box<dyn std::io::Write + Send + Sync>) {
+        self.conn.set_keylog(writer);
+    }
+
+    /// Stream qlog events (JSON-SEQ) for this connection, e.g. for qvis.
+    #[cfg(feature = "qlog")]
+    pub fn set_qlog(&mut self, writer: Box<dyn std::io::Write + Send + Sync>) {
+        self.conn.set_qlog(writer, "afterburner".to_string(), "afterburner-app client".to_string());
+    }
+
+    /// Handle data on server-initiated unidirectional streams (other than the timestamp stream).
+    pub fn on_server_uni(&mut self, handler: StreamHandler) {
+        self.handlers.server_uni = Some(handler);
+    }
+
+    /// Handle responses on bidirectional streams (other than the echo stream).
+    pub fn on_bidi(&mut self, handler: StreamHandler) {
+        self.handlers.bidi = Some(handler);
+    }
+
+    /// Handle received QUIC DATAGRAM frames.
+    pub fn on_datagram(&mut self, handler: DatagramHandler) {
+        self.handlers.datagram = Some(handler);
+    }
+
+    /// Queue one whole transaction on client bidi stream `stream_id`. `Ok(false)` means the
+    /// stream can't take all of it yet; nothing is written then, so the server can always
+    /// split the stream back into transactions.
+    pub fn send_stream_tx(&mut self, stream_id: u64, tx: &[u8]) -> Result<bool, quiche::Error> {
+        if matches!(self.conn.stream_capacity(stream_id), Ok(cap) if cap < tx.len()) {
+            return Ok(false);
+        }
+        Ok(self.conn.stream_send(stream_id, tx, false)? == tx.len())
+    }
+
+    /// Queue one transaction as a QUIC DATAGRAM frame. Fails when the send queue is full or
+    /// the server didn't negotiate DATAGRAM support.
+    pub fn send_dgram_tx(&mut self, tx: &[u8]) -> Result<(), quiche::Error> {
+        self.conn.dgram_send(tx)
+    }
+
+    /// Queue one transaction on a unidirectional stream of its own and track it until the
+    /// peer acknowledges it (see [`track`](crate::track)); `tag` comes back in its
+    /// [`TxReport`](crate::track::TxReport). Returns the stream ID. `Err(Done)` or
+    /// `Err(StreamLimit)` mean there is no credit or stream for it yet.
+    pub fn send_tracked_tx(&mut self, tag: u64, tx: &[u8]) -> Result<u64, quiche::Error> {
+        self.tracker.send(&mut self.conn, tag, tx)
+    }
+
+    /// Handle the outcome of every transaction sent with [`send_tracked_tx`](Self::send_tracked_tx).
+    pub fn on_tx_outcome(&mut self, handler: OutcomeHandler) {
+        self.tracker.set_handler(handler);
+    }
+
+    /// Counts and time to ack of the transactions sent with [`send_tracked_tx`](Self::send_tracked_tx).
+    pub fn tx_tracker(&self) -> &TxTracker {
+        &self.tracker
+    }
+
+    /// Close the connection; tracked transactions not acknowledged yet are reported as
+    /// `ConnectionClosed`.
+    pub fn close(&mut self, reason: &[u8]) {
+        let _ = self.conn.close(true, 0, reason);
+        self.tracker.abandon();
+    }
+
+    /// Next complete frame on `stream_id`, reading more from quiche as needed.
+    fn next_frame(conn: &mut quiche::Connection, reader: &mut FrameReader, stream_id: u64) -> Result<Option<Frame>, CodecError> {
+        loop {
+            if let Some(frame) = reader.next_frame()? {
+                return Ok(Some(frame));
+            }
+            match conn.stream_recv(stream_id, reader.spare()) {
+                Ok((read_len, _fin)) if read_len > 0 => reader.commit(read_len),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    fn on_timestamp_frame(&mut self, frame: Frame) {
+        match (frame, self.version) {
+            (Frame::Hello { min, max }, None) => match codec::negotiate(min, max) {
+                Some(v) if min == max => {
+                    info!(target: "proto", "Protocol version {}", v);
+                    self.version = Some(v);
+                }
+                _ => {
+                    error!(target: "proto", "Server chose unsupported protocol version {}..={}", min, max);
+                    let _ = self.conn.close(true, ERR_NO_COMMON_VERSION, b"unsupported protocol version");
+                    self.framing_failed = true;
+                }
+            },
+            (Frame::Timestamp { server_ns, seq }, Some(_)) => self.on_timestamp(server_ns, seq),
+            (frame, _) => self.protocol_error(TIMESTAMP_STREAM, &frame),
+        }
+    }
+
+    fn on_timestamp(&mut self, server_ts: u64, seq: u64) {
+        let now_ns = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let latency_ns = now_ns.saturating_sub(server_ts);
+        
+        // The stream is reliable and ordered: a sequence number that doesn't move forward,
+        // or jumps implausibly far, is the server's bug rather than loss
+        if let Some(last) = self.last_seq {
+            if seq <= last || seq - last > MAX_SEQ_GAP {
+                self.metrics.codec_errors.inc();
+                error!(target: "proto", "Timestamp sequence went from {} to {}", last, seq);
+                let _ = self.conn.close(true, ERR_FRAMING, b"bad timestamp sequence");
+                self.framing_failed = true;
+                return;
+            }
+            self.lost_packets += seq - last - 1;
+        }
+        self.last_seq = Some(seq);
+        
+        self.total_rx_msgs += 1;
+        self.metrics.rx_timestamps.inc();
+        self.metrics.timestamps_lost.set(self.lost_packets);
+        if self.mode == LatencyMode::OneWay {
+            self.record_latency(latency_ns);
+        }
+    }
+
+    fn record_latency(&mut self, ns: u64) {
+        self.interval_hist.record(ns);
+        self.total_hist.record(ns);
+    }
+
+    /// A well-formed frame that doesn't belong where it arrived.
+    fn protocol_error(&mut self, stream_id: u64, frame: &Frame) {
+        self.metrics.codec_errors.inc();
+        warn!(target: "proto", "Unexpected frame on stream {}: {:?}", stream_id, frame);
+    }
+
+    /// Count a framing error; returns whether the stream can still be parsed.
+    fn on_codec_error(&mut self, stream_id: u64, err: CodecError) -> bool {
+        self.metrics.codec_errors.inc();
+        warn!(target: "proto", "Framing error on stream {}: {:?}", stream_id, err);
+        if let CodecError::UnknownType { .. } = err {
+            return true;
+        }
+        let _ = self.conn.close(true, ERR_FRAMING, b"framing error");
+        self.framing_failed = true;
+        false
+    }
+
+    /// Encode and send a whole frame, never a partial one: the stream would lose its framing.
+    fn send_frame(&mut self, stream_id: u64, frame: Frame, fin: bool) -> bool {
+        if matches!(self.conn.stream_capacity(stream_id), Ok(cap) if cap < frame.encoded_len()) {
+            return false;
+        }
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let Ok(len) = frame.encode(&mut buf) else { return false };
+        self.conn.stream_send(stream_id, &buf[..len], fin).is_ok()
+    }
+
+    /// Send a probe stamped with the client's monotonic clock, unless the last one went out
+    /// less than `interval` ago; the server echoes it back on the same stream. No-op unless
+    /// running in echo mode. A probe that doesn't fit the stream waits for the next interval.
+    pub fn send_probe(&mut self, interval: Duration) {
+        if self.mode != LatencyMode::Echo {
+            return;
+        }
+        if let Some(probe) = self.echo.next_probe(interval.as_nanos() as u64) {
+            self.send_frame(ECHO_STREAM, Frame::Probe(probe), false);
+        }
+    }
+
+    fn print_interval_stats(&self) {
+        self.print_tracking("");
+        match self.mode {
+            LatencyMode::OneWay => info!(target: "stats", "Lat(us) {} | RX: {} | Lost: {}", 
+                Self::format_percentiles(&self.interval_hist),
+                self.total_rx_msgs,
+                self.lost_packets
+            ),
+            LatencyMode::Echo => {
+                let quic_rtt_us = self.conn.path_stats().find(|p| p.active)
+                    .map_or(0.0, |p| p.rtt.as_secs_f64() * 1e6);
+                info!(target: "stats", "RTT(us) {} | OWD(us) fwd p50={:.1} rev p50={:.1} | QUIC RTT(us)={:.1} | RX: {} | Lost: {}", 
+                    Self::format_percentiles(&self.interval_hist),
+                    self.echo.forward.percentile(50.0) as f64 / 1000.0,
+                    self.echo.reverse.percentile(50.0) as f64 / 1000.0,
+                    quic_rtt_us,
+                    self.total_rx_msgs,
+                    self.lost_packets
+                );
+            }
+        }
+    }
+
+    /// Time to ack and outcomes of tracked transactions, if any were sent.
+    fn print_tracking(&self, prefix: &str) {
+        let t = self.tracker.stats();
+        if t.sent == 0 {
+            return;
+        }
+        let hist = &self.tracker.time_to_ack;
+        let us = |ns: u64| ns as f64 / 1000.0;
+        info!(target: "stats", "{}Ack(us) p50={:.1} p99={:.1} Max={:.1} | Sent: {} | Acked: {} | In flight: {} | Stopped: {} | Failed: {} | Unacked at close: {}",
+            prefix, us(hist.percentile(50.0)), us(hist.percentile(99.0)), us(hist.max()),
+            t.sent, t.acked, t.in_flight, t.stopped, t.failed, t.closed);
+    }
+
+    fn format_percentiles(hist: &Histogram) -> String {
+        let us = |ns: u64| ns as f64 / 1000.0;
+        format!("p50={:.1} p90={:.1} p99={:.1} p99.9={:.1} p99.99={:.1} Min={:.1} Max={:.1}",
+            us(hist.percentile(50.0)), us(hist.percentile(90.0)), us(hist.percentile(99.0)),
+            us(hist.percentile(99.9)), us(hist.percentile(99.99)),
+            us(hist.min()), us(hist.max()))
+    }
+
+    /// Latency over the whole run.
+    pub fn latency_histogram(&self) -> &Histogram {
+        &self.total_hist
+    }
+
+    /// Print cumulative latency percentiles over the whole run.
+    pub fn print_latency_summary(&mut self) {
+        let echo_offset = self.echo.offset_ns().filter(|_| self.mode == LatencyMode::Echo && self.echo.forward.count() > 0);
+        if let Some(offset_ns) = echo_offset {
+            let us = |ns: u64| ns as f64 / 1000.0;
+            info!(target: "stats", "Total OWD(us) fwd p50={:.1} p99={:.1} rev p50={:.1} p99={:.1} | Clock offset(us)={:.1}",
+                us(self.echo.forward.percentile(50.0)), us(self.echo.forward.percentile(99.0)),
+                us(self.echo.reverse.percentile(50.0)), us(self.echo.reverse.percentile(99.0)),
+                offset_ns as f64 / 1000.0);
+        }
+        self.print_tracking("Total ");
+        let (rx, lost) = (self.total_rx_msgs, self.lost_packets);
+        let label = match self.mode {
+            LatencyMode::OneWay => "Lat",
+            LatencyMode::Echo => "RTT",
+        };
+        let hist = self.latency_histogram();
+        if hist.count() > 0 {
+            info!(target: "stats", "Total {}(us) {} | RX: {} | Lost: {}", label, Self::format_percentiles(hist), rx, lost);
+        }
+    }
+
+    /// Run quiche's timers (loss detection, idle timeout); call once per loop iteration.
+    pub fn on_timeout(&mut self) {
+        self.conn.on_timeout();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,107 @@
+//! Per-packet RX latency breakdown from the timestamps the XDP program stamps in front of
+//! each frame (see `afterburner_common::RxMeta`):
+//!
+//! - wire -> XDP: NIC hardware timestamp to XDP program entry (only when the NIC provides one)
+//! - XDP -> userspace: XDP program entry to `rx_batch` picking the frame's batch off the RX ring
+//! - userspace: `rx_batch` to the frame's batch being handed back to the fill ring
+
+use std::time::{Duration, Instant};
+use afterburner_common::{RxMeta, RX_META_HW_TS};
+use log::{info, warn};
+use crate::histogram::Histogram;
+
+const PRINT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Same clock as `bpf_ktime_get_ns()`.
+#[inline(always)]
+pub fn monotonic_ns() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC)
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Histograms of the three RX latency components, printed every 500 ms.
+pub struct RxTiming {
+    wire_to_xdp: Histogram,
+    xdp_to_user: Histogram,
+    user: Histogram,
+    /// CLOCK_REALTIME - CLOCK_MONOTONIC; hardware stamps are assumed PHC-synced to realtime
+    realtime_offset_ns: i64,
+    frames: u64,
+    frames_with_meta: u64,
+    warned_no_meta: bool,
+    last_print: Instant,
+}
+
+impl Default for RxTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RxTiming {
+    pub fn new() -> Self {
+        RxTiming {
+            wire_to_xdp: Histogram::new(),
+            xdp_to_user: Histogram::new(),
+            user: Histogram::new(),
+            realtime_offset_ns: realtime_offset_ns(),
+            frames: 0,
+            frames_with_meta: 0,
+            warned_no_meta: false,
+            last_print: Instant::now(),
+        }
+    }
+
+    /// Record one frame whose batch `rx_batch` returned at `polled_ns` and has just been released.
+    #[inline(always)]
+    pub fn record(&mut self, meta: Option<&RxMeta>, polled_ns: u64) {
+        self.user.record(monotonic_ns().saturating_sub(polled_ns));
+        self.frames += 1;
+        let Some(meta) = meta else { return };
+        self.frames_with_meta += 1;
+        self.xdp_to_user.record(polled_ns.saturating_sub(meta.xdp_ns));
+        if meta.flags & RX_META_HW_TS != 0 {
+            let xdp_realtime = meta.xdp_ns as i64 + self.realtime_offset_ns;
+            self.wire_to_xdp.record((xdp_realtime - meta.hw_ns as i64).max(0) as u64);
+        }
+    }
+
+    /// Print and reset the interval's breakdown every 500 ms.
+    pub fn maybe_print(&mut self) {
+        if self.last_print.elapsed() < PRINT_INTERVAL || self.frames == 0 {
+            return;
+        }
+        if self.frames_with_meta == 0 && !self.warned_no_meta {
+            warn!(target: "rxts", "No XDP metadata on received frames (driver lacks metadata support); \
+                      reporting userspace time only");
+            self.warned_no_meta = true;
+        }
+        info!(target: "rxts", "Lat(us) wire->xdp {} | xdp->user {} | user {} | Frames: {}",
+            summary(&self.wire_to_xdp), summary(&self.xdp_to_user), summary(&self.user), self.frames);
+
+        self.wire_to_xdp.reset();
+        self.xdp_to_user.reset();
+        self.user.reset();
+        self.frames = 0;
+        self.frames_with_meta = 0;
+        // Follow NTP/PTP steps of the realtime clock
+        self.realtime_offset_ns = realtime_offset_ns();
+        self.last_print = Instant::now();
+    }
+}
+
+fn realtime_offset_ns() -> i64 {
+    clock_ns(libc::CLOCK_REALTIME) as i64 - monotonic_ns() as i64
+}
+
+fn summary(hist: &Histogram) -> String {
+    if hist.count() == 0 {
+        return "n/a".to_string();
+    }
+    format!("p50={:.1} p99={:.1}", hist.percentile(50.0) as f64 / 1000.0, hist.percentile(99.0) as f64 / 1000.0)
+}
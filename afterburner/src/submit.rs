@@ -0,0 +1,444 @@
+//! Submitting transactions to an engine running on its own thread.
+//!
+//! [`EngineBuilder::spawn`](crate::EngineBuilder::spawn) hands out a [`Submitter`], which
+//! any number of threads can clone and [`submit`](Submitter::submit) through, and a
+//! [`Completions`] receiver. Each transaction goes out with
+//! [`QuicDriver::send_tracked_tx`], on a unidirectional stream of its own (see
+//! [`track`](crate::track)). For every transaction the receiver gets [`TxStatus::Written`]
+//! once it is on its stream, then [`TxStatus::Acked`] when the peer has acknowledged all of
+//! it, or [`TxStatus::Dropped`] with the reason it never will be.
+//!
+//! Submissions go through a bounded lock-free queue (crossbeam's `ArrayQueue`), so a
+//! submitter never takes a lock the pinned engine thread could be waiting on.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_queue::ArrayQueue;
+
+use crate::quic_driver::QuicDriver;
+use crate::track::{OutcomeHandler, TxOutcome};
+
+/// Identifies a submitted transaction in its [`Completion`]s; unique per engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxId(pub u64);
+
+impl fmt::Display for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tx#{}", self.0)
+    }
+}
+
+/// A transaction to send, with optional constraints on where and until when.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    pub payload: Vec<u8>,
+    /// Only send if the engine is connected to this address
+    pub leader: Option<SocketAddr>,
+    /// Drop instead of sending if it can't be written to a stream by then
+    pub deadline: Option<Instant>,
+}
+
+impl Transaction {
+    pub fn new(payload: impl Into<Vec<u8>>) -> Self {
+        Transaction { payload: payload.into(), leader: None, deadline: None }
+    }
+
+    pub fn leader(mut self, leader: SocketAddr) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+/// Why a submission was refused; the transaction is handed back.
+#[derive(Debug)]
+pub enum SubmitError {
+    /// The submission queue is full: the engine is behind, or still handshaking
+    Full(Transaction),
+    /// The engine thread has stopped
+    Stopped(Transaction),
+}
+
+impl SubmitError {
+    pub fn into_transaction(self) -> Transaction {
+        match self {
+            SubmitError::Full(tx) | SubmitError::Stopped(tx) => tx,
+        }
+    }
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::Full(_) => f.write_str("submission queue full"),
+            SubmitError::Stopped(_) => f.write_str("engine stopped"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// The queue between the submitters and the engine thread.
+pub(crate) struct Submissions {
+    slots: ArrayQueue<(TxId, Transaction)>,
+    next_id: AtomicU64,
+    /// Set once the engine thread stops taking submissions
+    closed: AtomicBool,
+    /// Submitters between checking `closed` and pushing, which shutdown waits out
+    in_flight: AtomicUsize,
+}
+
+/// Queues transactions for the engine thread. Cheap to clone; never blocks.
+#[derive(Clone)]
+pub struct Submitter {
+    queue: Arc<Submissions>,
+}
+
+impl Submitter {
+    /// Queue `tx`; its completions will carry the returned ID.
+    pub fn submit(&self, tx: Transaction) -> Result<TxId, SubmitError> {
+        // SeqCst pairs with `Dispatcher::shutdown`: either this sees `closed`, or shutdown
+        // sees us in flight and waits for the push before its last drain
+        self.queue.in_flight.fetch_add(1, Ordering::SeqCst);
+        let result = if self.queue.closed.load(Ordering::SeqCst) {
+            Err(SubmitError::Stopped(tx))
+        } else {
+            let id = TxId(self.queue.next_id.fetch_add(1, Ordering::Relaxed));
+            match self.queue.slots.push((id, tx)) {
+                Ok(()) => Ok(id),
+                Err((_, tx)) => Err(SubmitError::Full(tx)),
+            }
+        };
+        self.queue.in_flight.fetch_sub(1, Ordering::Release);
+        result
+    }
+}
+
+/// What happened to a transaction, and when the engine noticed.
+#[derive(Clone, Copy, Debug)]
+pub struct Completion {
+    pub id: TxId,
+    pub status: TxStatus,
+    pub at: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Accepted on `stream_id`; what didn't fit the flow control window follows as it opens
+    Written { stream_id: u64 },
+    /// The peer acknowledged all of it, `time_to_ack` after it was all written
+    Acked { stream_id: u64, time_to_ack: Duration },
+    /// It will not be (or may not have been) delivered
+    Dropped(DropReason),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The deadline passed before it could be written
+    Expired,
+    /// The engine is connected to a different leader
+    WrongLeader,
+    /// The connection closed before it was acknowledged
+    ConnectionClosed,
+    /// The peer asked us to stop sending on its stream (STOP_SENDING with this code)
+    Stopped(u64),
+    /// quiche refused (part of) the stream write
+    Rejected(quiche::Error),
+    /// The engine shut down before it was written
+    Shutdown,
+}
+
+/// Completions, in the order the engine saw them. Unbounded, so a receiver that is never
+/// drained grows; drop it if you don't want them.
+pub type Completions = Receiver<Completion>;
+
+/// The submission queue and a receiver for what became of each transaction.
+pub(crate) fn channel(queue_len: usize) -> (Submitter, Arc<Submissions>, Sender<Completion>, Completions) {
+    let submissions = Arc::new(Submissions {
+        slots: ArrayQueue::new(queue_len),
+        next_id: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        in_flight: AtomicUsize::new(0),
+    });
+    let (done, completions) = mpsc::channel();
+    (Submitter { queue: Arc::clone(&submissions) }, submissions, done, completions)
+}
+
+/// Forwards the driver's transaction outcomes into the completion channel.
+pub(crate) fn outcome_handler(done: Sender<Completion>) -> OutcomeHandler {
+    Box::new(move |report| {
+        let status = match report.outcome {
+            TxOutcome::Acked => TxStatus::Acked { stream_id: report.stream_id, time_to_ack: report.elapsed },
+            TxOutcome::Stopped(code) => TxStatus::Dropped(DropReason::Stopped(code)),
+            TxOutcome::Failed(e) => TxStatus::Dropped(DropReason::Rejected(e)),
+            TxOutcome::ConnectionClosed => TxStatus::Dropped(DropReason::ConnectionClosed),
+        };
+        let _ = done.send(Completion { id: TxId(report.tag), status, at: Instant::now() });
+    })
+}
+
+/// The engine-thread side: hands submitted transactions to the driver in order.
+pub(crate) struct Dispatcher {
+    submissions: Arc<Submissions>,
+    done: Sender<Completion>,
+    peer: SocketAddr,
+    queue_len: usize,
+    /// Waiting for the handshake, a stream or flow control credit, in submission order
+    pending: VecDeque<(TxId, Transaction)>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(submissions: Arc<Submissions>, done: Sender<Completion>, peer: SocketAddr, queue_len: usize) -> Self {
+        Dispatcher { submissions, done, peer, queue_len, pending: VecDeque::new() }
+    }
+
+    /// Write as many queued transactions as the connection takes. Runs as the engine's
+    /// `feed`; acks and later drops come from the driver's tracker.
+    pub(crate) fn poll(&mut self, driver: &mut QuicDriver) {
+        let now = Instant::now();
+        self.take_submissions();
+
+        if driver.conn.is_closed() {
+            self.drop_pending(DropReason::ConnectionClosed, now);
+            return;
+        }
+        if !driver.conn.is_established() {
+            // Nothing goes out yet, but queued transactions can still expire
+            self.drop_unsendable(now);
+            return;
+        }
+
+        while let Some((id, tx)) = self.pending.front() {
+            if let Some(reason) = unsendable(tx, self.peer, now) {
+                self.drop_front(reason, now);
+                continue;
+            }
+            match driver.send_tracked_tx(id.0, &tx.payload) {
+                Ok(stream_id) => {
+                    let id = *id;
+                    self.pending.pop_front();
+                    self.report(id, TxStatus::Written { stream_id }, now);
+                }
+                // No credit, or no stream until the peer raises MAX_STREAMS
+                Err(quiche::Error::Done | quiche::Error::StreamLimit) => break,
+                Err(e) => self.drop_front(DropReason::Rejected(e), now),
+            }
+        }
+    }
+
+    /// Stop taking from the queue while we can't keep up, so submitters see Full.
+    fn take_submissions(&mut self) {
+        while self.pending.len() < self.queue_len {
+            match self.submissions.slots.pop() {
+                Some(submission) => self.pending.push_back(submission),
+                None => break,
+            }
+        }
+    }
+
+    /// Refuse further submissions and report everything not written yet, queued or
+    /// pending, as [`DropReason::Shutdown`]. Every accepted submission gets a completion:
+    /// submitters already past the `closed` check are waited for.
+    pub(crate) fn shutdown(&mut self, now: Instant) {
+        self.submissions.closed.store(true, Ordering::SeqCst);
+        while self.submissions.in_flight.load(Ordering::Acquire) != 0 {
+            std::thread::yield_now();
+        }
+        while let Some(submission) = self.submissions.slots.pop() {
+            self.pending.push_back(submission);
+        }
+        self.drop_pending(DropReason::Shutdown, now);
+    }
+
+    /// Report everything not written yet as dropped.
+    pub(crate) fn drop_pending(&mut self, reason: DropReason, now: Instant) {
+        while !self.pending.is_empty() {
+            self.drop_front(reason, now);
+        }
+    }
+
+    /// Drop whatever in the queue has expired or is for another leader.
+    fn drop_unsendable(&mut self, now: Instant) {
+        let peer = self.peer;
+        let mut dropped = Vec::new();
+        self.pending.retain(|(id, tx)| match unsendable(tx, peer, now) {
+            Some(reason) => {
+                dropped.push((*id, reason));
+                false
+            }
+            None => true,
+        });
+        for (id, reason) in dropped {
+            self.report(id, TxStatus::Dropped(reason), now);
+        }
+    }
+
+    fn drop_front(&mut self, reason: DropReason, now: Instant) {
+        if let Some((id, _)) = self.pending.pop_front() {
+            self.report(id, TxStatus::Dropped(reason), now);
+        }
+    }
+
+    fn report(&self, id: TxId, status: TxStatus, at: Instant) {
+        // Nobody listening is fine
+        let _ = self.done.send(Completion { id, status, at });
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        // Also when the engine thread dies without a shutdown: submitters see Stopped, and
+        // whatever they got in is reported. A no-op after `shutdown`
+        self.shutdown(Instant::now());
+    }
+}
+
+/// Why `tx` must not be sent to `peer` at `now`, if it mustn't.
+fn unsendable(tx: &Transaction, peer: SocketAddr, now: Instant) -> Option<DropReason> {
+    if tx.leader.is_some_and(|leader| leader != peer) {
+        Some(DropReason::WrongLeader)
+    } else if tx.deadline.is_some_and(|deadline| deadline <= now) {
+        Some(DropReason::Expired)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.11:8004";
+
+    fn dispatcher(queue_len: usize) -> (Submitter, Dispatcher, Completions) {
+        let (submitter, submissions, done, completions) = channel(queue_len);
+        let dispatcher = Dispatcher::new(submissions, done, PEER.parse().unwrap(), queue_len);
+        (submitter, dispatcher, completions)
+    }
+
+    fn statuses(completions: &Completions) -> Vec<(u64, TxStatus)> {
+        completions.try_iter().map(|c| (c.id.0, c.status)).collect()
+    }
+
+    #[test]
+    fn leader_and_deadline_checks() {
+        let peer: SocketAddr = PEER.parse().unwrap();
+        let other: SocketAddr = "10.0.0.12:8004".parse().unwrap();
+        let now = Instant::now();
+        let later = now + Duration::from_millis(10);
+
+        assert_eq!(unsendable(&Transaction::new([1]), peer, now), None);
+        assert_eq!(unsendable(&Transaction::new([1]).leader(peer).deadline(later), peer, now), None);
+        assert_eq!(unsendable(&Transaction::new([1]).leader(other), peer, now), Some(DropReason::WrongLeader));
+        assert_eq!(unsendable(&Transaction::new([1]).deadline(now), peer, now), Some(DropReason::Expired));
+        assert_eq!(unsendable(&Transaction::new([1]).deadline(now), peer, later), Some(DropReason::Expired));
+        // A transaction for another leader is dropped for that, whatever its deadline
+        assert_eq!(
+            unsendable(&Transaction::new([1]).leader(other).deadline(now), peer, later),
+            Some(DropReason::WrongLeader)
+        );
+    }
+
+    #[test]
+    fn unsendable_transactions_drop_while_handshaking() {
+        let (submitter, mut dispatcher, completions) = dispatcher(8);
+        let now = Instant::now();
+        let other: SocketAddr = "10.0.0.12:8004".parse().unwrap();
+        submitter.submit(Transaction::new([0])).unwrap();
+        submitter.submit(Transaction::new([1]).leader(other)).unwrap();
+        submitter.submit(Transaction::new([2]).deadline(now)).unwrap();
+        submitter.submit(Transaction::new([3]).deadline(now + Duration::from_secs(60))).unwrap();
+
+        dispatcher.take_submissions();
+        dispatcher.drop_unsendable(now);
+        assert_eq!(
+            statuses(&completions),
+            [(1, TxStatus::Dropped(DropReason::WrongLeader)), (2, TxStatus::Dropped(DropReason::Expired))]
+        );
+        // The rest stay queued, in order
+        let left: Vec<_> = dispatcher.pending.iter().map(|(id, _)| id.0).collect();
+        assert_eq!(left, [0, 3]);
+    }
+
+    #[test]
+    fn full_queue_hands_the_transaction_back() {
+        let (submitter, mut dispatcher, _completions) = dispatcher(2);
+        submitter.submit(Transaction::new([0])).unwrap();
+        submitter.submit(Transaction::new([1])).unwrap();
+        let err = submitter.submit(Transaction::new([2])).unwrap_err();
+        assert!(matches!(&err, SubmitError::Full(_)));
+        assert_eq!(err.into_transaction().payload, [2]);
+
+        // Pending holds at most queue_len, so the queue stays full while the engine is behind
+        dispatcher.take_submissions();
+        submitter.submit(Transaction::new([3])).unwrap();
+        submitter.submit(Transaction::new([4])).unwrap();
+        dispatcher.take_submissions();
+        assert_eq!(dispatcher.pending.len(), 2);
+        assert!(matches!(submitter.submit(Transaction::new([5])), Err(SubmitError::Full(_))));
+    }
+
+    #[test]
+    fn shutdown_drops_queued_and_refuses_new() {
+        let (submitter, mut dispatcher, completions) = dispatcher(8);
+        submitter.submit(Transaction::new([0])).unwrap();
+        dispatcher.take_submissions();
+        submitter.submit(Transaction::new([1])).unwrap();
+
+        dispatcher.shutdown(Instant::now());
+        assert_eq!(
+            statuses(&completions),
+            [(0, TxStatus::Dropped(DropReason::Shutdown)), (1, TxStatus::Dropped(DropReason::Shutdown))]
+        );
+        assert!(matches!(submitter.submit(Transaction::new([2])), Err(SubmitError::Stopped(_))));
+    }
+
+    #[test]
+    fn dropped_dispatcher_stops_submissions() {
+        let (submitter, dispatcher, completions) = dispatcher(8);
+        submitter.submit(Transaction::new([0])).unwrap();
+        drop(dispatcher);
+        assert_eq!(statuses(&completions), [(0, TxStatus::Dropped(DropReason::Shutdown))]);
+        assert!(matches!(submitter.submit(Transaction::new([1])), Err(SubmitError::Stopped(_))));
+    }
+
+    #[test]
+    fn submissions_racing_shutdown_all_complete() {
+        for _ in 0..200 {
+            let (submitter, mut dispatcher, completions) = dispatcher(1024);
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let submitter = submitter.clone();
+                    std::thread::spawn(move || {
+                        let mut accepted = Vec::new();
+                        loop {
+                            match submitter.submit(Transaction::new([0])) {
+                                Ok(id) => accepted.push(id.0),
+                                Err(SubmitError::Stopped(_)) => return accepted,
+                                Err(SubmitError::Full(_)) => {}
+                            }
+                        }
+                    })
+                })
+                .collect();
+            std::thread::yield_now();
+            dispatcher.shutdown(Instant::now());
+
+            let mut accepted: Vec<u64> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+            accepted.sort_unstable();
+            let mut completed: Vec<u64> = statuses(&completions).into_iter().map(|(id, _)| id).collect();
+            completed.sort_unstable();
+            assert_eq!(accepted, completed);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Following each transaction from the stream write to the peer's acknowledgement.
+//!
+//! `stream_send` accepting the bytes only means quiche has buffered them. To know more, a
+//! tracked transaction gets a client-initiated unidirectional stream of its own (6, 10,
+//! 14, ...; 2 is the control stream), closed with a fin right after it. quiche forgets a
+//! stream of ours once everything up to the fin is acknowledged, and reports one the peer
+//! stopped as `StreamStopped`, so polling the stream tells how the transaction ended. A
+//! peer can't reset a stream it only receives on: it sends STOP_SENDING, and quiche answers
+//! with RESET_STREAM for us. Once that reset is acknowledged quiche forgets the stream too,
+//! so a stop has to be seen before then (it makes the stream writable, which is cheap to
+//! check) or it would look like an ack.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use afterburner_common::codec::CONTROL_STREAM;
+
+use crate::histogram::Histogram;
+
+/// Tracked transactions go on client-initiated uni streams after the control stream
+const FIRST_STREAM: u64 = CONTROL_STREAM + 4;
+/// In-flight streams checked for an ack per scan, oldest first. Acks come roughly in send
+/// order, so this bounds the work per RX without holding back more than a few reports.
+const ACK_SCAN_WINDOW: usize = 64;
+
+/// How a tracked transaction ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxOutcome {
+    /// The peer acknowledged all of it
+    Acked,
+    /// The peer sent STOP_SENDING with this code; it may have read some or all of it
+    Stopped(u64),
+    /// quiche refused the rest of a transaction that was only partly written
+    Failed(quiche::Error),
+    /// The connection closed before the ack
+    ConnectionClosed,
+}
+
+/// One transaction's outcome.
+#[derive(Clone, Copy, Debug)]
+pub struct TxReport {
+    /// The caller's tag from [`QuicDriver::send_tracked_tx`](crate::QuicDriver::send_tracked_tx)
+    pub tag: u64,
+    pub stream_id: u64,
+    pub outcome: TxOutcome,
+    /// From all of it being on the stream to the outcome being seen; the time to ack for
+    /// `Acked`, and zero if it never was all on the stream
+    pub elapsed: Duration,
+}
+
+/// Called with every [`TxReport`], on the engine thread.
+pub type OutcomeHandler = Box<dyn FnMut(&TxReport) + Send>;
+
+/// Transaction counts since the connection started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrackStats {
+    /// Accepted by [`QuicDriver::send_tracked_tx`](crate::QuicDriver::send_tracked_tx)
+    pub sent: u64,
+    pub acked: u64,
+    pub stopped: u64,
+    pub failed: u64,
+    /// Unacknowledged when the connection closed
+    pub closed: u64,
+    /// Sent and not ended yet
+    pub in_flight: u64,
+}
+
+/// A transaction only partly written when flow control credit ran out.
+struct Partial {
+    tag: u64,
+    stream_id: u64,
+    rest: Vec<u8>,
+}
+
+struct InFlight {
+    tag: u64,
+    written_at: Instant,
+}
+
+/// Streams for tracked transactions, and what is still waiting for its ack.
+pub struct TxTracker {
+    next_stream: u64,
+    /// Finished before anything new is sent, so transactions go out in order
+    partial: Option<Partial>,
+    /// By stream ID, i.e. in send order
+    in_flight: BTreeMap<u64, InFlight>,
+    /// Scratch space for the streams a scan found ended
+    ended: Vec<(u64, TxOutcome)>,
+    /// Packets arrived since the last scan; acks and STOP_SENDING only come with them
+    rx_since_scan: bool,
+    stats: TrackStats,
+    /// Time to ack in nanoseconds
+    pub time_to_ack: Histogram,
+    handler: Option<OutcomeHandler>,
+}
+
+impl Default for TxTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxTracker {
+    pub fn new() -> Self {
+        TxTracker {
+            next_stream: FIRST_STREAM,
+            partial: None,
+            in_flight: BTreeMap::new(),
+            ended: Vec::with_capacity(ACK_SCAN_WINDOW),
+            rx_since_scan: false,
+            stats: TrackStats::default(),
+            time_to_ack: Histogram::new(),
+            handler: None,
+        }
+    }
+
+    pub fn stats(&self) -> TrackStats {
+        TrackStats { in_flight: self.in_flight.len() as u64 + self.partial.is_some() as u64, ..self.stats }
+    }
+
+    pub fn set_handler(&mut self, handler: OutcomeHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Open the next stream for `tx` and write it with a fin. Returns the stream ID once
+    /// quiche has taken at least part of it; the rest follows from [`poll`](Self::poll)
+    /// as credit comes in, and until then this returns `Err(Done)`. `Err(StreamLimit)`
+    /// means waiting for the peer to allow more streams.
+    pub fn send(&mut self, conn: &mut quiche::Connection, tag: u64, tx: &[u8]) -> Result<u64, quiche::Error> {
+        self.flush(conn);
+        if self.partial.is_some() {
+            return Err(quiche::Error::Done);
+        }
+        let stream_id = self.next_stream;
+        let written = conn.stream_send(stream_id, tx, true)?;
+        self.advance_stream();
+        self.stats.sent += 1;
+        if written < tx.len() {
+            self.partial = Some(Partial { tag, stream_id, rest: tx[written..].to_vec() });
+        } else {
+            self.in_flight.insert(stream_id, InFlight { tag, written_at: Instant::now() });
+        }
+        Ok(stream_id)
+    }
+
+    /// Next client-initiated uni stream.
+    fn advance_stream(&mut self) {
+        self.next_stream += 4;
+    }
+
+    /// Note that packets came in, so the next [`poll`](Self::poll) looks for acks.
+    pub fn on_rx(&mut self) {
+        self.rx_since_scan = true;
+    }
+
+    /// Finish a partly written transaction and report every one that ended. Call once
+    /// per loop iteration, after RX.
+    pub fn poll(&mut self, conn: &mut quiche::Connection) {
+        if conn.is_closed() {
+            self.abandon();
+            return;
+        }
+        self.flush(conn);
+        if !std::mem::take(&mut self.rx_since_scan) {
+            return;
+        }
+
+        let now = Instant::now();
+        // Stops first: a stopped stream is writable until quiche collects it
+        for stream_id in conn.writable() {
+            if !self.in_flight.contains_key(&stream_id) {
+                continue;
+            }
+            if let Err(quiche::Error::StreamStopped(code)) = conn.stream_capacity(stream_id) {
+                self.ended.push((stream_id, TxOutcome::Stopped(code)));
+            }
+        }
+        self.finish_ended(now);
+
+        for (&stream_id, _) in self.in_flight.iter().take(ACK_SCAN_WINDOW) {
+            if let Some(outcome) = outcome(conn.stream_capacity(stream_id)) {
+                self.ended.push((stream_id, outcome));
+            }
+        }
+        self.finish_ended(now);
+    }
+
+    fn finish_ended(&mut self, now: Instant) {
+        let mut ended = std::mem::take(&mut self.ended);
+        for (stream_id, outcome) in ended.drain(..) {
+            if let Some(tx) = self.in_flight.remove(&stream_id) {
+                self.report(tx.tag, stream_id, outcome, now - tx.written_at);
+            }
+        }
+        self.ended = ended;
+    }
+
+    /// Report everything still in flight as `ConnectionClosed`, e.g. after closing the
+    /// connection ourselves.
+    pub fn abandon(&mut self) {
+        let now = Instant::now();
+        if let Some(p) = self.partial.take() {
+            self.report(p.tag, p.stream_id, TxOutcome::ConnectionClosed, Duration::ZERO);
+        }
+        for (stream_id, tx) in std::mem::take(&mut self.in_flight) {
+            self.report(tx.tag, stream_id, TxOutcome::ConnectionClosed, now - tx.written_at);
+        }
+    }
+
+    fn flush(&mut self, conn: &mut quiche::Connection) {
+        let Some(mut p) = self.partial.take() else { return };
+        match conn.stream_send(p.stream_id, &p.rest, true) {
+            Ok(written) if written < p.rest.len() => {
+                p.rest.drain(..written);
+                self.partial = Some(p);
+            }
+            Ok(_) => {
+                self.in_flight.insert(p.stream_id, InFlight { tag: p.tag, written_at: Instant::now() });
+            }
+            Err(quiche::Error::Done) => self.partial = Some(p),
+            Err(quiche::Error::StreamStopped(code)) => {
+                self.report(p.tag, p.stream_id, TxOutcome::Stopped(code), Duration::ZERO);
+            }
+            Err(e) => self.report(p.tag, p.stream_id, TxOutcome::Failed(e), Duration::ZERO),
+        }
+    }
+
+    fn report(&mut self, tag: u64, stream_id: u64, outcome: TxOutcome, elapsed: Duration) {
+        match outcome {
+            TxOutcome::Acked => {
+                self.stats.acked += 1;
+                self.time_to_ack.record(elapsed.as_nanos() as u64);
+            }
+            TxOutcome::Stopped(_) => self.stats.stopped += 1,
+            TxOutcome::Failed(_) => self.stats.failed += 1,
+            TxOutcome::ConnectionClosed => self.stats.closed += 1,
+        }
+        if let Some(h) = self.handler.as_mut() {
+            h(&TxReport { tag, stream_id, outcome, elapsed });
+        }
+    }
+}
+
+/// What `stream_capacity` says about a fully written stream of ours: `None` while it is
+/// still in flight. A stream gone altogether was acknowledged, unless it was seen stopped
+/// before (stops are reported then and never revisited).
+fn outcome(capacity: Result<usize, quiche::Error>) -> Option<TxOutcome> {
+    match capacity {
+        Err(quiche::Error::InvalidStreamState(_)) => Some(TxOutcome::Acked),
+        Err(quiche::Error::StreamStopped(code)) => Some(TxOutcome::Stopped(code)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn stream_capacity_maps_to_outcomes() {
+        assert_eq!(outcome(Ok(1200)), None);
+        assert_eq!(outcome(Ok(0)), None);
+        assert_eq!(outcome(Err(quiche::Error::Done)), None);
+        assert_eq!(outcome(Err(quiche::Error::InvalidStreamState(6))), Some(TxOutcome::Acked));
+        assert_eq!(outcome(Err(quiche::Error::StreamStopped(42))), Some(TxOutcome::Stopped(42)));
+    }
+
+    #[test]
+    fn streams_step_through_client_uni_ids() {
+        let mut tracker = TxTracker::new();
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(tracker.next_stream);
+            tracker.advance_stream();
+        }
+        assert_eq!(ids, [6, 10, 14, 18]);
+        // Client-initiated unidirectional, and never the control stream
+        assert!(ids.iter().all(|&id| id & 3 == 2 && id != CONTROL_STREAM));
+    }
+
+    #[test]
+    fn reports_count_by_outcome_and_reach_the_handler() {
+        let mut tracker = TxTracker::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        tracker.set_handler(Box::new(move |r: &TxReport| sink.lock().unwrap().push((r.tag, r.stream_id, r.outcome))));
+
+        let now = Instant::now();
+        tracker.in_flight.insert(6, InFlight { tag: 1, written_at: now });
+        tracker.in_flight.insert(10, InFlight { tag: 2, written_at: now });
+        tracker.in_flight.insert(14, InFlight { tag: 3, written_at: now });
+        assert_eq!(tracker.stats().in_flight, 3);
+
+        // A stream reported stopped is gone, so a later "collected" can't turn it into an ack
+        tracker.ended.push((10, TxOutcome::Stopped(7)));
+        tracker.finish_ended(now);
+        tracker.ended.push((10, TxOutcome::Acked));
+        tracker.ended.push((6, TxOutcome::Acked));
+        tracker.finish_ended(now);
+        tracker.abandon();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [(2, 10, TxOutcome::Stopped(7)), (1, 6, TxOutcome::Acked), (3, 14, TxOutcome::ConnectionClosed)]
+        );
+        let stats = tracker.stats();
+        assert_eq!((stats.acked, stats.stopped, stats.closed, stats.in_flight), (1, 1, 1, 0));
+        assert_eq!(tracker.time_to_ack.count(), 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! UMEM allocation: huge pages of an explicit size, placed on the NIC's NUMA node, with how
+//! the memory was actually backed reported at startup.
+//!
+//! The region is mapped without `MAP_POPULATE`, bound to the node with `mbind`, and only then
+//! faulted in, so the pages are allocated where the NIC can reach them without crossing the
+//! interconnect. `MPOL_MF_MOVE` also migrates pages that `mlockall(MCL_FUTURE)` populated
+//! at mmap time.
+
+use std::{fmt, fs, io, ptr};
+
+use libc::{c_ulong, c_void, mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use log::{info, warn};
+
+use crate::config::UmemConfig;
+
+const MAP_HUGE_SHIFT: i32 = 26;
+const MPOL_PREFERRED: i32 = 1;
+const MPOL_BIND: i32 = 2;
+const MPOL_F_NODE: i32 = 1 << 0;
+const MPOL_F_ADDR: i32 = 1 << 1;
+const MPOL_MF_STRICT: u32 = 1 << 0;
+const MPOL_MF_MOVE: u32 = 1 << 1;
+
+/// Huge page size for the UMEM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum HugePages {
+    /// Regular pages
+    #[cfg_attr(feature = "clap", value(name = "off"))]
+    #[serde(rename = "off")]
+    Off,
+    /// 2 MiB pages (reserve with `nr_hugepages`)
+    #[cfg_attr(feature = "clap", value(name = "2m"))]
+    #[serde(rename = "2m")]
+    Size2M,
+    /// 1 GiB pages (usually reserved at boot: `hugepagesz=1G hugepages=N`)
+    #[cfg_attr(feature = "clap", value(name = "1g"))]
+    #[serde(rename = "1g")]
+    Size1G,
+}
+
+impl HugePages {
+    fn page_size(self) -> Option<usize> {
+        match self {
+            HugePages::Off => None,
+            HugePages::Size2M => Some(2 << 20),
+            HugePages::Size1G => Some(1 << 30),
+        }
+    }
+}
+
+/// How the UMEM ended up being backed.
+#[derive(Debug, Clone, Copy)]
+pub struct Backing {
+    pub page_size: usize,
+    pub huge: bool,
+    /// Node the NIC sits on (`None` for virtual devices and single-node machines)
+    pub nic_node: Option<u32>,
+    /// Node the memory was bound to
+    pub bound_node: Option<u32>,
+    /// Node the first page was actually allocated on
+    pub actual_node: Option<u32>,
+}
+
+impl fmt::Display for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = |n: Option<u32>| n.map_or("-".to_string(), |n| n.to_string());
+        write!(
+            f,
+            "{} pages{} | NUMA node {} (NIC: {}, bound: {})",
+            format_size(self.page_size),
+            if self.huge { " (huge)" } else { "" },
+            node(self.actual_node),
+            node(self.nic_node),
+            node(self.bound_node),
+        )
+    }
+}
+
+/// An anonymous UMEM mapping, unmapped on drop.
+pub struct Umem {
+    pub ptr: *mut u8,
+    map_len: usize,
+    pub backing: Backing,
+}
+
+impl Umem {
+    /// Map `cfg.size` bytes for the socket on `iface`. Falls back to regular pages and to
+    /// a preferred (rather than bound) node unless `cfg.strict`.
+    pub fn allocate(cfg: &UmemConfig, iface: &str) -> io::Result<Umem> {
+        let nic_node = nic_numa_node(iface);
+        let node = cfg.numa_node.or(nic_node);
+
+        let (ptr, map_len, page_size) = match map(cfg.size, cfg.hugepages, node) {
+            Ok(m) => m,
+            Err(e) if cfg.strict || cfg.hugepages == HugePages::Off => return Err(e),
+            Err(e) => {
+                warn!(target: "umem", "{}; falling back to regular pages", e);
+                map(cfg.size, HugePages::Off, node)?
+            }
+        };
+        let umem = Umem {
+            ptr,
+            map_len,
+            backing: Backing { page_size, huge: page_size > base_page_size(), nic_node, bound_node: None, actual_node: None },
+        };
+        umem.place(node, cfg.strict)
+    }
+
+    /// Bind to `node` (if any), fault every page in and record where they landed.
+    fn place(mut self, node: Option<u32>, strict: bool) -> io::Result<Umem> {
+        if let Some(node) = node {
+            // A bound fault that finds no huge page on the node is a SIGBUS, not an error
+            if strict && self.backing.huge {
+                let needed = self.map_len / self.backing.page_size;
+                let free = node_free_hugepages(node, self.backing.page_size);
+                if free.is_some_and(|free| free < needed) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::OutOfMemory,
+                        hugepage_hint(self.backing.page_size, needed, Some(node), &io::Error::from(io::ErrorKind::OutOfMemory)),
+                    ));
+                }
+            }
+            match mbind(self.ptr, self.map_len, node, strict) {
+                Ok(()) => self.backing.bound_node = Some(node),
+                Err(e) if strict => {
+                    return Err(io::Error::new(e.kind(), format!("binding UMEM to NUMA node {}: {}", node, e)))
+                }
+                Err(e) => warn!(target: "umem", "Failed to bind UMEM to NUMA node {}: {}", node, e),
+            }
+        }
+
+        // Fault in now rather than on the first packet; also allocates on the bound node
+        for off in (0..self.map_len).step_by(self.backing.page_size) {
+            unsafe { self.ptr.add(off).write_volatile(0) };
+        }
+        self.backing.actual_node = page_node(self.ptr);
+
+        let b = &self.backing;
+        info!(target: "umem", "{} in {}", format_size(self.map_len), b);
+        if let (Some(want), Some(got)) = (node, b.actual_node) {
+            if want != got {
+                warn!(target: "umem", "UMEM is on NUMA node {} but should be on {}; every packet crosses the interconnect", got, want);
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, self.map_len) };
+    }
+}
+
+/// Anonymous private mapping of at least `size` bytes; not yet populated.
+fn map(size: usize, huge: HugePages, node: Option<u32>) -> io::Result<(*mut u8, usize, usize)> {
+    let mut flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let page_size = match huge.page_size() {
+        Some(page) => {
+            flags |= MAP_HUGETLB | ((page.trailing_zeros() as i32) << MAP_HUGE_SHIFT);
+            page
+        }
+        None => base_page_size(),
+    };
+    let map_len = size.div_ceil(page_size) * page_size;
+
+    let ptr = unsafe { mmap(ptr::null_mut(), map_len, PROT_READ | PROT_WRITE, flags, -1, 0) };
+    if ptr == MAP_FAILED {
+        let e = io::Error::last_os_error();
+        return Err(match huge.page_size() {
+            Some(page) => io::Error::new(e.kind(), hugepage_hint(page, map_len / page, node, &e)),
+            None => e,
+        });
+    }
+    Ok((ptr as *mut u8, map_len, page_size))
+}
+
+/// sysfs directory of the huge page pool for `page`, per node or system-wide.
+fn hugepage_pool(page: usize, node: Option<u32>) -> String {
+    let kb = page >> 10;
+    match node {
+        Some(n) => format!("/sys/devices/system/node/node{}/hugepages/hugepages-{}kB", n, kb),
+        None => format!("/sys/kernel/mm/hugepages/hugepages-{}kB", kb),
+    }
+}
+
+fn node_free_hugepages(node: u32, page: usize) -> Option<usize> {
+    let pool = hugepage_pool(page, Some(node));
+    fs::read_to_string(format!("{}/free_hugepages", pool)).ok()?.trim().parse().ok()
+}
+
+fn hugepage_hint(page: usize, needed: usize, node: Option<u32>, e: &io::Error) -> String {
+    let pool = hugepage_pool(page, node);
+    let free = fs::read_to_string(format!("{}/free_hugepages", pool)).ok();
+    format!(
+        "{} huge page UMEM unavailable ({}): need {}, {} free; reserve them with `echo {} | sudo tee {}/nr_hugepages`",
+        format_size(page),
+        e,
+        needed,
+        free.as_deref().map_or("?", str::trim),
+        needed,
+        pool,
+    )
+}
+
+fn mbind(addr: *mut u8, len: usize, node: u32, strict: bool) -> io::Result<()> {
+    if node >= u64::BITS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "node out of range"));
+    }
+    let mask: u64 = 1 << node;
+    let (mode, flags) = if strict { (MPOL_BIND, MPOL_MF_MOVE | MPOL_MF_STRICT) } else { (MPOL_PREFERRED, MPOL_MF_MOVE) };
+    // maxnode counts bits, and the kernel ignores the last one
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len as c_ulong,
+            mode as c_ulong,
+            &mask as *const u64,
+            u64::BITS as c_ulong + 1,
+            flags as c_ulong,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// NUMA node backing the page at `addr`.
+fn page_node(addr: *mut u8) -> Option<u32> {
+    let mut node: i32 = -1;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut node as *mut i32,
+            ptr::null_mut::<u64>(),
+            0 as c_ulong,
+            addr,
+            (MPOL_F_NODE | MPOL_F_ADDR) as c_ulong,
+        )
+    };
+    (ret == 0 && node >= 0).then_some(node as u32)
+}
+
+/// The NIC's node from sysfs; `-1` (or no `device` link, as on veth) means unknown.
+fn nic_numa_node(iface: &str) -> Option<u32> {
+    let s = fs::read_to_string(format!("/sys/class/net/{}/device/numa_node", iface)).ok()?;
+    s.trim().parse::<i32>().ok().filter(|&n| n >= 0).map(|n| n as u32)
+}
+
+fn base_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn format_size(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 && b.is_multiple_of(1 << 30) => format!("{} GiB", b >> 30),
+        b if b >= 1 << 20 && b.is_multiple_of(1 << 20) => format!("{} MiB", b >> 20),
+        b => format!("{} KiB", b >> 10),
+    }
+}
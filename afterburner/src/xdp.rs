@@ -0,0 +1,681 @@
+//! Attaching the XDP program that redirects our UDP port to the AF_XDP socket, including
+//! cleaning up after a crashed run (via rtnetlink).
+
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::{io, mem};
+use aya::programs::{loaded_programs, Xdp, XdpFlags};
+use aya::programs::xdp::XdpLinkId;
+use log::{info, warn};
+use libc::{
+    bind, close, recv, send, socket, sockaddr_nl, AF_NETLINK, AF_UNSPEC, NETLINK_ROUTE,
+    SOCK_CLOEXEC, SOCK_RAW,
+};
+
+// rtnetlink constants (linux/rtnetlink.h, linux/if_link.h)
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(1 << 15 | 1 << 14);
+
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const IFLA_XDP_DRV_PROG_ID: u16 = 5;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+const IFLA_XDP_EXPECTED_FD: u16 = 8;
+
+/// `XDP_FLAGS_REPLACE`: only attach if the program in `IFLA_XDP_EXPECTED_FD` is the current one
+const XDP_FLAGS_REPLACE: u32 = 1 << 4;
+/// `BPF_PROG_GET_FD_BY_ID` (linux/bpf.h)
+const BPF_PROG_GET_FD_BY_ID: libc::c_long = 13;
+const BPF_LINK_CREATE: libc::c_long = 28;
+/// `BPF_XDP` attach type
+pub(crate) const BPF_XDP: u32 = 37;
+
+const XDP_ATTACHED_NONE: u8 = 0;
+const XDP_ATTACHED_DRV: u8 = 1;
+const XDP_ATTACHED_SKB: u8 = 2;
+const XDP_ATTACHED_HW: u8 = 3;
+
+/// Name of our XDP program as reported by the kernel (`bpf_prog_info.name`).
+pub(crate) const PROGRAM_NAME: &str = "afterburner";
+
+/// How the XDP program is attached to the interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum XdpMode {
+    /// Native (driver) mode, falling back to generic SKB mode if the driver lacks XDP support
+    Auto,
+    /// Generic XDP (works on every interface, no zero-copy)
+    Skb,
+    /// Native XDP in the NIC driver
+    Drv,
+    /// Offloaded to the NIC
+    Hw,
+}
+
+impl XdpMode {
+    fn flags(self) -> XdpFlags {
+        match self {
+            XdpMode::Auto | XdpMode::Drv => XdpFlags::DRV_MODE,
+            XdpMode::Skb => XdpFlags::SKB_MODE,
+            XdpMode::Hw => XdpFlags::HW_MODE,
+        }
+    }
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct NlMsgHdr {
+    len: u32,
+    ty: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    ty: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct NlAttr {
+    len: u16,
+    ty: u16,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct NlMsgErr {
+    error: i32,
+    msg: NlMsgHdr,
+}
+
+/// An XDP program currently attached to an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AttachedProg {
+    id: u32,
+    /// One of the `XDP_ATTACHED_*` modes
+    mode: u8,
+}
+
+/// How our program is attached, which decides how it comes off again.
+#[derive(Debug)]
+pub enum Attachment {
+    /// Attached by aya (through a `bpf_link` on kernels >= 5.9); detached with
+    /// `Xdp::detach`, or when the program is dropped
+    Link(XdpLinkId),
+    /// Swapped in over netlink for a program that was already attached
+    Replaced(Replaced),
+    /// Our own `bpf_link` to a device-bound program (see [`attach_bound`]); closing it
+    /// detaches the program
+    Bound(OwnedFd),
+}
+
+/// The program [`attach_program`] puts on the interface.
+enum Program<'a> {
+    /// Loaded by aya
+    Aya(&'a mut Xdp),
+    /// Loaded device-bound, which aya can't do, so it can't attach it either
+    Bound(BorrowedFd<'a>),
+}
+
+/// Our program, swapped in over netlink for a stale one or, with `--xdp-force`, a foreign
+/// one. Unlike a `bpf_link`, nothing removes it when we exit, so it is detached on drop; if
+/// we crash instead, the next run finds it as a stale afterburner program.
+#[derive(Debug)]
+pub struct Replaced {
+    ifindex: u32,
+    /// One of the `XDP_ATTACHED_*` modes
+    mode: u8,
+    prog_id: u32,
+    detached: bool,
+}
+
+impl Replaced {
+    /// Detach our program, unless something else has replaced it in the meantime.
+    pub fn detach(mut self) -> Result<(), io::Error> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), io::Error> {
+        if mem::replace(&mut self.detached, true) {
+            return Ok(());
+        }
+        match query_attached(self.ifindex)? {
+            Some(prog) if prog.id == self.prog_id => detach_netlink(self.ifindex, self.mode),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Replaced {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            warn!(target: "xdp", "Failed to detach XDP program id {}: {}", self.prog_id, e);
+        }
+    }
+}
+
+/// Attach the afterburner XDP program to `iface`.
+///
+/// An afterburner program that no process holds anymore is stale, left behind by a crashed
+/// run, and is replaced; one still held by a running process, like any other program, is
+/// only replaced when `force` is set. Replacing is atomic if the old program is attached
+/// in the mode we want. On kernels >= 5.9 aya attaches through a `bpf_link`, so the kernel
+/// removes the program when the process exits, even on a crash.
+pub fn attach(program: &mut Xdp, iface: &str, mode: XdpMode, force: bool) -> Result<Attachment, io::Error> {
+    attach_program(Program::Aya(program), iface, mode, force)
+}
+
+/// [`attach`] for a program loaded bound to `iface`, which only attaches in native mode.
+/// Errors keep the kind of the failed step, so the caller can fall back to a plain program.
+pub fn attach_bound(program: BorrowedFd<'_>, iface: &str, force: bool) -> Result<Attachment, io::Error> {
+    attach_program(Program::Bound(program), iface, XdpMode::Drv, force)
+}
+
+fn attach_program(program: Program<'_>, iface: &str, mode: XdpMode, force: bool) -> Result<Attachment, io::Error> {
+    let ifindex = ifindex(iface)?;
+
+    if let Some(prog) = query_attached(ifindex)? {
+        let name = program_name(prog.id);
+        let name = name.as_deref().unwrap_or("?");
+        let held_by_link = |e: io::Error, what: &str| {
+            if e.raw_os_error() == Some(libc::EBUSY) {
+                io::Error::new(e.kind(), format!(
+                    "XDP program id {} on {} is held by a bpf_link; is another afterburner instance running?",
+                    prog.id, iface))
+            } else {
+                io::Error::new(e.kind(), format!("failed to {} XDP program id {} on {}: {}", what, prog.id, iface, e))
+            }
+        };
+        let owner = prog_owner(prog.id);
+        match (name, owner) {
+            (PROGRAM_NAME, None) => {
+                info!(target: "xdp", "Found stale afterburner program (id {}) on {}, replacing it", prog.id, iface);
+            }
+            (PROGRAM_NAME, Some(pid)) if !force => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} has afterburner program id {} attached by running process {}; is another instance running? pass --xdp-force to replace it",
+                    iface, prog.id, pid)));
+            }
+            _ if !force => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} already has XDP program {:?} (id {}) attached; pass --xdp-force to replace it",
+                    iface, name, prog.id)));
+            }
+            (_, Some(pid)) => {
+                warn!(target: "xdp", "Replacing XDP program {:?} (id {}) on {}, held by running process {}", name, prog.id, iface, pid);
+            }
+            (_, None) => warn!(target: "xdp", "Replacing XDP program {:?} (id {}) on {}", name, prog.id, iface),
+        }
+        if attached_flags(prog.mode).bits() == mode.flags().bits() {
+            let new_fd = match &program {
+                Program::Aya(program) => program.fd().map_err(io::Error::other)?.as_fd(),
+                Program::Bound(fd) => *fd,
+            };
+            let replaced = replace(new_fd, ifindex, prog).map_err(|e| held_by_link(e, "replace"))?;
+            info!(target: "xdp", "Attached to {} in {} mode", iface, mode_name(mode));
+            return Ok(Attachment::Replaced(replaced));
+        }
+        // Native and generic XDP can't be attached at the same time, so this one has to
+        // come off before ours goes on
+        warn!(target: "xdp", "XDP program id {} is attached in {} mode, so {} has no XDP program until ours is attached",
+            prog.id, attached_name(prog.mode), iface);
+        detach_netlink(ifindex, prog.mode).map_err(|e| held_by_link(e, "detach"))?;
+    }
+
+    let program = match program {
+        Program::Aya(program) => program,
+        Program::Bound(fd) => {
+            let link = link_create(fd, ifindex, mode.flags().bits())
+                .map_err(|e| io::Error::new(e.kind(), format!("attach to {} in DRV mode: {}", iface, e)))?;
+            info!(target: "xdp", "Attached to {} in DRV mode", iface);
+            return Ok(Attachment::Bound(link));
+        }
+    };
+    match program.attach(iface, mode.flags()) {
+        Ok(link_id) => {
+            info!(target: "xdp", "Attached to {} in {} mode", iface, mode_name(mode));
+            Ok(Attachment::Link(link_id))
+        }
+        Err(e) if mode == XdpMode::Auto && is_unsupported(&e) => {
+            warn!(target: "xdp", "Driver for {} lacks native XDP ({}), falling back to SKB mode", iface, e);
+            let link_id = program.attach(iface, XdpFlags::SKB_MODE)
+                .map_err(|e| io::Error::other(format!("attach to {} in SKB mode: {}", iface, e)))?;
+            info!(target: "xdp", "Attached to {} in SKB mode", iface);
+            Ok(Attachment::Link(link_id))
+        }
+        Err(e) if mode == XdpMode::Drv && is_unsupported(&e) => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "the driver for {} does not support native XDP ({}); use --xdp-mode skb or auto", iface, e)))
+        }
+        Err(e) if mode == XdpMode::Hw && is_unsupported(&e) => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "{} does not support XDP offload ({}); use --xdp-mode drv or auto", iface, e)))
+        }
+        Err(e) if mode != XdpMode::Skb && os_error(&e) == Some(libc::EINVAL) => {
+            // The driver has native XDP but refused this attach; falling back would quietly
+            // give up zero-copy
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{} rejected the XDP program in {} mode ({}); check that the MTU fits native XDP and that no program is attached in another mode, or use --xdp-mode skb",
+                iface, mode_name(mode), e)))
+        }
+        Err(e) => Err(io::Error::other(format!("attach to {}: {}", iface, e))),
+    }
+}
+
+fn mode_name(mode: XdpMode) -> &'static str {
+    match mode {
+        XdpMode::Auto | XdpMode::Drv => "DRV",
+        XdpMode::Skb => "SKB",
+        XdpMode::Hw => "HW",
+    }
+}
+
+fn attached_name(attached_mode: u8) -> &'static str {
+    match attached_mode {
+        XDP_ATTACHED_SKB => "SKB",
+        XDP_ATTACHED_HW => "HW",
+        _ => "DRV",
+    }
+}
+
+/// The attach flags for a program attached in `attached_mode`.
+fn attached_flags(attached_mode: u8) -> XdpFlags {
+    match attached_mode {
+        XDP_ATTACHED_SKB => XdpFlags::SKB_MODE,
+        XDP_ATTACHED_HW => XdpFlags::HW_MODE,
+        _ => XdpFlags::DRV_MODE,
+    }
+}
+
+/// Whether an attach error means the requested mode is not available on this device.
+fn is_unsupported(err: &(dyn std::error::Error + 'static)) -> bool {
+    os_error(err) == Some(libc::EOPNOTSUPP)
+}
+
+/// The errno behind an attach error.
+fn os_error(err: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    let mut cur = Some(err);
+    while let Some(e) = cur {
+        if let Some(io_err) = e.downcast_ref::<io::Error>() {
+            return io_err.raw_os_error();
+        }
+        cur = e.source();
+    }
+    None
+}
+
+pub(crate) fn ifindex(iface: &str) -> Result<u32, io::Error> {
+    let if_name = CString::new(iface)?;
+    let idx = unsafe { libc::if_nametoindex(if_name.as_ptr()) };
+    if idx == 0 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("interface '{}' not found", iface)));
+    }
+    Ok(idx)
+}
+
+fn program_name(id: u32) -> Option<String> {
+    loaded_programs()
+        .filter_map(Result::ok)
+        .find(|info| info.id() == id)
+        .and_then(|info| info.name_as_str().map(str::to_owned))
+}
+
+/// A process other than us holding an fd for program `id` (the program itself, or a
+/// `bpf_link` to it), found through `/proc/<pid>/fdinfo`. A program attached over netlink
+/// outlives its loader, so this is what tells a live instance from a crashed one.
+fn prog_owner(id: u32) -> Option<u32> {
+    let me = std::process::id();
+    let needle = format!("prog_id:\t{}", id);
+    std::fs::read_dir("/proc").ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|&pid| pid != me)
+        .find(|pid| {
+            let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fdinfo", pid)) else { return false };
+            fds.filter_map(Result::ok).any(|fd| {
+                std::fs::read_to_string(fd.path()).is_ok_and(|info| info.lines().any(|l| l == needle))
+            })
+        })
+}
+
+/// Minimal rtnetlink socket for the two requests we need (RTM_GETLINK / RTM_SETLINK).
+struct Netlink {
+    fd: i32,
+    seq: u32,
+}
+
+impl Netlink {
+    fn open() -> Result<Self, io::Error> {
+        unsafe {
+            let fd = socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE);
+            if fd < 0 { return Err(io::Error::last_os_error()); }
+            let mut sa: sockaddr_nl = mem::zeroed();
+            sa.nl_family = AF_NETLINK as u16;
+            if bind(fd, &sa as *const _ as *const _, mem::size_of::<sockaddr_nl>() as u32) != 0 {
+                let err = io::Error::last_os_error();
+                close(fd);
+                return Err(err);
+            }
+            Ok(Netlink { fd, seq: 0 })
+        }
+    }
+
+    /// Send `ifinfomsg` + `attrs` as a single request and return the raw reply.
+    fn request(&mut self, ty: u16, flags: u16, ifindex: u32, attrs: &[u8], reply: &mut [u8]) -> Result<usize, io::Error> {
+        self.seq += 1;
+        let hdr_len = mem::size_of::<NlMsgHdr>() + mem::size_of::<IfInfoMsg>();
+        let mut msg = vec![0u8; hdr_len + attrs.len()];
+        unsafe {
+            let hdr = msg.as_mut_ptr() as *mut NlMsgHdr;
+            (*hdr).len = msg.len() as u32;
+            (*hdr).ty = ty;
+            (*hdr).flags = NLM_F_REQUEST | flags;
+            (*hdr).seq = self.seq;
+            let ifi = msg.as_mut_ptr().add(mem::size_of::<NlMsgHdr>()) as *mut IfInfoMsg;
+            (*ifi).family = AF_UNSPEC as u8;
+            (*ifi).index = ifindex as i32;
+        }
+        msg[hdr_len..].copy_from_slice(attrs);
+
+        unsafe {
+            if send(self.fd, msg.as_ptr() as *const _, msg.len(), 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let n = recv(self.fd, reply.as_mut_ptr() as *mut _, reply.len(), 0);
+            if n < 0 { return Err(io::Error::last_os_error()); }
+            let n = n as usize;
+            if n < mem::size_of::<NlMsgHdr>() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink reply"));
+            }
+            let hdr = &*(reply.as_ptr() as *const NlMsgHdr);
+            if hdr.ty == NLMSG_ERROR {
+                if n < mem::size_of::<NlMsgHdr>() + mem::size_of::<NlMsgErr>() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink error"));
+                }
+                let err = &*(reply.as_ptr().add(mem::size_of::<NlMsgHdr>()) as *const NlMsgErr);
+                if err.error != 0 { return Err(io::Error::from_raw_os_error(-err.error)); }
+            }
+            Ok(n)
+        }
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
+
+/// Iterate over the netlink attributes packed in `buf`, yielding `(type, payload)`.
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < mem::size_of::<NlAttr>() { return None; }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let ty = u16::from_ne_bytes([buf[2], buf[3]]) & NLA_TYPE_MASK;
+        if len < mem::size_of::<NlAttr>() || len > buf.len() { return None; }
+        let payload = &buf[mem::size_of::<NlAttr>()..len];
+        buf = &buf[((len + 3) & !3).min(buf.len())..];
+        Some((ty, payload))
+    })
+}
+
+fn push_attr(buf: &mut Vec<u8>, ty: u16, payload: &[u8]) {
+    let len = (mem::size_of::<NlAttr>() + payload.len()) as u16;
+    buf.extend_from_slice(&len.to_ne_bytes());
+    buf.extend_from_slice(&ty.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    while !buf.len().is_multiple_of(4) { buf.push(0); }
+}
+
+/// Query the XDP program attached to `ifindex`, if any.
+fn query_attached(ifindex: u32) -> Result<Option<AttachedProg>, io::Error> {
+    let mut nl = Netlink::open()?;
+    let mut reply = vec![0u8; 32 * 1024];
+    let n = nl.request(RTM_GETLINK, 0, ifindex, &[], &mut reply)?;
+
+    let hdr = unsafe { &*(reply.as_ptr() as *const NlMsgHdr) };
+    if hdr.ty != RTM_NEWLINK {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected RTM_GETLINK reply"));
+    }
+    let start = mem::size_of::<NlMsgHdr>() + mem::size_of::<IfInfoMsg>();
+    let end = (hdr.len as usize).min(n);
+    if end < start { return Ok(None); }
+
+    let Some((_, xdp)) = attrs(&reply[start..end]).find(|(ty, _)| *ty == IFLA_XDP) else {
+        return Ok(None);
+    };
+
+    Ok(parse_xdp(xdp))
+}
+
+/// The program in the nested `IFLA_XDP` attributes of an `RTM_NEWLINK`.
+///
+/// The kernel reports each mode's program as `IFLA_XDP_{SKB,DRV,HW}_PROG_ID`, in that
+/// order, and then `IFLA_XDP_ATTACHED`, which is `XDP_ATTACHED_MULTI` when there is more
+/// than one (a HW program next to a DRV or SKB one). So the first per-mode ID decides; the
+/// non-offloaded program is the one ours would clash with. Kernels before 4.20 only
+/// report `IFLA_XDP_PROG_ID` and the mode in `IFLA_XDP_ATTACHED`.
+fn parse_xdp(xdp: &[u8]) -> Option<AttachedProg> {
+    let mut attached = XDP_ATTACHED_NONE;
+    let mut id = 0;
+    let mut per_mode = None;
+    for (ty, payload) in attrs(xdp) {
+        if ty == IFLA_XDP_ATTACHED {
+            attached = payload.first().copied().unwrap_or(XDP_ATTACHED_NONE);
+            continue;
+        }
+        let Some(value) = payload.get(..4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]])) else { continue };
+        let mode = match ty {
+            IFLA_XDP_PROG_ID => {
+                id = value;
+                continue;
+            }
+            IFLA_XDP_DRV_PROG_ID => XDP_ATTACHED_DRV,
+            IFLA_XDP_SKB_PROG_ID => XDP_ATTACHED_SKB,
+            IFLA_XDP_HW_PROG_ID => XDP_ATTACHED_HW,
+            _ => continue,
+        };
+        per_mode.get_or_insert(AttachedProg { id: value, mode });
+    }
+
+    match (per_mode, attached) {
+        (Some(prog), _) if prog.id != 0 => Some(prog),
+        (_, XDP_ATTACHED_DRV | XDP_ATTACHED_SKB | XDP_ATTACHED_HW) if id != 0 => Some(AttachedProg { id, mode: attached }),
+        _ => None,
+    }
+}
+
+/// Detach a netlink-attached XDP program (equivalent to `ip link set dev X xdp off`).
+///
+/// Fails with `EBUSY` if the program is owned by a `bpf_link`.
+fn detach_netlink(ifindex: u32, attached_mode: u8) -> Result<(), io::Error> {
+    let mut nested = Vec::with_capacity(16);
+    push_attr(&mut nested, IFLA_XDP_FD, &(-1i32).to_ne_bytes());
+    push_attr(&mut nested, IFLA_XDP_FLAGS, &attached_flags(attached_mode).bits().to_ne_bytes());
+    set_xdp(ifindex, &nested)
+}
+
+/// Swap `program` in for `old` in a single request (`ip link set dev X xdp ... replace`
+/// semantics), so the interface always has one of the two. With `XDP_FLAGS_REPLACE` the
+/// kernel refuses with `EEXIST` if `old` is no longer the attached program.
+fn replace(program: BorrowedFd<'_>, ifindex: u32, old: AttachedProg) -> Result<Replaced, io::Error> {
+    let new_fd = program.as_raw_fd();
+    let old_fd = prog_fd_by_id(old.id)?;
+
+    let mut nested = Vec::with_capacity(32);
+    push_attr(&mut nested, IFLA_XDP_FD, &new_fd.to_ne_bytes());
+    push_attr(&mut nested, IFLA_XDP_EXPECTED_FD, &old_fd.as_raw_fd().to_ne_bytes());
+    let flags = attached_flags(old.mode).bits() | XDP_FLAGS_REPLACE;
+    push_attr(&mut nested, IFLA_XDP_FLAGS, &flags.to_ne_bytes());
+    set_xdp(ifindex, &nested)?;
+
+    let prog_id = query_attached(ifindex)?.map_or(0, |prog| prog.id);
+    Ok(Replaced { ifindex, mode: old.mode, prog_id, detached: false })
+}
+
+/// RTM_SETLINK with the given `IFLA_XDP` attributes.
+fn set_xdp(ifindex: u32, nested: &[u8]) -> Result<(), io::Error> {
+    let mut attrs = Vec::with_capacity(nested.len() + 4);
+    push_attr(&mut attrs, IFLA_XDP | NLA_F_NESTED, nested);
+
+    let mut nl = Netlink::open()?;
+    let mut reply = [0u8; 1024];
+    nl.request(RTM_SETLINK, NLM_F_ACK, ifindex, &attrs, &mut reply)?;
+    Ok(())
+}
+
+/// `BPF_LINK_CREATE` for `program` on `ifindex`: aya's attach, for a program aya didn't load.
+fn link_create(program: BorrowedFd<'_>, ifindex: u32, flags: u32) -> Result<OwnedFd, io::Error> {
+    #[repr(C)]
+    struct LinkCreateAttr {
+        prog_fd: u32,
+        target_ifindex: u32,
+        attach_type: u32,
+        flags: u32,
+    }
+    let attr = LinkCreateAttr { prog_fd: program.as_raw_fd() as u32, target_ifindex: ifindex, attach_type: BPF_XDP, flags };
+    let fd = unsafe {
+        libc::syscall(libc::SYS_bpf, BPF_LINK_CREATE, &attr as *const _, mem::size_of::<LinkCreateAttr>())
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// An fd for the loaded program `id`, as the kernel wants it in `IFLA_XDP_EXPECTED_FD`.
+fn prog_fd_by_id(id: u32) -> Result<OwnedFd, io::Error> {
+    #[repr(C)]
+    struct GetFdByIdAttr {
+        prog_id: u32,
+        next_id: u32,
+        open_flags: u32,
+    }
+    let attr = GetFdByIdAttr { prog_id: id, next_id: 0, open_flags: 0 };
+    let fd = unsafe {
+        libc::syscall(libc::SYS_bpf, BPF_PROG_GET_FD_BY_ID, &attr as *const _, mem::size_of::<GetFdByIdAttr>())
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_attr(buf: &mut Vec<u8>, ty: u16, id: u32) {
+        push_attr(buf, ty, &id.to_ne_bytes());
+    }
+
+    #[test]
+    fn attrs_read_back_what_push_attr_wrote() {
+        let mut buf = Vec::new();
+        push_attr(&mut buf, IFLA_XDP_FD, &(-1i32).to_ne_bytes());
+        push_attr(&mut buf, IFLA_XDP_ATTACHED, &[XDP_ATTACHED_SKB]);
+        push_attr(&mut buf, IFLA_XDP_FLAGS, &7u32.to_ne_bytes());
+        // The 1-byte payload is padded to the 4-byte attribute alignment
+        assert_eq!(buf.len(), 3 * 8);
+        assert_eq!(&buf[8..10], &5u16.to_ne_bytes());
+
+        let parsed: Vec<_> = attrs(&buf).collect();
+        assert_eq!(parsed, [
+            (IFLA_XDP_FD, &(-1i32).to_ne_bytes()[..]),
+            (IFLA_XDP_ATTACHED, &[XDP_ATTACHED_SKB][..]),
+            (IFLA_XDP_FLAGS, &7u32.to_ne_bytes()[..]),
+        ]);
+    }
+
+    #[test]
+    fn attrs_mask_the_nested_flag() {
+        let mut nested = Vec::new();
+        id_attr(&mut nested, IFLA_XDP_PROG_ID, 3);
+        let mut buf = Vec::new();
+        push_attr(&mut buf, IFLA_XDP | NLA_F_NESTED, &nested);
+
+        let (ty, payload) = attrs(&buf).next().unwrap();
+        assert_eq!(ty, IFLA_XDP);
+        assert_eq!(attrs(payload).collect::<Vec<_>>(), [(IFLA_XDP_PROG_ID, &3u32.to_ne_bytes()[..])]);
+    }
+
+    #[test]
+    fn attrs_stop_at_a_malformed_attribute() {
+        let mut buf = Vec::new();
+        id_attr(&mut buf, IFLA_XDP_PROG_ID, 3);
+        // Claims more than is left
+        let mut long = buf.clone();
+        long.extend_from_slice(&64u16.to_ne_bytes());
+        long.extend_from_slice(&IFLA_XDP_FLAGS.to_ne_bytes());
+        long.extend_from_slice(&[0; 4]);
+        assert_eq!(attrs(&long).count(), 1);
+        // Shorter than its own header
+        let mut short = buf.clone();
+        short.extend_from_slice(&2u16.to_ne_bytes());
+        short.extend_from_slice(&IFLA_XDP_FLAGS.to_ne_bytes());
+        assert_eq!(attrs(&short).count(), 1);
+        // A trailing partial header
+        buf.extend_from_slice(&[8, 0]);
+        assert_eq!(attrs(&buf).count(), 1);
+        assert_eq!(attrs(&[]).count(), 0);
+    }
+
+    #[test]
+    fn parse_xdp_reads_a_single_program() {
+        // As the kernel sends it: per-mode ID, then the mode, then the ID again
+        let mut buf = Vec::new();
+        id_attr(&mut buf, IFLA_XDP_SKB_PROG_ID, 42);
+        push_attr(&mut buf, IFLA_XDP_ATTACHED, &[XDP_ATTACHED_SKB]);
+        id_attr(&mut buf, IFLA_XDP_PROG_ID, 42);
+        assert_eq!(parse_xdp(&buf), Some(AttachedProg { id: 42, mode: XDP_ATTACHED_SKB }));
+    }
+
+    #[test]
+    fn parse_xdp_keeps_the_per_mode_program_in_multi_mode() {
+        let mut buf = Vec::new();
+        id_attr(&mut buf, IFLA_XDP_DRV_PROG_ID, 7);
+        id_attr(&mut buf, IFLA_XDP_HW_PROG_ID, 9);
+        // XDP_ATTACHED_MULTI, and no IFLA_XDP_PROG_ID
+        push_attr(&mut buf, IFLA_XDP_ATTACHED, &[4]);
+        let prog = parse_xdp(&buf).unwrap();
+        assert_eq!(prog, AttachedProg { id: 7, mode: XDP_ATTACHED_DRV });
+        assert_eq!(attached_flags(prog.mode).bits(), XdpFlags::DRV_MODE.bits());
+    }
+
+    #[test]
+    fn parse_xdp_falls_back_to_the_attached_mode() {
+        // Kernels without the per-mode IDs
+        let mut buf = Vec::new();
+        push_attr(&mut buf, IFLA_XDP_ATTACHED, &[XDP_ATTACHED_HW]);
+        id_attr(&mut buf, IFLA_XDP_PROG_ID, 5);
+        assert_eq!(parse_xdp(&buf), Some(AttachedProg { id: 5, mode: XDP_ATTACHED_HW }));
+    }
+
+    #[test]
+    fn parse_xdp_without_a_program() {
+        let mut buf = Vec::new();
+        push_attr(&mut buf, IFLA_XDP_ATTACHED, &[XDP_ATTACHED_NONE]);
+        assert_eq!(parse_xdp(&buf), None);
+        assert_eq!(parse_xdp(&[]), None);
+    }
+}
@@ -0,0 +1,781 @@
+//! The AF_XDP socket: UMEM, the fill/completion/RX/TX rings, and borrow-checked batches of
+//! received and to-be-sent frames.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::{io, mem};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use afterburner_common::{warn_ratelimited, RxMeta, RX_META_MAGIC};
+use log::{debug, warn};
+use libc::{
+    mmap, munmap, setsockopt, socket, AF_XDP, MAP_FAILED,
+    MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE,
+    SOCK_RAW, SOL_XDP, XDP_COPY, XDP_MMAP_OFFSETS, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING, XDP_RX_RING,
+    XDP_STATISTICS, XDP_TX_RING, XDP_UMEM_COMPLETION_RING, XDP_UMEM_FILL_RING,
+    XDP_UMEM_PGOFF_COMPLETION_RING, XDP_UMEM_PGOFF_FILL_RING, XDP_UMEM_REG,
+};
+
+use crate::config::UmemConfig;
+use crate::error::Error;
+use crate::frame_pool::FramePool;
+use crate::xsk_ring::{ConsRing, ProdRing};
+use crate::umem::Umem;
+
+/// Default and largest supported UMEM frame size (one page)
+pub const FRAME_SIZE: usize = 4096;
+/// Most descriptors an `RxBatch` or `TxBatch` holds
+pub const MAX_BATCH: u32 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffsets,
+    tx: XdpRingOffsets,
+    fr: XdpRingOffsets,
+    cr: XdpRingOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffsets {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+/// An mmapped ring, unmapped on drop; accessed through a [`ProdRing`] or [`ConsRing`] view.
+struct XdpRing {
+    producer: *mut AtomicU32,
+    consumer: *mut AtomicU32,
+    desc: *mut u8,
+    size: u32,
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl XdpRing {
+    /// Map the ring of `size` entries of `T` at page offset `pgoff` of the socket.
+    fn map<T>(fd: &OwnedFd, off: &XdpRingOffsets, size: u32, pgoff: i64) -> io::Result<XdpRing> {
+        let len = off.desc as usize + size as usize * mem::size_of::<T>();
+        let ptr = unsafe {
+            mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_POPULATE, fd.as_raw_fd(), pgoff)
+        };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            Ok(XdpRing {
+                producer: ptr.add(off.producer as usize) as *mut AtomicU32,
+                consumer: ptr.add(off.consumer as usize) as *mut AtomicU32,
+                desc: ptr.add(off.desc as usize) as *mut u8,
+                size,
+                ptr,
+                len,
+            })
+        }
+    }
+
+    unsafe fn prod<T: Copy>(&self) -> ProdRing<T> {
+        ProdRing::new(self.producer, self.consumer, self.desc as *mut T, self.size)
+    }
+
+    unsafe fn cons<T: Copy>(&self) -> ConsRing<T> {
+        ConsRing::new(self.producer, self.consumer, self.desc as *const T, self.size)
+    }
+}
+
+impl Drop for XdpRing {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// Set a `SOL_XDP` socket option.
+fn set_opt<T>(fd: &OwnedFd, name: i32, val: &T) -> io::Result<()> {
+    let ret = unsafe { setsockopt(fd.as_raw_fd(), SOL_XDP, name, val as *const T as *const _, mem::size_of::<T>() as u32) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Snapshot of how many entries sit in each ring, plus where the frames are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RingOccupancy {
+    pub rx: u32,
+    pub fill: u32,
+    pub tx: u32,
+    pub comp: u32,
+    /// Frames on the free list, usable by RX or TX
+    pub free_frames: usize,
+    /// Frames held by RX (fill and RX rings)
+    pub rx_frames: u32,
+}
+
+/// The kernel's `XDP_STATISTICS` for the socket.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelStats {
+    /// Dropped for reasons other than invalid descriptors
+    pub rx_dropped: u64,
+    pub rx_invalid_descs: u64,
+    pub tx_invalid_descs: u64,
+    /// Dropped because the RX ring was full
+    pub rx_ring_full: u64,
+    /// Times the kernel found the fill ring empty (Linux 5.9+)
+    pub rx_fill_ring_empty_descs: u64,
+    /// Times the kernel found the TX ring empty when woken (Linux 5.9+)
+    pub tx_ring_empty_descs: u64,
+}
+
+/// Socket health: kernel counters merged with our own, cumulative since creation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XskStats {
+    /// `None` if the kernel didn't report them
+    pub kernel: Option<KernelStats>,
+    /// RX descriptors pointing outside the UMEM
+    pub rx_invalid_addrs: u64,
+    /// Packets ready to send that found the TX ring full
+    pub tx_ring_full: u64,
+    /// Packets ready to send that found no free frame
+    pub tx_frames_exhausted: u64,
+    /// Forwarded frames dropped because the TX ring was full
+    pub forward_dropped: u64,
+    /// Frames on the fill ring right now
+    pub fill_ring: u32,
+}
+
+/// How many of `XskStats::counters` describe RX losses; the rest are TX backpressure.
+const RX_COUNTERS: usize = 5;
+
+impl XskStats {
+    /// Every counter by name, RX losses first. Kernel counters read 0 when unavailable.
+    pub fn counters(&self) -> [(&'static str, u64); 10] {
+        let k = self.kernel.unwrap_or_default();
+        [
+            ("rx_dropped", k.rx_dropped),
+            ("rx_ring_full", k.rx_ring_full),
+            ("fill_ring_empty", k.rx_fill_ring_empty_descs),
+            ("rx_invalid_descs", k.rx_invalid_descs),
+            ("rx_invalid_addrs", self.rx_invalid_addrs),
+            ("tx_invalid_descs", k.tx_invalid_descs),
+            ("tx_ring_empty", k.tx_ring_empty_descs),
+            ("tx_ring_full", self.tx_ring_full),
+            ("tx_frames_exhausted", self.tx_frames_exhausted),
+            ("forward_dropped", self.forward_dropped),
+        ]
+    }
+
+    /// Log the counters that went up since `prev`: RX losses as a warning, TX backpressure
+    /// (routine while flooding) at debug level.
+    pub fn log_changes(&self, prev: &XskStats) {
+        let (now, was) = (self.counters(), prev.counters());
+        let changed = |range: std::ops::Range<usize>| {
+            range
+                .filter(|&i| now[i].1 > was[i].1)
+                .map(|i| format!("{} +{}", now[i].0, now[i].1 - was[i].1))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let rx = changed(0..RX_COUNTERS);
+        if !rx.is_empty() {
+            warn!(target: "xsk", "RX losses: {} (fill ring: {})", rx, self.fill_ring);
+        }
+        let tx = changed(RX_COUNTERS..now.len());
+        if !tx.is_empty() {
+            debug!(target: "xsk", "TX backpressure: {}", tx);
+        }
+    }
+}
+
+/// An AF_XDP socket bound to one queue of one interface, with its own UMEM.
+pub struct XdpSocket {
+    /// Fill, completion, RX and TX mappings; fields drop in order, so the rings are
+    /// unmapped and the socket closed before the UMEM goes
+    _ring_maps: [XdpRing; 4],
+    fd: OwnedFd,
+    _umem: Umem,
+    umem_ptr: *mut u8,
+    frame_size: usize,
+    num_frames: usize,
+    rx_ring: ConsRing<XdpDesc>,
+    tx_ring: ProdRing<XdpDesc>,
+    fill_ring: ProdRing<u64>,
+    comp_ring: ConsRing<u64>,
+    frames: FramePool,
+    /// Userspace counters; `stats()` adds the kernel's and the fill depth
+    stats: XskStats,
+}
+
+impl XdpSocket {
+    /// Create the socket, UMEM and rings, bind to `queue_id` of `iface` (zero-copy if the
+    /// driver supports it, copy mode otherwise) and fill the fill ring. The socket still has
+    /// to be put in the XDP program's `XSK` map before it receives anything.
+    pub fn new(iface: &str, queue_id: u32, cfg: &UmemConfig) -> Result<Self, Error> {
+        let (frame_size, num_frames, ring_size) = (cfg.frame_size, cfg.num_frames(), cfg.ring_size);
+        let step = |step: &'static str| {
+            move |source: io::Error| Error::Socket { step, iface: iface.to_string(), queue_id, source }
+        };
+        // Everything below is owned as soon as it exists (the fd, the UMEM, each ring
+        // mapping), so an early return releases whatever was set up so far.
+
+        // 1. Socket
+        let fd = unsafe { socket(AF_XDP, SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(step("create socket")(io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // 2. UMEM (huge pages on the NIC's NUMA node, see umem.rs)
+        let umem = Umem::allocate(cfg, iface).map_err(step("allocate UMEM"))?;
+        let umem_ptr = umem.ptr;
+
+        let mr = XdpUmemReg {
+            addr: umem_ptr as u64, len: cfg.size as u64, chunk_size: frame_size as u32, headroom: 0, flags: 0,
+        };
+        set_opt(&fd, XDP_UMEM_REG, &mr).map_err(step("register UMEM"))?;
+        // 3. Ring Sizes
+        set_opt(&fd, XDP_UMEM_FILL_RING, &ring_size).map_err(step("size fill ring"))?;
+        set_opt(&fd, XDP_UMEM_COMPLETION_RING, &ring_size).map_err(step("size completion ring"))?;
+        set_opt(&fd, XDP_RX_RING, &ring_size).map_err(step("size RX ring"))?;
+        set_opt(&fd, XDP_TX_RING, &ring_size).map_err(step("size TX ring"))?;
+
+        // 4. Offsets
+        let mut off = XdpMmapOffsets::default();
+        let mut optlen = mem::size_of::<XdpMmapOffsets>() as u32;
+        if unsafe { libc::getsockopt(fd.as_raw_fd(), SOL_XDP, XDP_MMAP_OFFSETS, &mut off as *mut _ as *mut _, &mut optlen) } != 0 {
+            return Err(step("read ring offsets")(io::Error::last_os_error()));
+        }
+
+        // 5. Map Rings: fill and completion hold u64 addresses, RX and TX xdp_descs
+        let fill_ring = XdpRing::map::<u64>(&fd, &off.fr, ring_size, XDP_UMEM_PGOFF_FILL_RING as i64)
+            .map_err(step("map fill ring"))?;
+        let comp_ring = XdpRing::map::<u64>(&fd, &off.cr, ring_size, XDP_UMEM_PGOFF_COMPLETION_RING as i64)
+            .map_err(step("map completion ring"))?;
+        let rx_ring = XdpRing::map::<XdpDesc>(&fd, &off.rx, ring_size, XDP_PGOFF_RX_RING)
+            .map_err(step("map RX ring"))?;
+        let tx_ring = XdpRing::map::<XdpDesc>(&fd, &off.tx, ring_size, XDP_PGOFF_TX_RING)
+            .map_err(step("map TX ring"))?;
+
+        // 6. Frame pool; the fill ring gets its initial share before bind
+        let frames = FramePool::new(num_frames as u32, frame_size, ring_size, cfg.rx_reserve, cfg.tx_reserve);
+
+        // 7. Bind
+        let if_name = CString::new(iface).map_err(|e| step("look up interface")(e.into()))?;
+        let mut sa: libc::sockaddr_xdp = unsafe { mem::zeroed() };
+        sa.sxdp_family = AF_XDP as u16;
+        sa.sxdp_ifindex = unsafe { libc::if_nametoindex(if_name.as_ptr()) };
+        if sa.sxdp_ifindex == 0 {
+            return Err(step("look up interface")(io::Error::last_os_error()));
+        }
+        sa.sxdp_queue_id = queue_id;
+
+        let bind = |sa: &libc::sockaddr_xdp| unsafe {
+            libc::bind(fd.as_raw_fd(), sa as *const _ as *const _, mem::size_of::<libc::sockaddr_xdp>() as u32) == 0
+        };
+        if !bind(&sa) {
+            sa.sxdp_flags = XDP_COPY;
+            if !bind(&sa) {
+                return Err(step("bind")(io::Error::last_os_error()));
+            }
+        }
+
+        let (rx, tx, fill, comp) = unsafe { (rx_ring.cons(), tx_ring.prod(), fill_ring.prod(), comp_ring.cons()) };
+        let mut sock = XdpSocket {
+            _ring_maps: [fill_ring, comp_ring, rx_ring, tx_ring],
+            fd, umem_ptr, _umem: umem, frame_size, num_frames,
+            rx_ring: rx, tx_ring: tx, fill_ring: fill, comp_ring: comp,
+            frames,
+            stats: XskStats::default(),
+        };
+        sock.refill();
+        Ok(sock)
+    }
+
+    /// Take up to `max` (at most [`MAX_BATCH`]) received frames off the RX ring. The frames
+    /// go back to the frame pool when the batch is dropped, unless forwarded.
+    pub fn rx_batch(&mut self, max: u32) -> RxBatch<'_> {
+        self.reconcile();
+        self.refill();
+        let max = max.min(MAX_BATCH);
+        let (start, len) = self.rx_ring.peek(max);
+        // Full batches mean the kernel is keeping up with more frames than we give it
+        if len == max {
+            self.frames.grow_rx(len);
+        }
+        RxBatch {
+            sock: self,
+            start,
+            len,
+            next: Cell::new(0),
+            forward: std::array::from_fn(|_| Cell::new(0)),
+        }
+    }
+
+    /// Reserve up to `max` (at most [`MAX_BATCH`]) TX descriptors, each with a free frame
+    /// (reclaiming completed frames first). Frames that were sent are queued for the kernel
+    /// when the batch is dropped; the NIC isn't notified until `flush_tx`.
+    pub fn tx_batch(&mut self, max: u32) -> TxBatch<'_> {
+        self.reconcile();
+        let (start, len, limit) = self.tx_reserve(max.min(MAX_BATCH));
+        TxBatch {
+            sock: self,
+            start,
+            len,
+            limit,
+            next: Cell::new(0),
+            missed: Cell::new(0),
+            lens: std::array::from_fn(|_| Cell::new(0)),
+        }
+    }
+
+    /// Settle a batch that was leaked (`mem::forget`) instead of dropped, so the next one
+    /// starts from the published indices: its TX slots are cancelled and its RX descriptors
+    /// released, all of their frames going back to the pool.
+    #[inline(always)]
+    fn reconcile(&mut self) {
+        let (tx_start, tx_n) = self.tx_ring.unsubmitted();
+        let (rx_start, rx_n) = self.rx_ring.unreleased();
+        if tx_n > 0 || rx_n > 0 {
+            self.reconcile_leaked(tx_start, tx_n, rx_start, rx_n);
+        }
+    }
+
+    #[cold]
+    fn reconcile_leaked(&mut self, tx_start: u32, tx_n: u32, rx_start: u32, rx_n: u32) {
+        warn_ratelimited!(target: "xsk", "Leaked batch: {} TX slots and {} RX descriptors not handed back, reclaiming them", tx_n, rx_n);
+        // Only one batch borrows the socket at a time, so there are at most MAX_BATCH of each
+        let unsent: [Cell<u32>; MAX_BATCH as usize] = std::array::from_fn(|_| Cell::new(0));
+        // TX first: forwarding from rx_release would otherwise publish the leaked slots
+        if tx_n > 0 {
+            self.tx_commit(tx_start, &unsent[..tx_n as usize]);
+        }
+        self.rx_release(rx_start, &unsent[..rx_n as usize]);
+    }
+
+    /// Put frames from the pool on the fill ring, up to RX's current share.
+    fn refill(&mut self) {
+        let want = self.frames.rx_deficit();
+        if want == 0 {
+            return;
+        }
+        let n = self.fill_ring.free(want).min(want);
+        let Some(idx) = self.fill_ring.reserve(n) else { return };
+        for i in 0..n {
+            let addr = self.frames.take_rx().expect("rx_deficit counts free frames");
+            self.fill_ring.set(idx.wrapping_add(i), addr);
+        }
+        self.fill_ring.submit(n);
+    }
+
+    /// Frame at RX ring index `idx`; `None` if the kernel handed us an address outside the
+    /// UMEM.
+    fn rx_frame(&self, idx: u32) -> Option<(u64, usize)> {
+        let desc = self.rx_ring.get(idx);
+        let addr = desc.addr;
+
+        let umem_len = self.num_frames * self.frame_size;
+        if addr as usize >= umem_len {
+            warn_ratelimited!(target: "xsk", "Invalid RX addr: {} >= UMEM size {}", addr, umem_len);
+            return None;
+        }
+
+        // Clamp len to the end of the frame to prevent buffer overflow
+        let room = self.frame_size - addr as usize % self.frame_size;
+        Some((addr, (desc.len as usize).min(room)))
+    }
+
+    /// Read the `RxMeta` in front of the packet at `addr`, clearing its magic so a later
+    /// packet in the same frame can't pick up a stale copy.
+    #[inline(always)]
+    fn take_meta(&self, addr: u64) -> Option<RxMeta> {
+        let meta_len = mem::size_of::<RxMeta>();
+        if (addr as usize % self.frame_size) < meta_len {
+            return None;
+        }
+        unsafe {
+            let ptr = self.umem_ptr.add(addr as usize - meta_len) as *mut RxMeta;
+            let meta = ptr::read_unaligned(ptr);
+            if meta.magic != RX_META_MAGIC {
+                return None;
+            }
+            ptr::write_unaligned(ptr::addr_of_mut!((*ptr).magic), 0);
+            Some(meta)
+        }
+    }
+
+    /// Hand the RX descriptors from `start` back to the kernel (one index store). Frames with
+    /// a `forward` length go on the TX ring as they are, the rest back to the pool.
+    fn rx_release(&mut self, start: u32, forward: &[Cell<u32>]) {
+        let n = forward.len() as u32;
+        if n == 0 {
+            return;
+        }
+        let umem_len = (self.num_frames * self.frame_size) as u64;
+        let mut forwarded = 0;
+        for (i, len) in forward.iter().enumerate() {
+            let addr = self.rx_ring.get(start.wrapping_add(i as u32)).addr;
+            // Addresses that aren't ours (see rx_frame) must not enter the pool
+            if addr >= umem_len {
+                self.stats.rx_invalid_addrs += 1;
+                self.frames.rx_lost();
+                continue;
+            }
+            match len.get() {
+                0 => self.frames.rx_done(addr),
+                len => {
+                    // Forwarded frames are few; reserve one TX slot each and drop them if
+                    // the ring is full
+                    match self.tx_ring.reserve(1) {
+                        Some(idx) => {
+                            self.tx_ring.set(idx, XdpDesc { addr, len, options: 0 });
+                            self.frames.rx_forwarded();
+                            forwarded += 1;
+                        }
+                        None => {
+                            self.stats.forward_dropped += 1;
+                            self.frames.rx_done(addr);
+                        }
+                    }
+                }
+            }
+        }
+        if forwarded > 0 {
+            self.tx_ring.submit(forwarded);
+        }
+        self.rx_ring.release(n);
+    }
+
+    /// Move completed TX frames back to the pool.
+    fn comp_drain(&mut self) {
+        let (idx, n) = self.comp_ring.peek(u32::MAX);
+        if n == 0 {
+            return;
+        }
+        for i in 0..n {
+            self.frames.tx_done(self.comp_ring.get(idx.wrapping_add(i)));
+        }
+        self.comp_ring.release(n);
+    }
+
+    /// Reserve up to `max` TX slots and put a free frame in each; returns the first slot,
+    /// how many, and what kept it from `max`. Neither RX's share of the pool nor the
+    /// shortage counters change until the batch reports a packet it had no slot for (see
+    /// `TxBatch::missed`).
+    fn tx_reserve(&mut self, max: u32) -> (u32, u32, TxLimit) {
+        self.comp_drain();
+        let available = self.frames.tx_available();
+        let want = max.min(available);
+        let n = self.tx_ring.free(want).min(want);
+        let limit = if n < want {
+            TxLimit::Ring
+        } else if available < max {
+            TxLimit::Frames
+        } else {
+            TxLimit::Request
+        };
+        if n == 0 {
+            return (0, 0, limit);
+        }
+        let idx = self.tx_ring.reserve(n).expect("checked free slots above");
+        for i in 0..n {
+            let addr = self.frames.take_tx().expect("checked free frames above");
+            self.tx_ring.set(idx.wrapping_add(i), XdpDesc { addr, len: 0, options: 0 });
+        }
+        (idx, n, limit)
+    }
+
+    /// `missed` packets were ready to go but their batch had no slot left, because of
+    /// `limit`. Only this real demand moves frames from RX to TX.
+    fn tx_missed(&mut self, limit: TxLimit, missed: u32) {
+        match limit {
+            TxLimit::Frames => {
+                self.frames.shrink_rx(missed);
+                self.stats.tx_frames_exhausted += missed as u64;
+            }
+            TxLimit::Ring => self.stats.tx_ring_full += missed as u64,
+            TxLimit::Request => {}
+        }
+    }
+
+    /// Publish the reserved slots from `start` that have a length, packed to the front in
+    /// order (one index store); the rest are cancelled and their frames freed.
+    fn tx_commit(&mut self, start: u32, lens: &[Cell<u32>]) {
+        let mut kept = 0;
+        for (i, len) in lens.iter().enumerate() {
+            let desc = self.tx_ring.get(start.wrapping_add(i as u32));
+            match len.get() {
+                0 => self.frames.tx_done(desc.addr),
+                len => {
+                    self.tx_ring.set(start.wrapping_add(kept), XdpDesc { len, ..desc });
+                    kept += 1;
+                }
+            }
+        }
+        self.tx_ring.cancel(lens.len() as u32 - kept);
+        if kept > 0 {
+            self.tx_ring.submit(kept);
+        }
+    }
+
+    /// Kick the NIC doorbell - call once after queuing TX batches
+    #[inline(always)]
+    pub fn flush_tx(&self) {
+        unsafe {
+            libc::sendto(self.fd.as_raw_fd(), ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0);
+        }
+    }
+
+    /// Kernel and userspace health counters. Costs a `getsockopt`, so call it periodically
+    /// rather than per packet.
+    pub fn stats(&self) -> XskStats {
+        XskStats { kernel: self.kernel_stats(), fill_ring: self.fill_ring.depth(), ..self.stats }
+    }
+
+    fn kernel_stats(&self) -> Option<KernelStats> {
+        let mut st: libc::xdp_statistics = unsafe { mem::zeroed() };
+        let mut optlen = mem::size_of::<libc::xdp_statistics>() as u32;
+        // Kernels before 5.9 fill only the first three fields and leave the rest zero
+        let ret = unsafe { libc::getsockopt(self.fd.as_raw_fd(), SOL_XDP, XDP_STATISTICS, &mut st as *mut _ as *mut _, &mut optlen) };
+        if ret != 0 {
+            warn_ratelimited!(target: "xsk", "XDP_STATISTICS failed: {}", io::Error::last_os_error());
+            return None;
+        }
+        Some(KernelStats {
+            rx_dropped: st.rx_dropped,
+            rx_invalid_descs: st.rx_invalid_descs,
+            tx_invalid_descs: st.tx_invalid_descs,
+            rx_ring_full: st.rx_ring_full,
+            rx_fill_ring_empty_descs: st.rx_fill_ring_empty_descs,
+            tx_ring_empty_descs: st.tx_ring_empty_descs,
+        })
+    }
+
+    /// Entries in each ring and where the frames are; for gauges, not the hot path.
+    pub fn ring_occupancy(&self) -> RingOccupancy {
+        RingOccupancy {
+            rx: self.rx_ring.depth(),
+            fill: self.fill_ring.depth(),
+            tx: self.tx_ring.depth(),
+            comp: self.comp_ring.depth(),
+            free_frames: self.frames.free_frames(),
+            rx_frames: self.frames.rx_frames(),
+        }
+    }
+}
+
+impl AsRawFd for XdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Received frames borrowed from the RX ring. Iterate `&batch` for the frames; each is handed
+/// out once, and all of them are released when the batch is dropped, which the borrow checker
+/// only allows once no `RxFrame` is left.
+///
+/// The ring's consumer index only moves on drop. A batch leaked with `mem::forget` is
+/// reclaimed by the next `rx_batch` or `tx_batch`, as if all its frames were dropped
+/// unforwarded.
+pub struct RxBatch<'s> {
+    sock: &'s mut XdpSocket,
+    start: u32,
+    len: u32,
+    /// Next descriptor to hand out
+    next: Cell<u32>,
+    /// Length to retransmit per descriptor; 0 unless `RxFrame::forward`
+    forward: [Cell<u32>; MAX_BATCH as usize],
+}
+
+impl RxBatch<'_> {
+    /// Descriptors in the batch, including any the iterator skips as invalid.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'b> Iterator for &'b RxBatch<'_> {
+    type Item = RxFrame<'b>;
+
+    fn next(&mut self) -> Option<RxFrame<'b>> {
+        loop {
+            let i = self.next.get();
+            if i >= self.len {
+                return None;
+            }
+            self.next.set(i + 1);
+            let sock: &'b XdpSocket = self.sock;
+            let Some((addr, len)) = sock.rx_frame(self.start.wrapping_add(i)) else { continue };
+            let meta = sock.take_meta(addr);
+            // Each descriptor is handed out once, and distinct descriptors are distinct frames
+            let data = unsafe { std::slice::from_raw_parts_mut(sock.umem_ptr.add(addr as usize), len) };
+            return Some(RxFrame { data, meta, forward: &self.forward[i as usize] });
+        }
+    }
+}
+
+impl Drop for RxBatch<'_> {
+    fn drop(&mut self) {
+        self.sock.rx_release(self.start, &self.forward[..self.len as usize]);
+    }
+}
+
+/// A received packet, valid until its `RxBatch` is dropped.
+pub struct RxFrame<'b> {
+    data: &'b mut [u8],
+    meta: Option<RxMeta>,
+    forward: &'b Cell<u32>,
+}
+
+impl RxFrame<'_> {
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    /// Mutable packet bytes (quiche decrypts in place).
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+
+    /// What the XDP program stamped in front of the packet (`None` when the driver doesn't
+    /// support XDP metadata).
+    pub fn meta(&self) -> Option<&RxMeta> {
+        self.meta.as_ref()
+    }
+
+    /// Retransmit the first `len` bytes of this frame (typically after rewriting its headers
+    /// in place) without copying: the frame goes straight from the RX to the TX ring when
+    /// the batch is dropped, and is freed once the NIC completes it. Like any TX, it isn't
+    /// sent before `flush_tx`; if the TX ring is full, the frame is dropped.
+    pub fn forward(self, len: usize) {
+        debug_assert!(len > 0 && len <= self.data.len(), "forward length {} outside packet of {}", len, self.data.len());
+        self.forward.set(len.min(self.data.len()) as u32);
+    }
+}
+
+/// Reserved TX descriptors with a free frame each. Take frames with [`next_frame`](Self::next_frame) and
+/// [`send`](TxFrame::send) them; when the batch is dropped the sent ones go on the TX ring in
+/// the order they were taken and the others are returned to the free list.
+///
+/// The ring's producer index only moves on drop. A batch leaked with `mem::forget` is
+/// reclaimed by the next `rx_batch` or `tx_batch`, as if none of its frames were sent.
+pub struct TxBatch<'s> {
+    sock: &'s mut XdpSocket,
+    start: u32,
+    len: u32,
+    /// What kept the reservation below the request
+    limit: TxLimit,
+    /// Next slot to hand out
+    next: Cell<u32>,
+    /// Packets that were ready once every slot was handed out
+    missed: Cell<u32>,
+    /// Length to send per slot; 0 until `TxFrame::send`
+    lens: [Cell<u32>; MAX_BATCH as usize],
+}
+
+impl TxBatch<'_> {
+    /// The next free frame, or `None` once every reserved slot has been handed out. Several
+    /// frames can be in flight at once.
+    pub fn next_frame(&self) -> Option<TxFrame<'_>> {
+        let slot = self.next.get();
+        if slot >= self.len {
+            return None;
+        }
+        self.next.set(slot + 1);
+        let addr = self.sock.tx_ring.get(self.start.wrapping_add(slot)).addr;
+        // Each slot is handed out once and holds its own frame
+        let buf = unsafe { std::slice::from_raw_parts_mut(self.sock.umem_ptr.add(addr as usize), self.sock.frame_size) };
+        Some(TxFrame { len: &self.lens[slot as usize], buf })
+    }
+
+    /// Slots reserved, i.e. how many frames `next_frame` will hand out.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Frames sent so far.
+    pub fn sent(&self) -> u32 {
+        self.lens[..self.len as usize].iter().filter(|len| len.get() > 0).count() as u32
+    }
+
+    /// Whether fewer slots were reserved than asked for, for lack of free frames or ring
+    /// space.
+    pub fn is_short(&self) -> bool {
+        self.limit != TxLimit::Request
+    }
+
+    /// A packet was ready to go after `next_frame` ran out. Counted as a shortage when the
+    /// batch is dropped, and if free frames were what ran out, RX gives one up for it; a
+    /// batch that merely had nothing to send changes neither.
+    pub fn missed(&self) {
+        self.missed.set(self.missed.get() + 1);
+    }
+}
+
+impl Drop for TxBatch<'_> {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            self.sock.tx_commit(self.start, &self.lens[..self.len as usize]);
+        }
+        if self.missed.get() > 0 {
+            self.sock.tx_missed(self.limit, self.missed.get());
+        }
+    }
+}
+
+/// What kept a TX reservation below the number of slots asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxLimit {
+    /// Nothing: every slot asked for was reserved
+    Request,
+    /// Too few free frames
+    Frames,
+    /// Too few free TX ring slots
+    Ring,
+}
+
+/// A free UMEM frame to build a packet in. Dropping it without `send` gives it back.
+pub struct TxFrame<'b> {
+    len: &'b Cell<u32>,
+    buf: &'b mut [u8],
+}
+
+impl TxFrame<'_> {
+    /// The whole frame.
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    /// Queue the first `len` bytes for transmission when the batch is dropped.
+    pub fn send(self, len: usize) {
+        debug_assert!(len > 0 && len <= self.buf.len(), "TX length {} outside frame of {}", len, self.buf.len());
+        self.len.set(len.min(self.buf.len()) as u32);
+    }
+}
@@ -0,0 +1,257 @@
+//! Single-producer/single-consumer access to AF_XDP rings, modelled on libxdp's
+//! `xsk_ring_prod__*` / `xsk_ring_cons__*`.
+//!
+//! The producer and consumer indices live on cache lines shared with the kernel, so every
+//! access can bounce a line between cores. Each side keeps a cached copy of both indices and
+//! only reloads the other side's index when the cached view runs out (no free slots, or no
+//! entries), and publishes its own index once per batch rather than once per descriptor.
+//! With the `ring-stats` feature each ring counts those shared accesses (see
+//! `benches/rings.rs`).
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Shared-index accesses made by one side of a ring.
+#[cfg(feature = "ring-stats")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncStats {
+    /// Acquire loads of the other side's index
+    pub loads: u64,
+    /// Release stores of our own index
+    pub stores: u64,
+}
+
+/// The side of a ring we write descriptors into (fill, TX).
+pub struct ProdRing<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut T,
+    mask: u32,
+    size: u32,
+    /// Next slot to hand out from `reserve`
+    cached_prod: u32,
+    /// Consumer index plus `size`, i.e. one past the last slot we may write
+    cached_cons: u32,
+    /// Producer index as last published
+    published: u32,
+    #[cfg(feature = "ring-stats")]
+    pub stats: SyncStats,
+}
+
+impl<T: Copy> ProdRing<T> {
+    /// # Safety
+    /// The pointers must stay valid for the ring's lifetime, `desc` must hold `size` entries
+    /// (a power of two), and nothing else may produce into the ring.
+    pub unsafe fn new(producer: *const AtomicU32, consumer: *const AtomicU32, desc: *mut T, size: u32) -> Self {
+        debug_assert!(size.is_power_of_two());
+        let prod = (*producer).load(Ordering::Relaxed);
+        ProdRing {
+            producer,
+            consumer,
+            desc,
+            mask: size - 1,
+            size,
+            cached_prod: prod,
+            cached_cons: (*consumer).load(Ordering::Acquire).wrapping_add(size),
+            published: prod,
+            #[cfg(feature = "ring-stats")]
+            stats: SyncStats::default(),
+        }
+    }
+
+    /// Free slots; the consumer index is only reloaded if fewer than `want` are known free.
+    #[inline(always)]
+    pub fn free(&mut self, want: u32) -> u32 {
+        let free = self.cached_cons.wrapping_sub(self.cached_prod);
+        if free >= want {
+            return free;
+        }
+        #[cfg(feature = "ring-stats")]
+        {
+            self.stats.loads += 1;
+        }
+        self.cached_cons = unsafe { (*self.consumer).load(Ordering::Acquire) }.wrapping_add(self.size);
+        self.cached_cons.wrapping_sub(self.cached_prod)
+    }
+
+    /// Reserve exactly `n` slots, returning the index of the first; `None` if the ring is
+    /// too full. Fill them with [`set`](Self::set), then [`submit`](Self::submit).
+    #[inline(always)]
+    pub fn reserve(&mut self, n: u32) -> Option<u32> {
+        if self.free(n) < n {
+            return None;
+        }
+        let idx = self.cached_prod;
+        self.cached_prod = self.cached_prod.wrapping_add(n);
+        Some(idx)
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, idx: u32, value: T) {
+        unsafe { self.desc.add((idx & self.mask) as usize).write(value) };
+    }
+
+    #[inline(always)]
+    pub fn get(&self, idx: u32) -> T {
+        unsafe { self.desc.add((idx & self.mask) as usize).read() }
+    }
+
+    /// Hand the next `n` reserved slots to the consumer (one release store).
+    #[inline(always)]
+    pub fn submit(&mut self, n: u32) {
+        debug_assert!(n <= self.cached_prod.wrapping_sub(self.published), "submitting unreserved slots");
+        self.published = self.published.wrapping_add(n);
+        #[cfg(feature = "ring-stats")]
+        {
+            self.stats.stores += 1;
+        }
+        unsafe { (*self.producer).store(self.published, Ordering::Release) };
+    }
+
+    /// The first reserved slot not yet submitted, and how many there are.
+    #[inline(always)]
+    pub fn unsubmitted(&self) -> (u32, u32) {
+        (self.published, self.cached_prod.wrapping_sub(self.published))
+    }
+
+    /// Give back the last `n` reserved, unsubmitted slots.
+    #[inline(always)]
+    pub fn cancel(&mut self, n: u32) {
+        debug_assert!(n <= self.cached_prod.wrapping_sub(self.published), "cancelling unreserved slots");
+        self.cached_prod = self.cached_prod.wrapping_sub(n);
+    }
+
+    /// Entries waiting for the consumer (reads both shared indices; for monitoring).
+    pub fn depth(&self) -> u32 {
+        unsafe { (*self.producer).load(Ordering::Acquire).wrapping_sub((*self.consumer).load(Ordering::Acquire)) }
+    }
+}
+
+/// The side of a ring we read descriptors from (RX, completion).
+pub struct ConsRing<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *const T,
+    mask: u32,
+    /// Producer index as last loaded
+    cached_prod: u32,
+    /// Next entry to hand out from `peek`
+    cached_cons: u32,
+    /// Consumer index as last published
+    released: u32,
+    #[cfg(feature = "ring-stats")]
+    pub stats: SyncStats,
+}
+
+impl<T: Copy> ConsRing<T> {
+    /// # Safety
+    /// As for [`ProdRing::new`], with nothing else consuming from the ring.
+    pub unsafe fn new(producer: *const AtomicU32, consumer: *const AtomicU32, desc: *const T, size: u32) -> Self {
+        debug_assert!(size.is_power_of_two());
+        let cons = (*consumer).load(Ordering::Relaxed);
+        ConsRing {
+            producer,
+            consumer,
+            desc,
+            mask: size - 1,
+            cached_prod: (*producer).load(Ordering::Acquire),
+            cached_cons: cons,
+            released: cons,
+            #[cfg(feature = "ring-stats")]
+            stats: SyncStats::default(),
+        }
+    }
+
+    /// Take up to `max` entries, returning the index of the first and how many. The producer
+    /// index is only reloaded once the cached entries are used up. Read them with
+    /// [`get`](Self::get), then [`release`](Self::release).
+    #[inline(always)]
+    pub fn peek(&mut self, max: u32) -> (u32, u32) {
+        let mut entries = self.cached_prod.wrapping_sub(self.cached_cons);
+        if entries == 0 {
+            #[cfg(feature = "ring-stats")]
+            {
+                self.stats.loads += 1;
+            }
+            self.cached_prod = unsafe { (*self.producer).load(Ordering::Acquire) };
+            entries = self.cached_prod.wrapping_sub(self.cached_cons);
+        }
+        let n = entries.min(max);
+        let idx = self.cached_cons;
+        self.cached_cons = self.cached_cons.wrapping_add(n);
+        (idx, n)
+    }
+
+    #[inline(always)]
+    pub fn get(&self, idx: u32) -> T {
+        unsafe { self.desc.add((idx & self.mask) as usize).read() }
+    }
+
+    /// Hand the next `n` peeked entries back to the producer (one release store).
+    #[inline(always)]
+    pub fn release(&mut self, n: u32) {
+        debug_assert!(n <= self.cached_cons.wrapping_sub(self.released), "releasing unpeeked entries");
+        self.released = self.released.wrapping_add(n);
+        #[cfg(feature = "ring-stats")]
+        {
+            self.stats.stores += 1;
+        }
+        unsafe { (*self.consumer).store(self.released, Ordering::Release) };
+    }
+
+    /// The first peeked entry not yet released, and how many there are.
+    #[inline(always)]
+    pub fn unreleased(&self) -> (u32, u32) {
+        (self.released, self.cached_cons.wrapping_sub(self.released))
+    }
+
+    /// Entries not yet consumed (reads both shared indices; for monitoring).
+    pub fn depth(&self) -> u32 {
+        unsafe { (*self.producer).load(Ordering::Acquire).wrapping_sub((*self.consumer).load(Ordering::Acquire)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 8;
+
+    struct Shared {
+        producer: AtomicU32,
+        consumer: AtomicU32,
+        desc: [u64; SIZE as usize],
+    }
+
+    fn shared() -> Box<Shared> {
+        Box::new(Shared { producer: AtomicU32::new(0), consumer: AtomicU32::new(0), desc: [0; SIZE as usize] })
+    }
+
+    #[test]
+    fn unsubmitted_tracks_reserved_slots() {
+        let mut sh = shared();
+        let mut ring = unsafe { ProdRing::new(&sh.producer, &sh.consumer, sh.desc.as_mut_ptr(), SIZE) };
+        assert_eq!(ring.reserve(3), Some(0));
+        assert_eq!(ring.unsubmitted(), (0, 3));
+        ring.submit(2);
+        assert_eq!(ring.unsubmitted(), (2, 1));
+        ring.cancel(1);
+        assert_eq!(ring.unsubmitted(), (2, 0));
+        assert_eq!(sh.producer.load(Ordering::Relaxed), 2);
+        // The cancelled slot is handed out again
+        assert_eq!(ring.reserve(1), Some(2));
+    }
+
+    #[test]
+    fn unreleased_tracks_peeked_entries() {
+        let sh = shared();
+        let mut ring = unsafe { ConsRing::new(&sh.producer, &sh.consumer, sh.desc.as_ptr(), SIZE) };
+        sh.producer.store(5, Ordering::Release);
+        assert_eq!(ring.peek(4), (0, 4));
+        assert_eq!(ring.unreleased(), (0, 4));
+        ring.release(4);
+        assert_eq!(ring.unreleased(), (4, 0));
+        assert_eq!(ring.peek(4), (4, 1));
+        assert_eq!(ring.unreleased(), (4, 1));
+        assert_eq!(sh.consumer.load(Ordering::Relaxed), 4);
+    }
+}
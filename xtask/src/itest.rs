@@ -0,0 +1,27 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::{build_ebpf, Result};
+
+/// Build the eBPF object and run the end-to-end test against it.
+///
+/// The test itself (afterburner-app/tests/e2e.rs) brings up the netns, starts
+/// stream_server and the client, and asserts on their output.
+pub fn run(root: &Path, duration: u64, release: bool) -> Result<()> {
+    let obj = build_ebpf(root, release)?;
+
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(root)
+        .args(["test", "--package", "afterburner-app", "--test", "e2e"])
+        .env("AFTERBURNER_EBPF_OBJ", &obj)
+        .env("AFTERBURNER_ITEST_SECS", duration.to_string());
+    if release {
+        cmd.arg("--release");
+    }
+    cmd.args(["--", "--nocapture"]);
+
+    if !cmd.status()?.success() {
+        return Err("itest failed".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+//! Shared helpers for xtask and the end-to-end tests in `afterburner-app/tests`.
+
+pub mod netns;
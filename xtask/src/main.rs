@@ -1,31 +1,159 @@
-use std::path::PathBuf;
-use std::process::Command;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use clap::{Parser, Subcommand};
+use xtask::netns;
 
-fn main(){
-    let target = "bpfel-unknown-none";
+mod itest;
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser, Debug)]
+#[command(about = "Afterburner build and test automation")]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Build the eBPF program for bpfel-unknown-none
+    BuildEbpf {
+        #[arg(long)]
+        release: bool,
+    },
+    /// Build the eBPF program and the app, then launch the app (with sudo if needed)
+    Run {
+        #[arg(long)]
+        release: bool,
+        /// Arguments passed to afterburner-app
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Create or remove the veth0 <-> veth1 (ns1) test topology
+    Netns {
+        #[command(subcommand)]
+        action: NetnsAction,
+    },
+    /// Run the end-to-end test (afterburner-app/tests/e2e.rs) over the veth/netns topology
+    Itest {
+        /// How long to run the client, in seconds
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+        #[arg(long)]
+        release: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+enum NetnsAction {
+    Up,
+    Down,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let root = workspace_root();
+
+    let res = match cli.cmd {
+        Cmd::BuildEbpf { release } => build_ebpf(&root, release).map(|_| ()),
+        Cmd::Run { release, args } => run(&root, release, &args),
+        Cmd::Netns { action } => require_root("netns").and_then(|_| match action {
+            NetnsAction::Up => netns::up().map_err(Into::into),
+            NetnsAction::Down => netns::down().map_err(Into::into),
+        }),
+        Cmd::Itest { duration, release } => require_root("itest").and_then(|_| itest::run(&root, duration, release)),
+    };
 
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("xtask: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn workspace_root() -> PathBuf {
     let dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").expect("env::var"));
+    dir.parent().expect("Could not find workspace root").to_path_buf()
+}
+
+fn target_dir(root: &Path) -> PathBuf {
+    std::env::var_os("CARGO_TARGET_DIR").map(PathBuf::from).unwrap_or_else(|| root.join("target"))
+}
 
-    let root = dir.parent().expect("Could not find workspace root");
-
-    let status = Command::new("cargo")
-        .current_dir(root)
-        .args([
-            "build",
-            "--package",
-            "afterburner-ebpf",
-            "--target",
-            target,
-            "--release",
-            "-Z",
-            "build-std=core",
-        ])
-        .status()
-        .expect("status");
+fn profile(release: bool) -> &'static str {
+    if release { "release" } else { "debug" }
+}
 
-    if !status.success() {
-        panic!("Failed to build eBPF program");
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn require_root(cmd: &str) -> Result<()> {
+    if !is_root() {
+        return Err(format!("`{}` needs root (try: sudo -E cargo xtask {})", cmd, cmd).into());
+    }
+    Ok(())
+}
+
+/// Build the eBPF program and return the path of the object file.
+pub fn build_ebpf(root: &Path, release: bool) -> Result<PathBuf> {
+    let target = "bpfel-unknown-none";
+
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(root)
+        .args(["build", "--package", "afterburner-ebpf", "--target", target, "-Z", "build-std=core"]);
+    if release {
+        cmd.arg("--release");
+    }
+
+    if !cmd.status()?.success() {
+        return Err("Failed to build eBPF program".into());
     }
 
     println!("eBPF Program Compiled Successfully");
+    Ok(target_dir(root).join(target).join(profile(release)).join("afterburner"))
+}
+
+/// Build afterburner-app and stream_server, embedding the given eBPF object.
+fn build_app(root: &Path, release: bool, ebpf_obj: &Path) -> Result<()> {
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(root)
+        .args(["build", "--package", "afterburner-app"])
+        .env("AFTERBURNER_EBPF_OBJ", ebpf_obj);
+    if release {
+        cmd.arg("--release");
+    }
+
+    if !cmd.status()?.success() {
+        return Err("Failed to build afterburner-app".into());
+    }
+    Ok(())
+}
+
+/// Path of a binary from the afterburner-app package.
+fn app_bin(root: &Path, release: bool, name: &str) -> PathBuf {
+    target_dir(root).join(profile(release)).join(name)
+}
+
+fn run(root: &Path, release: bool, args: &[String]) -> Result<()> {
+    let obj = build_ebpf(root, release)?;
+    build_app(root, release, &obj)?;
+
+    let app = app_bin(root, release, "afterburner-app");
+    let mut cmd = if is_root() {
+        Command::new(&app)
+    } else {
+        let mut sudo = Command::new("sudo");
+        sudo.arg("-E").arg(&app);
+        sudo
+    };
+
+    let status = cmd.current_dir(root).args(args).status()?;
+    if !status.success() {
+        return Err(format!("afterburner-app exited with {}", status).into());
+    }
+    Ok(())
 }
@@ -0,0 +1,402 @@
+//! Idempotent veth + network namespace test topology, built directly over rtnetlink.
+//!
+//! ```text
+//!   host: veth0 10.0.0.10/24  <───>  ns1: veth1 10.0.0.11/24
+//! ```
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::{io, mem, ptr, thread};
+
+pub const NETNS: &str = "ns1";
+pub const HOST_IF: &str = "veth0";
+pub const PEER_IF: &str = "veth1";
+pub const HOST_ADDR: [u8; 4] = [10, 0, 0, 10];
+pub const PEER_ADDR: [u8; 4] = [10, 0, 0, 11];
+const PREFIX_LEN: u8 = 24;
+const TXQUEUELEN: u32 = 1000;
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+// rtnetlink constants (linux/netlink.h, linux/rtnetlink.h, linux/if_link.h, linux/veth.h)
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x001;
+const NLM_F_ACK: u16 = 0x004;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLA_F_NESTED: u16 = 1 << 15;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_TXQLEN: u16 = 13;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const VETH_INFO_PEER: u16 = 1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// ethtool ioctls (linux/ethtool.h, linux/sockios.h)
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_STSO: u32 = 0x1f;
+const ETHTOOL_SGSO: u32 = 0x24;
+const ETHTOOL_GFLAGS: u32 = 0x25;
+const ETHTOOL_SFLAGS: u32 = 0x26;
+const ETHTOOL_SGRO: u32 = 0x2c;
+const ETH_FLAG_LRO: u32 = 1 << 15;
+
+#[repr(C)]
+#[allow(dead_code)]
+struct NlMsgHdr {
+    len: u32,
+    ty: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+#[derive(Default)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    ty: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct IfAddrMsg {
+    family: u8,
+    prefixlen: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: *mut libc::c_void,
+    _pad: [u8; 16],
+}
+
+/// Create `ns1`, the veth pair, addresses and link settings. Safe to run repeatedly.
+pub fn up() -> io::Result<()> {
+    create_netns()?;
+
+    if ifindex(HOST_IF).is_none() {
+        let ns = File::open(ns_path())?;
+        create_veth(HOST_IF, PEER_IF, ns.as_raw_fd())?;
+        println!("[netns] Created {} <-> {} ({})", HOST_IF, PEER_IF, NETNS);
+    }
+
+    configure_link(HOST_IF, HOST_ADDR)?;
+    in_netns(|| configure_link(PEER_IF, PEER_ADDR))?;
+
+    println!("[netns] {}: {}/{} <-> {}: {}/{}",
+        HOST_IF, fmt_addr(HOST_ADDR), PREFIX_LEN, PEER_IF, fmt_addr(PEER_ADDR), PREFIX_LEN);
+    Ok(())
+}
+
+/// Remove the veth pair and `ns1`. Safe to run when they are already gone.
+pub fn down() -> io::Result<()> {
+    // Deleting one end of a veth pair removes its peer as well
+    if let Some(idx) = ifindex(HOST_IF) {
+        let mut msg = Msg::new(RTM_DELLINK, 0);
+        msg.push(&IfInfoMsg { index: idx as i32, ..Default::default() });
+        request(msg)?;
+        println!("[netns] Deleted {}", HOST_IF);
+    }
+
+    let path = ns_path();
+    if path.exists() {
+        let target = CString::new(path.as_os_str().as_encoded_bytes())?;
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        fs::remove_file(&path)?;
+        println!("[netns] Deleted {}", NETNS);
+    }
+    Ok(())
+}
+
+pub fn ns_path() -> PathBuf {
+    PathBuf::from(NETNS_RUN_DIR).join(NETNS)
+}
+
+/// Run `f` on a thread that has joined `ns1`, so sockets it opens live in the namespace.
+pub fn in_netns<T: Send>(f: impl FnOnce() -> io::Result<T> + Send) -> io::Result<T> {
+    let ns = File::open(ns_path())?;
+    thread::scope(|s| {
+        s.spawn(|| {
+            if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            f()
+        })
+        .join()
+        .expect("netns thread panicked")
+    })
+}
+
+fn fmt_addr(a: [u8; 4]) -> String {
+    format!("{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+}
+
+fn ifindex(iface: &str) -> Option<u32> {
+    let name = CString::new(iface).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        idx => Some(idx),
+    }
+}
+
+/// Equivalent of `ip netns add`: bind-mount a fresh net namespace onto /run/netns/<name>.
+fn create_netns() -> io::Result<()> {
+    let path = ns_path();
+    if path.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(NETNS_RUN_DIR)?;
+    make_shared(NETNS_RUN_DIR)?;
+    File::create(&path)?;
+
+    let target = CString::new(path.as_os_str().as_encoded_bytes())?;
+    let res = thread::scope(|s| {
+        s.spawn(|| unsafe {
+            if libc::unshare(libc::CLONE_NEWNET) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let src = CString::new("/proc/thread-self/ns/net").expect("CString");
+            if libc::mount(src.as_ptr(), target.as_ptr(), ptr::null(), libc::MS_BIND, ptr::null()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+        .join()
+        .expect("unshare thread panicked")
+    });
+
+    if let Err(e) = res {
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+    println!("[netns] Created {}", NETNS);
+    Ok(())
+}
+
+/// Make the netns run dir a shared mount so namespaces are visible across mount namespaces
+/// (mirrors iproute2).
+fn make_shared(dir: &str) -> io::Result<()> {
+    let dir = CString::new(dir)?;
+    let none = CString::new("none")?;
+    unsafe {
+        if libc::mount(none.as_ptr(), dir.as_ptr(), none.as_ptr(), libc::MS_SHARED | libc::MS_REC, ptr::null()) == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err);
+        }
+        // Not a mount point yet: bind it onto itself first
+        if libc::mount(dir.as_ptr(), dir.as_ptr(), none.as_ptr(), libc::MS_BIND | libc::MS_REC, ptr::null()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::mount(none.as_ptr(), dir.as_ptr(), none.as_ptr(), libc::MS_SHARED | libc::MS_REC, ptr::null()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// `ip link add <host> type veth peer name <peer> netns <ns>`
+fn create_veth(host: &str, peer: &str, ns_fd: i32) -> io::Result<()> {
+    let mut msg = Msg::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL);
+    msg.push(&IfInfoMsg::default());
+    msg.attr_str(IFLA_IFNAME, host);
+    msg.begin_nest(IFLA_LINKINFO);
+    msg.attr_str(IFLA_INFO_KIND, "veth");
+    msg.begin_nest(IFLA_INFO_DATA);
+    msg.begin_nest(VETH_INFO_PEER);
+    msg.push(&IfInfoMsg::default());
+    msg.attr_str(IFLA_IFNAME, peer);
+    msg.attr(IFLA_NET_NS_FD, &(ns_fd as u32).to_ne_bytes());
+    msg.end_nest();
+    msg.end_nest();
+    msg.end_nest();
+    request(msg)
+}
+
+/// Assign the address, set txqueuelen, bring the link up and turn off offloads that
+/// don't work with fixed-size AF_XDP frames. Must run in the interface's namespace.
+fn configure_link(iface: &str, addr: [u8; 4]) -> io::Result<()> {
+    let idx = ifindex(iface)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("interface '{}' not found", iface)))?;
+
+    let mut msg = Msg::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL);
+    msg.push(&IfAddrMsg { family: libc::AF_INET as u8, prefixlen: PREFIX_LEN, flags: 0, scope: 0, index: idx });
+    msg.attr(IFA_LOCAL, &addr);
+    msg.attr(IFA_ADDRESS, &addr);
+    match request(msg) {
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {}
+        res => res?,
+    }
+
+    let mut msg = Msg::new(RTM_NEWLINK, 0);
+    msg.push(&IfInfoMsg {
+        index: idx as i32,
+        flags: libc::IFF_UP as u32,
+        change: libc::IFF_UP as u32,
+        ..Default::default()
+    });
+    msg.attr(IFLA_TXQLEN, &TXQUEUELEN.to_ne_bytes());
+    request(msg)?;
+
+    offloads_off(iface)
+}
+
+/// `ethtool -K <iface> gro off lro off gso off tso off`
+fn offloads_off(iface: &str) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let ethtool = |cmd: u32, data: u32| -> io::Result<u32> {
+        let mut val = EthtoolValue { cmd, data };
+        let mut ifr = IfReq { name: [0; libc::IFNAMSIZ], data: &mut val as *mut _ as *mut _, _pad: [0; 16] };
+        for (dst, src) in ifr.name.iter_mut().zip(iface.bytes().take(libc::IFNAMSIZ - 1)) {
+            *dst = src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(fd, SIOCETHTOOL, &mut ifr) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(val.data)
+    };
+    let unsupported = |e: &io::Error| matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::EINVAL));
+
+    let mut res = Ok(());
+    for cmd in [ETHTOOL_SGRO, ETHTOOL_SGSO, ETHTOOL_STSO] {
+        match ethtool(cmd, 0) {
+            Err(e) if !unsupported(&e) => res = Err(e),
+            _ => {}
+        }
+    }
+    match ethtool(ETHTOOL_GFLAGS, 0) {
+        Ok(flags) if flags & ETH_FLAG_LRO != 0 => {
+            if let Err(e) = ethtool(ETHTOOL_SFLAGS, flags & !ETH_FLAG_LRO) {
+                if !unsupported(&e) { res = Err(e); }
+            }
+        }
+        _ => {}
+    }
+
+    unsafe { libc::close(fd) };
+    res
+}
+
+/// A netlink request under construction.
+struct Msg {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl Msg {
+    fn new(ty: u16, flags: u16) -> Self {
+        let mut msg = Msg { buf: Vec::with_capacity(256), nests: Vec::new() };
+        msg.push(&NlMsgHdr { len: 0, ty, flags: NLM_F_REQUEST | NLM_F_ACK | flags, seq: 1, pid: 0 });
+        msg
+    }
+
+    /// Append a fixed family header (`ifinfomsg`, `ifaddrmsg`, ...).
+    fn push<T>(&mut self, hdr: &T) {
+        let bytes = unsafe { std::slice::from_raw_parts(hdr as *const T as *const u8, mem::size_of::<T>()) };
+        self.buf.extend_from_slice(bytes);
+        self.align();
+    }
+
+    fn attr(&mut self, ty: u16, payload: &[u8]) {
+        self.buf.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(payload);
+        self.align();
+    }
+
+    fn attr_str(&mut self, ty: u16, s: &str) {
+        let mut payload = s.as_bytes().to_vec();
+        payload.push(0);
+        self.attr(ty, &payload);
+    }
+
+    fn begin_nest(&mut self, ty: u16) {
+        self.nests.push(self.buf.len());
+        self.attr(ty | NLA_F_NESTED, &[]);
+    }
+
+    fn end_nest(&mut self) {
+        let start = self.nests.pop().expect("unbalanced nest");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.buf.len().is_multiple_of(4) {
+            self.buf.push(0);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Send a single request on a fresh rtnetlink socket (in the calling thread's namespace)
+/// and wait for its ACK.
+fn request(msg: Msg) -> io::Result<()> {
+    let msg = msg.finish();
+    unsafe {
+        let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = (|| {
+            if libc::send(fd, msg.as_ptr() as *const _, msg.len(), 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut reply = [0u8; 4096];
+            let n = libc::recv(fd, reply.as_mut_ptr() as *mut _, reply.len(), 0);
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let hdr_len = mem::size_of::<NlMsgHdr>();
+            if (n as usize) < hdr_len + 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "short netlink reply"));
+            }
+            let hdr = &*(reply.as_ptr() as *const NlMsgHdr);
+            if hdr.ty == NLMSG_ERROR {
+                let errno = i32::from_ne_bytes(reply[hdr_len..hdr_len + 4].try_into().expect("4 bytes"));
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+            }
+            Ok(())
+        })();
+        libc::close(fd);
+        res
+    }
+}